@@ -4,6 +4,7 @@
 use arrayvec::ArrayVec;
 use bsp::entry;
 use bsp::hal::{clocks::init_clocks_and_plls, pac, sio::Sio, watchdog::Watchdog};
+use core::cell::Cell;
 use cortex_m::prelude::*;
 #[cfg(feature = "defmt")]
 use defmt::*;
@@ -11,7 +12,7 @@ use defmt::*;
 use defmt_rtt as _;
 use embedded_hal::digital::{InputPin, OutputPin};
 use fugit::ExtU32;
-use not_webusb::NotWebUsb;
+use not_webusb::{NotWebUsbBuilder, NotWebUsbError, NotWebUsbStorage, Timeouts};
 use panic_probe as _;
 use rp_pico as bsp;
 use rp2040_hal::Timer;
@@ -22,6 +23,9 @@ use usb_device::{
 use usbd_human_interface_device::device::fido::RawFidoConfig;
 use usbd_human_interface_device::prelude::*;
 
+/// Kept in a static rather than on the stack, as it is over 1KB.
+static mut STORAGE: NotWebUsbStorage<1024> = NotWebUsbStorage::new();
+
 #[entry]
 fn main() -> ! {
     let mut pac = pac::Peripherals::take().unwrap();
@@ -78,8 +82,19 @@ fn main() -> ! {
     let mut led_state = false;
     let mut flash_interval_ms = 1000;
     let mut flash_passed_ms = 0;
-
-    let mut not_webusb = NotWebUsb::<_, 1024>::new(fido, &|_| true);
+    // Blink rapidly for a while after a wink, so the user can tell which device the host is talking to.
+    let wink = Cell::new(false);
+    let mut wink_remaining_ms: u32 = 0;
+
+    let now = || timer.get_counter();
+    // SAFETY: main is only entered once, so this is the only reference to STORAGE.
+    let storage = unsafe { &mut *core::ptr::addr_of_mut!(STORAGE) };
+    let wink_callback = || wink.set(true);
+    let mut not_webusb = NotWebUsbBuilder::new(storage, &|_| true)
+        .timeouts(&now, Timeouts::default())
+        .wink(&wink_callback)
+        .device_version(0, 1, 0)
+        .build(fido);
 
     #[cfg(feature = "defmt")]
     info!("begin main loop");
@@ -87,10 +102,19 @@ fn main() -> ! {
     loop {
         if flash_led.wait().is_ok() {
             flash_passed_ms += 5;
+            wink_remaining_ms = wink_remaining_ms.saturating_sub(5);
         }
-
-        // divide flash_interval_ms by 2, since off and on state need to both occur during interval.
-        if flash_passed_ms > flash_interval_ms / 2 {
+        if wink.take() {
+            wink_remaining_ms = 2000;
+        }
+        let interval_ms = if wink_remaining_ms > 0 {
+            100
+        } else {
+            flash_interval_ms
+        };
+
+        // divide interval_ms by 2, since off and on state need to both occur during interval.
+        if flash_passed_ms > interval_ms / 2 {
             led_state = !led_state;
             led_pin.set_state(led_state.into()).unwrap();
             flash_passed_ms = 0;
@@ -103,11 +127,21 @@ fn main() -> ! {
 
         // TODO: can we make NotWebUsb poll logic allow only calling when usb_dev.poll returns true?
         usb_dev.poll(&mut [not_webusb.fido_class()]);
-        not_webusb.poll().unwrap();
+        match not_webusb.poll() {
+            Ok(()) => {}
+            Err(NotWebUsbError::UsbError) => {
+                core::panic!("not-webusb hit an unrecoverable usb error")
+            }
+            // e.g. a webpage stopped talking to us partway through a request, NotWebUsb has already recovered.
+            Err(_err) => {
+                #[cfg(feature = "defmt")]
+                warn!("not-webusb error {}", _err);
+            }
+        }
 
         if let Some(request) = not_webusb.check_pending_request() {
             // UI will provide a value between 1-255, starting at 128
-            let input = request[0];
+            let input = request.payload[0];
 
             // At the initial value of 128, the interval should be 1s, the lowest possible interval should be 10ms
             // So, map the value between 0..128 to 10..1000
@@ -115,7 +149,7 @@ fn main() -> ! {
             #[cfg(feature = "defmt")]
             info!("flash_interval_ms {}", flash_interval_ms);
 
-            not_webusb.send_response(ArrayVec::new());
+            not_webusb.send_response(ArrayVec::new()).unwrap();
         }
     }
 }
@@ -1,7 +1,6 @@
 #![no_std]
 #![no_main]
 
-use arrayvec::ArrayVec;
 use bsp::entry;
 use bsp::hal::{clocks::init_clocks_and_plls, pac, sio::Sio, watchdog::Watchdog};
 use cortex_m::prelude::*;
@@ -11,7 +10,7 @@ use defmt::*;
 use defmt_rtt as _;
 use embedded_hal::digital::{InputPin, OutputPin};
 use fugit::ExtU32;
-use not_webusb::NotWebUsb;
+use not_webusb::{NotWebUsb, NotWebUsbError, NotWebUsbStorage, Timeouts};
 use panic_probe as _;
 use rp_pico as bsp;
 use rp2040_hal::Timer;
@@ -22,6 +21,9 @@ use usb_device::{
 use usbd_human_interface_device::device::fido::RawFidoConfig;
 use usbd_human_interface_device::prelude::*;
 
+/// Kept in a static rather than on the stack, as it is over 10KB.
+static mut STORAGE: NotWebUsbStorage<10000> = NotWebUsbStorage::new();
+
 #[entry]
 fn main() -> ! {
     let mut pac = pac::Peripherals::take().unwrap();
@@ -77,7 +79,11 @@ fn main() -> ! {
     flash_led.start(100.millis());
     let mut led_state = false;
 
-    let mut not_webusb = NotWebUsb::<_, 10000>::new(fido, &|_| true);
+    let now = || timer.get_counter();
+    // SAFETY: main is only entered once, so this is the only reference to STORAGE.
+    let storage = unsafe { &mut *core::ptr::addr_of_mut!(STORAGE) };
+    let mut not_webusb =
+        NotWebUsb::new(fido, storage, &|_| true).with_timeouts(&now, Timeouts::default());
 
     #[cfg(feature = "defmt")]
     info!("begin main loop");
@@ -95,15 +101,33 @@ fn main() -> ! {
 
         // TODO: can we make NotWebUsb poll logic allow only calling when usb_dev.poll returns true?
         usb_dev.poll(&mut [not_webusb.fido_class()]);
-        not_webusb.poll().unwrap();
+        match not_webusb.poll() {
+            Ok(()) => {}
+            Err(NotWebUsbError::UsbError) => {
+                core::panic!("not-webusb hit an unrecoverable usb error")
+            }
+            // e.g. a webpage stopped talking to us partway through a request, NotWebUsb has already recovered.
+            Err(_err) => {
+                #[cfg(feature = "defmt")]
+                warn!("not-webusb error {}", _err);
+            }
+        }
 
-        if let Some(request) = not_webusb.check_pending_request() {
+        if let Some(len) = not_webusb
+            .check_pending_request()
+            .map(|request| request.payload.len())
+        {
             #[cfg(feature = "defmt")]
             info!("processing request");
-            let response: ArrayVec<u8, 10000> =
-                request.iter().copied().map(pico_example::rot13).collect();
-
-            not_webusb.send_response(response);
+            // Transform the request into the response in place, a 10000 byte ArrayVec would not fit on the stack.
+            not_webusb
+                .send_response_with(|buf| {
+                    for byte in &mut buf[..len] {
+                        *byte = pico_example::rot13(*byte);
+                    }
+                    len
+                })
+                .unwrap();
         }
     }
 }
@@ -1,7 +1,6 @@
 #![no_std]
 #![no_main]
 
-use arrayvec::ArrayVec;
 use bsp::entry;
 use bsp::hal::{clocks::init_clocks_and_plls, pac, sio::Sio, watchdog::Watchdog};
 use cortex_m::prelude::*;
@@ -11,7 +10,7 @@ use defmt::*;
 use defmt_rtt as _;
 use embedded_hal::digital::{InputPin, OutputPin};
 use fugit::ExtU32;
-use not_webusb::NotWebUsb;
+use not_webusb::{NotWebUsb, NotWebUsbError, NotWebUsbStorage, OriginAllowlist, Timeouts};
 use panic_probe as _;
 use rp_pico as bsp;
 use rp2040_hal::Timer;
@@ -22,6 +21,12 @@ use usb_device::{
 use usbd_human_interface_device::device::fido::RawFidoConfig;
 use usbd_human_interface_device::prelude::*;
 
+/// Kept in a static rather than on the stack, as it is over 10KB.
+static mut STORAGE: NotWebUsbStorage<10000> = NotWebUsbStorage::new();
+
+/// The github pages deployment, and `./serve.sh` for local development.
+static ALLOWLIST: OriginAllowlist<2> = OriginAllowlist::new(["rukai.github.io", "localhost"]);
+
 #[entry]
 fn main() -> ! {
     let mut pac = pac::Peripherals::take().unwrap();
@@ -80,14 +85,12 @@ fn main() -> ! {
     flash_led.start(100.millis());
     let mut led_state = false;
 
-    // sha256 hash of "rukai.github.io"
-    const GITHUB_ORIGIN_HASH: [u8; 32] = [
-        177, 35, 155, 252, 236, 173, 132, 229, 7, 216, 88, 116, 147, 211, 15, 63, 109, 115, 157,
-        167, 78, 170, 168, 131, 115, 65, 251, 76, 71, 75, 154, 114,
-    ];
-
+    let now = || timer.get_counter();
+    // SAFETY: main is only entered once, so this is the only reference to STORAGE.
+    let storage = unsafe { &mut *core::ptr::addr_of_mut!(STORAGE) };
     let mut not_webusb =
-        NotWebUsb::<_, 10000>::new(fido, &|origin_hash| origin_hash == GITHUB_ORIGIN_HASH);
+        NotWebUsb::new(fido, storage, &|origin_hash| ALLOWLIST.allows(origin_hash))
+            .with_timeouts(&now, Timeouts::default());
 
     #[cfg(feature = "defmt")]
     info!("begin main loop");
@@ -103,15 +106,33 @@ fn main() -> ! {
 
         // TODO: can we make NotWebUsb poll logic allow only calling when usb_dev.poll returns true?
         usb_dev.poll(&mut [not_webusb.fido_class()]);
-        not_webusb.poll().unwrap();
+        match not_webusb.poll() {
+            Ok(()) => {}
+            Err(NotWebUsbError::UsbError) => {
+                core::panic!("not-webusb hit an unrecoverable usb error")
+            }
+            // e.g. a webpage stopped talking to us partway through a request, NotWebUsb has already recovered.
+            Err(_err) => {
+                #[cfg(feature = "defmt")]
+                warn!("not-webusb error {}", _err);
+            }
+        }
 
-        if let Some(request) = not_webusb.check_pending_request() {
+        if let Some(len) = not_webusb
+            .check_pending_request()
+            .map(|request| request.payload.len())
+        {
             #[cfg(feature = "defmt")]
             info!("processing request");
-            let response: ArrayVec<u8, 10000> =
-                request.iter().copied().map(pico_example::rot13).collect();
-
-            not_webusb.send_response(response);
+            // Transform the request into the response in place, a 10000 byte ArrayVec would not fit on the stack.
+            not_webusb
+                .send_response_with(|buf| {
+                    for byte in &mut buf[..len] {
+                        *byte = pico_example::rot13(*byte);
+                    }
+                    len
+                })
+                .unwrap();
         }
     }
 }
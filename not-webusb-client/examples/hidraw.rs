@@ -0,0 +1,29 @@
+//! Sends the first argument as a request to the first connected not-webusb device and prints the response.
+//!
+//! e.g. with the rot13 example flashed to a pico: `cargo run --example hidraw -- hello`
+
+#[cfg(all(target_os = "linux", feature = "hidraw"))]
+fn main() {
+    use not_webusb_client::hidraw::{HidrawDevice, enumerate};
+    use not_webusb_client::{Client, ClientOptions};
+
+    let request = std::env::args().nth(1).unwrap_or_default();
+
+    // Matches the VID/PID used by the pico examples.
+    let info = enumerate()
+        .unwrap()
+        .into_iter()
+        .find(|info| info.vendor_id == 0x1209 && info.product_id == 0x0001)
+        .expect("no not-webusb device connected");
+    println!("Using {} at {}", info.name, info.path.display());
+
+    let device = HidrawDevice::open(&info.path).unwrap();
+    let mut client = Client::new(device, ClientOptions::default()).unwrap();
+    let response = client.read_write(request.as_bytes()).unwrap();
+    println!("{}", String::from_utf8_lossy(&response));
+}
+
+#[cfg(not(all(target_os = "linux", feature = "hidraw")))]
+fn main() {
+    println!("This example requires linux and the hidraw feature");
+}
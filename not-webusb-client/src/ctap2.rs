@@ -0,0 +1,98 @@
+//! The CTAP2 getAssertion request/response that devices with the `ctap2` feature accept in place of U2F authenticate.
+
+use crate::Error;
+use serde_cbor::Value;
+use std::collections::BTreeMap;
+
+const COMMAND_GET_ASSERTION: u8 = 0x02;
+const COMMAND_GET_INFO: u8 = 0x04;
+
+/// The status byte of a successful response.
+pub const STATUS_SUCCESS: u8 = 0x00;
+
+/// A successful getAssertion response.
+#[derive(Debug)]
+pub struct Assertion {
+    pub authenticator_data: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+/// The authenticatorGetInfo request.
+pub fn get_info_request() -> Vec<u8> {
+    vec![COMMAND_GET_INFO]
+}
+
+/// Returns true if the GetInfo response lists `FIDO_2_0` among the supported versions.
+///
+/// Like browsers, a malformed response is treated as the device only supporting U2F.
+pub fn supports_fido_2_0(response: &[u8]) -> bool {
+    let Ok(Value::Map(info)) = parse_response(response) else {
+        return false;
+    };
+    match info.get(&Value::Integer(0x01)) {
+        Some(Value::Array(versions)) => versions.contains(&Value::Text("FIDO_2_0".to_owned())),
+        _ => false,
+    }
+}
+
+/// Build a getAssertion request in the same way a browser does for a webauthn `navigator.credentials.get` call with a single allowed credential.
+pub fn get_assertion_request(
+    rp_id: &str,
+    client_data_hash: &[u8; 32],
+    credential_id: &[u8],
+) -> Vec<u8> {
+    let credential = BTreeMap::from([
+        (
+            Value::Text("id".to_owned()),
+            Value::Bytes(credential_id.to_vec()),
+        ),
+        (
+            Value::Text("type".to_owned()),
+            Value::Text("public-key".to_owned()),
+        ),
+    ]);
+    let options = BTreeMap::from([(Value::Text("up".to_owned()), Value::Bool(true))]);
+    let parameters = Value::Map(BTreeMap::from([
+        (Value::Integer(0x01), Value::Text(rp_id.to_owned())),
+        (
+            Value::Integer(0x02),
+            Value::Bytes(client_data_hash.to_vec()),
+        ),
+        (
+            Value::Integer(0x03),
+            Value::Array(vec![Value::Map(credential)]),
+        ),
+        (Value::Integer(0x05), Value::Map(options)),
+    ]));
+
+    let mut request = vec![COMMAND_GET_ASSERTION];
+    serde_cbor::to_writer(&mut request, &parameters).expect("encoding a Value cannot fail");
+    request
+}
+
+/// Parse a getAssertion response, a status other than success is returned as `Error::Ctap2`.
+pub fn parse_get_assertion_response(response: &[u8]) -> Result<Assertion, Error> {
+    let invalid = || Error::InvalidResponse(format!("invalid getAssertion response {response:?}"));
+
+    let Value::Map(mut assertion) = parse_response(response)? else {
+        return Err(invalid());
+    };
+    let mut bytes = |key| match assertion.remove(&Value::Integer(key)) {
+        Some(Value::Bytes(bytes)) => Ok(bytes),
+        _ => Err(invalid()),
+    };
+    Ok(Assertion {
+        authenticator_data: bytes(0x02)?,
+        signature: bytes(0x03)?,
+    })
+}
+
+/// Checks the status byte and decodes the CBOR that follows it.
+fn parse_response(response: &[u8]) -> Result<Value, Error> {
+    match response.split_first() {
+        Some((&STATUS_SUCCESS, cbor)) => serde_cbor::from_slice(cbor)
+            .map_err(|err| Error::InvalidResponse(format!("invalid CBOR response: {err}"))),
+        Some((&status, _)) => Err(Error::Ctap2(status)),
+        None => Err(Error::InvalidResponse("empty CTAP2 response".into())),
+    }
+}
@@ -0,0 +1,124 @@
+//! The CTAPHID protocol used to send U2F and CBOR messages over 64 byte HID reports.
+
+use crate::{Error, Transport};
+
+pub const BROADCAST_CID: u32 = 0xFFFF_FFFF;
+
+pub const CMD_PING: u8 = 0x01;
+pub const CMD_MSG: u8 = 0x03;
+pub const CMD_INIT: u8 = 0x06;
+pub const CMD_CBOR: u8 = 0x10;
+pub const CMD_CANCEL: u8 = 0x11;
+pub const CMD_KEEPALIVE: u8 = 0x3B;
+pub const CMD_ERROR: u8 = 0x3F;
+
+/// Set on the cmd byte of initialization packets, continuation packets use the byte for their sequence number instead.
+const INITIALIZATION_PACKET: u8 = 0x80;
+const INITIALIZATION_DATA_LEN: usize = 64 - 7;
+const CONTINUATION_DATA_LEN: usize = 64 - 5;
+
+/// Split a CTAPHID message into an initialization packet followed by continuation packets.
+pub fn encode_message(cid: u32, cmd: u8, payload: &[u8]) -> Result<Vec<[u8; 64]>, Error> {
+    let length = u16::try_from(payload.len())
+        .map_err(|_| Error::InvalidRequest("CTAPHID message is too large".into()))?;
+
+    let mut packet = [0; 64];
+    packet[0..4].copy_from_slice(&cid.to_be_bytes());
+    packet[4] = INITIALIZATION_PACKET | cmd;
+    packet[5..7].copy_from_slice(&length.to_be_bytes());
+    let (initial, rest) = payload.split_at(payload.len().min(INITIALIZATION_DATA_LEN));
+    packet[7..7 + initial.len()].copy_from_slice(initial);
+
+    let mut packets = vec![packet];
+    for (sequence, chunk) in rest.chunks(CONTINUATION_DATA_LEN).enumerate() {
+        let sequence = u8::try_from(sequence)
+            .ok()
+            .filter(|sequence| *sequence < INITIALIZATION_PACKET)
+            .ok_or_else(|| Error::InvalidRequest("CTAPHID message is too large".into()))?;
+        let mut packet = [0; 64];
+        packet[0..4].copy_from_slice(&cid.to_be_bytes());
+        packet[4] = sequence;
+        packet[5..5 + chunk.len()].copy_from_slice(chunk);
+        packets.push(packet);
+    }
+    Ok(packets)
+}
+
+/// Send a CTAPHID message and wait for the response to it, returning the cmd and payload of the response.
+///
+/// Keepalive messages and packets for other channels are skipped.
+/// A CTAPHID error response is returned as `Error::CtapHid`.
+pub fn transact(
+    transport: &mut impl Transport,
+    cid: u32,
+    cmd: u8,
+    payload: &[u8],
+) -> Result<(u8, Vec<u8>), Error> {
+    for packet in encode_message(cid, cmd, payload)? {
+        transport.write_report(&packet)?;
+    }
+
+    let (response_cmd, length, mut response) = loop {
+        let packet = transport.read_report()?;
+        if packet_cid(&packet) != cid || packet[4] & INITIALIZATION_PACKET == 0 {
+            continue;
+        }
+        let response_cmd = packet[4] & !INITIALIZATION_PACKET;
+        if response_cmd == CMD_KEEPALIVE {
+            continue;
+        }
+        let length = u16::from_be_bytes([packet[5], packet[6]]) as usize;
+        let initial = &packet[7..7 + length.min(INITIALIZATION_DATA_LEN)];
+        break (response_cmd, length, initial.to_vec());
+    };
+
+    let mut expected_sequence = 0;
+    while response.len() < length {
+        let packet = transport.read_report()?;
+        if packet_cid(&packet) != cid {
+            continue;
+        }
+        if packet[4] != expected_sequence {
+            return Err(Error::InvalidResponse(format!(
+                "expected CTAPHID continuation packet {expected_sequence} but received {packet:?}"
+            )));
+        }
+        let remaining = (length - response.len()).min(CONTINUATION_DATA_LEN);
+        response.extend_from_slice(&packet[5..5 + remaining]);
+        expected_sequence += 1;
+    }
+
+    if response_cmd == CMD_ERROR {
+        return Err(Error::CtapHid(response.first().copied().unwrap_or(0)));
+    }
+    if response_cmd != cmd {
+        return Err(Error::InvalidResponse(format!(
+            "sent CTAPHID command {cmd:#x} but received response for {response_cmd:#x}"
+        )));
+    }
+    Ok((response_cmd, response))
+}
+
+/// Allocate a new channel, returning its CID.
+pub fn init(transport: &mut impl Transport) -> Result<u32, Error> {
+    let nonce = nonce();
+    let (_, response) = transact(transport, BROADCAST_CID, CMD_INIT, &nonce)?;
+    if response.len() < 17 || response[0..8] != nonce {
+        return Err(Error::InvalidResponse(format!(
+            "invalid CTAPHID init response {response:?}"
+        )));
+    }
+    Ok(u32::from_be_bytes(response[8..12].try_into().unwrap()))
+}
+
+fn packet_cid(packet: &[u8; 64]) -> u32 {
+    u32::from_be_bytes(packet[0..4].try_into().unwrap())
+}
+
+/// The nonce only needs to distinguish our init response from those of other clients initializing at the same time.
+fn nonce() -> [u8; 8] {
+    let time = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    (time.as_nanos() as u64 ^ std::process::id() as u64).to_be_bytes()
+}
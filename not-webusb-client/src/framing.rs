@@ -0,0 +1,284 @@
+//! The not-webusb protocol that is smuggled through U2F authenticate requests and responses.
+//!
+//! Requests are split into key handles, each starting with a [`RequestHeader`] byte.
+//! Responses are returned in the two ASN.1 integers of the authenticate signature.
+//! The first response signature starts with the total response length as a big endian u32.
+//!
+//! Devices with the `ctap2` feature answer CTAP2 getAssertion requests too, where key handles are sent as credential IDs.
+//! Those accept key handles of up to [`MAX_CREDENTIAL_ID_LEN`] bytes and return longer signatures,
+//! which they advertise via the signCount of every assertion, see [`max_key_handle_len`].
+//!
+//! Nothing in here depends on how the key handles reach the device, so it is shared by every client implementation.
+//! [`Exchange`] ties it all together for a single request/response.
+
+use crate::Error;
+
+/// The signature sent in place of a response when the origin lacks the permissions required by the request.
+const FORBIDDEN_SIGNATURE: &[u8] = &[0x30, 0x00];
+
+/// U2F key handles are at most 255 bytes.
+pub const MAX_KEY_HANDLE_LEN: usize = 255;
+
+/// The amount of request bytes that fit in a single key handle, after the header byte.
+pub const REQUEST_CHUNK_LEN: usize = MAX_KEY_HANDLE_LEN - 1;
+
+/// CTAP2 credential IDs, and therefore key handles sent via CTAP2, are at most 1023 bytes.
+pub const MAX_CREDENTIAL_ID_LEN: usize = 1023;
+
+/// The first byte of every key handle sent to the device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum RequestHeader {
+    /// More request key handles will follow this one.
+    InitialRequest = 0,
+    /// Requests the next part of the response.
+    NeedMoreResponseData = 1,
+    /// The last key handle of a request, the device replies with the first part of the response.
+    FinalRequest = 2,
+}
+
+/// Split `request` into the key handles that must be sent to the device, in order.
+pub fn request_key_handles(request: &[u8]) -> Vec<Vec<u8>> {
+    request_key_handles_with_len(request, MAX_KEY_HANDLE_LEN)
+}
+
+/// Split `request` into key handles of at most `max_key_handle_len` bytes, see `request_key_handles`.
+pub fn request_key_handles_with_len(request: &[u8], max_key_handle_len: usize) -> Vec<Vec<u8>> {
+    let mut chunks: Vec<&[u8]> = request.chunks(max_key_handle_len - 1).collect();
+    if chunks.is_empty() {
+        chunks.push(&[]);
+    }
+
+    let final_index = chunks.len() - 1;
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(i, chunk)| {
+            let header = if i == final_index {
+                RequestHeader::FinalRequest
+            } else {
+                RequestHeader::InitialRequest
+            };
+            let mut key_handle = Vec::with_capacity(chunk.len() + 1);
+            key_handle.push(header as u8);
+            key_handle.extend_from_slice(chunk);
+            key_handle
+        })
+        .collect()
+}
+
+/// The longest key handle that the device accepts, given the authenticatorData of an assertion it returned.
+///
+/// Devices answering via CTAP2 send the length as the signCount, U2F responses always have a signCount of 0.
+pub fn max_key_handle_len(authenticator_data: &[u8]) -> usize {
+    match authenticator_data.get(33..37) {
+        Some(sign_count) => {
+            let sign_count = u32::from_be_bytes(sign_count.try_into().unwrap()) as usize;
+            sign_count.clamp(MAX_KEY_HANDLE_LEN, MAX_CREDENTIAL_ID_LEN)
+        }
+        None => MAX_KEY_HANDLE_LEN,
+    }
+}
+
+/// Returns the bytes smuggled in the two ASN.1 integers of an authenticate signature.
+///
+/// Each integer is prefixed by a 0x7f byte to keep it positive, which is stripped.
+/// Lengths may use the short or long form, since CTAP2 signatures are longer than 127 bytes.
+pub fn signature_payload(signature: &[u8]) -> Result<[&[u8]; 2], Error> {
+    let invalid = || Error::InvalidResponse(format!("invalid signature {signature:?}"));
+
+    let Some((&0x30, rest)) = signature.split_first() else {
+        return Err(invalid());
+    };
+    let (sequence_len, rest) = asn1_length(rest).ok_or_else(invalid)?;
+    if rest.len() != sequence_len {
+        return Err(invalid());
+    }
+    let (first, rest) = asn1_integer(rest).ok_or_else(invalid)?;
+    let (second, rest) = asn1_integer(rest).ok_or_else(invalid)?;
+    if !rest.is_empty() {
+        return Err(invalid());
+    }
+    Ok([first, second])
+}
+
+/// Returns the integer body with the 0x7f prefix stripped, and the remaining bytes.
+fn asn1_integer(bytes: &[u8]) -> Option<(&[u8], &[u8])> {
+    let Some((&0x02, rest)) = bytes.split_first() else {
+        return None;
+    };
+    let (len, rest) = asn1_length(rest)?;
+    if rest.len() < len {
+        return None;
+    }
+    let (integer, rest) = rest.split_at(len);
+    let (&0x7f, integer) = integer.split_first()? else {
+        return None;
+    };
+    Some((integer, rest))
+}
+
+/// Returns a DER length and the remaining bytes.
+fn asn1_length(bytes: &[u8]) -> Option<(usize, &[u8])> {
+    let (&first, rest) = bytes.split_first()?;
+    if first < 0x80 {
+        return Some((first as usize, rest));
+    }
+    // The long form gives the amount of length bytes that follow, more than 2 is never needed for a signature.
+    let len_bytes = (first & 0x7f) as usize;
+    if len_bytes > 2 || rest.len() < len_bytes {
+        return None;
+    }
+    let (len, rest) = rest.split_at(len_bytes);
+    let len = len.iter().fold(0, |len, byte| len << 8 | *byte as usize);
+    Some((len, rest))
+}
+
+/// Reassembles a response from the signatures returned by the device.
+#[derive(Default)]
+pub struct ResponseDecoder {
+    /// The total response length, known once the first signature has been received.
+    len: Option<usize>,
+    response: Vec<u8>,
+}
+
+impl ResponseDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Process the signature returned for a `FinalRequest` or `NeedMoreResponseData` key handle.
+    pub fn push_signature(&mut self, signature: &[u8]) -> Result<(), Error> {
+        let [first, second] = signature_payload(signature)?;
+        let first = match self.len {
+            None => {
+                let Some((len, first)) = first.split_first_chunk() else {
+                    return Err(Error::InvalidResponse(format!(
+                        "initial response signature is missing the length {signature:?}"
+                    )));
+                };
+                let len = u32::from_be_bytes(*len) as usize;
+                self.len = Some(len);
+                first
+            }
+            Some(_) => first,
+        };
+
+        for part in [first, second] {
+            let remaining = self.remaining();
+            self.response
+                .extend_from_slice(&part[..part.len().min(remaining)]);
+        }
+        Ok(())
+    }
+
+    /// The amount of response bytes that have not yet been received.
+    fn remaining(&self) -> usize {
+        self.len.unwrap_or(0) - self.response.len()
+    }
+
+    /// Returns true once the entire response has been received.
+    pub fn is_complete(&self) -> bool {
+        self.len.is_some() && self.remaining() == 0
+    }
+
+    /// Returns the response, which will be incomplete if called before `ResponseDecoder::is_complete` returns true.
+    pub fn into_response(self) -> Vec<u8> {
+        self.response
+    }
+}
+
+/// A single request/response exchange with the device, independent of how key handles are actually sent.
+///
+/// Send the key handle returned by `Exchange::next_key_handle` as the allowed credential of an authenticate request,
+/// pass the returned signature to `Exchange::push_signature` and repeat until there are no more key handles to send.
+///
+/// When the authenticatorData of the assertion is available, as it is to webpages, pass it along via `Exchange::push_assertion` instead,
+/// so that the rest of the request is sent in longer key handles if the device answered via CTAP2.
+pub struct Exchange {
+    request_key_handles: Vec<Vec<u8>>,
+    max_key_handle_len: usize,
+    /// The amount of `request_key_handles` that the device has returned a signature for.
+    sent: usize,
+    decoder: ResponseDecoder,
+}
+
+impl Exchange {
+    pub fn new(request: &[u8]) -> Self {
+        Exchange {
+            request_key_handles: request_key_handles(request),
+            max_key_handle_len: MAX_KEY_HANDLE_LEN,
+            sent: 0,
+            decoder: ResponseDecoder::new(),
+        }
+    }
+
+    /// The key handle to send next, or `None` once the entire response has been received.
+    pub fn next_key_handle(&self) -> Option<&[u8]> {
+        const NEED_MORE_RESPONSE_DATA: &[u8] = &[RequestHeader::NeedMoreResponseData as u8];
+
+        if let Some(key_handle) = self.request_key_handles.get(self.sent) {
+            Some(key_handle)
+        } else if !self.decoder.is_complete() {
+            Some(NEED_MORE_RESPONSE_DATA)
+        } else {
+            None
+        }
+    }
+
+    /// Process the signature returned for the key handle from `Exchange::next_key_handle`.
+    ///
+    /// The device returns an empty signature when its `web_origin_filter` rejects the origin, which results in `Error::OriginRejected`,
+    /// and an empty ASN.1 sequence when the origin lacks the permissions the request requires, which results in `Error::Forbidden`.
+    pub fn push_signature(&mut self, signature: &[u8]) -> Result<(), Error> {
+        if signature.is_empty() {
+            return Err(Error::OriginRejected);
+        }
+        if signature == FORBIDDEN_SIGNATURE {
+            return Err(Error::Forbidden);
+        }
+        if self.sent < self.request_key_handles.len() {
+            self.sent += 1;
+            // The signatures for `InitialRequest` key handles contain no response data.
+            if self.sent < self.request_key_handles.len() {
+                return Ok(());
+            }
+        }
+        self.decoder.push_signature(signature)
+    }
+
+    /// Process the assertion returned for the key handle from `Exchange::next_key_handle`, see `Exchange::push_signature`.
+    ///
+    /// The rest of the request is split into key handles as long as the device accepts, see `max_key_handle_len`.
+    pub fn push_assertion(
+        &mut self,
+        authenticator_data: &[u8],
+        signature: &[u8],
+    ) -> Result<(), Error> {
+        self.push_signature(signature)?;
+        self.set_max_key_handle_len(max_key_handle_len(authenticator_data));
+        Ok(())
+    }
+
+    /// Split the key handles that have not been sent yet into key handles of at most `max_key_handle_len` bytes.
+    pub fn set_max_key_handle_len(&mut self, max_key_handle_len: usize) {
+        if max_key_handle_len == self.max_key_handle_len
+            || self.sent >= self.request_key_handles.len()
+        {
+            return;
+        }
+        self.max_key_handle_len = max_key_handle_len;
+        let unsent: Vec<u8> = self.request_key_handles[self.sent..]
+            .iter()
+            .flat_map(|key_handle| key_handle[1..].iter().copied())
+            .collect();
+        self.request_key_handles.truncate(self.sent);
+        self.request_key_handles
+            .extend(request_key_handles_with_len(&unsent, max_key_handle_len));
+    }
+
+    /// Returns the response, which will be incomplete if called before `Exchange::next_key_handle` returns `None`.
+    pub fn into_response(self) -> Vec<u8> {
+        self.decoder.into_response()
+    }
+}
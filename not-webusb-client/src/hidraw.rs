@@ -0,0 +1,185 @@
+//! A [`Transport`] that talks to FIDO devices directly through the Linux hidraw interface.
+//!
+//! This bypasses the browser and the OS FIDO prompt entirely, which is useful for factory test stations and desktop tools.
+//! The user needs read/write access to the `/dev/hidraw*` node, usually granted via a udev rule.
+
+use crate::Transport;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// The HID usage page assigned to FIDO authenticators.
+pub const FIDO_USAGE_PAGE: u16 = 0xF1D0;
+
+/// A hidraw node whose report descriptor declares the FIDO usage page.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HidrawDeviceInfo {
+    /// e.g. `/dev/hidraw3`
+    pub path: PathBuf,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// The product name reported by the device, may be empty.
+    pub name: String,
+}
+
+/// Lists all FIDO HID interfaces, this includes both not-webusb devices and regular security keys.
+///
+/// Filter the result by `vendor_id` and `product_id` to find your device.
+pub fn enumerate() -> io::Result<Vec<HidrawDeviceInfo>> {
+    let mut devices = vec![];
+    let entries = match fs::read_dir("/sys/class/hidraw") {
+        Ok(entries) => entries,
+        // The hidraw module is not loaded, so there cannot be any devices.
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(devices),
+        Err(err) => return Err(err),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let sys_device = entry.path().join("device");
+        // The device may have been unplugged since listing the directory.
+        let Ok(report_descriptor) = fs::read(sys_device.join("report_descriptor")) else {
+            continue;
+        };
+        if !is_fido_report_descriptor(&report_descriptor) {
+            continue;
+        }
+        let uevent = fs::read_to_string(sys_device.join("uevent")).unwrap_or_default();
+        let (vendor_id, product_id, name) = parse_uevent(&uevent);
+        devices.push(HidrawDeviceInfo {
+            path: Path::new("/dev").join(entry.file_name()),
+            vendor_id,
+            product_id,
+            name,
+        });
+    }
+    devices.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(devices)
+}
+
+/// Returns true if any usage page item in the HID report descriptor is the FIDO usage page.
+pub fn is_fido_report_descriptor(descriptor: &[u8]) -> bool {
+    const LONG_ITEM: u8 = 0xFE;
+    const USAGE_PAGE_TAG: u8 = 0x04;
+
+    let mut i = 0;
+    while let Some(&prefix) = descriptor.get(i) {
+        if prefix == LONG_ITEM {
+            let Some(&data_len) = descriptor.get(i + 1) else {
+                return false;
+            };
+            i += 3 + data_len as usize;
+            continue;
+        }
+        let data_len = match prefix & 0x03 {
+            3 => 4,
+            len => len as usize,
+        };
+        let Some(data) = descriptor.get(i + 1..i + 1 + data_len) else {
+            return false;
+        };
+        if prefix & 0xFC == USAGE_PAGE_TAG {
+            let mut value = [0; 4];
+            value[..data.len()].copy_from_slice(data);
+            if u32::from_le_bytes(value) == FIDO_USAGE_PAGE as u32 {
+                return true;
+            }
+        }
+        i += 1 + data_len;
+    }
+    false
+}
+
+/// Extract the vendor id, product id and name from the uevent file of a hid device.
+fn parse_uevent(uevent: &str) -> (u16, u16, String) {
+    let mut vendor_id = 0;
+    let mut product_id = 0;
+    let mut name = String::new();
+    for line in uevent.lines() {
+        if let Some(id) = line.strip_prefix("HID_ID=") {
+            // formatted as bus:vendor:product, e.g. 0003:00001209:00000001
+            let mut parts = id.split(':').skip(1);
+            let mut parse = || {
+                parts
+                    .next()
+                    .and_then(|part| u32::from_str_radix(part, 16).ok())
+                    .unwrap_or(0) as u16
+            };
+            vendor_id = parse();
+            product_id = parse();
+        } else if let Some(value) = line.strip_prefix("HID_NAME=") {
+            name = value.to_owned();
+        }
+    }
+    (vendor_id, product_id, name)
+}
+
+/// An open hidraw node.
+pub struct HidrawDevice {
+    file: File,
+    read_timeout: Duration,
+}
+
+impl HidrawDevice {
+    /// Open a hidraw node, e.g. one returned by [`enumerate`].
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(HidrawDevice {
+            file,
+            read_timeout: Duration::from_secs(5),
+        })
+    }
+
+    /// How long `Transport::read_report` waits for a report before returning an `io::ErrorKind::TimedOut` error.
+    /// Defaults to 5 seconds.
+    pub fn set_read_timeout(&mut self, read_timeout: Duration) {
+        self.read_timeout = read_timeout;
+    }
+}
+
+impl Transport for HidrawDevice {
+    fn write_report(&mut self, report: &[u8; 64]) -> io::Result<()> {
+        // FIDO devices do not use numbered reports, hidraw indicates this with a leading report id of 0.
+        let mut buffer = [0; 65];
+        buffer[1..].copy_from_slice(report);
+        let written = self.file.write(&buffer)?;
+        if written != buffer.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                format!("only wrote {written} bytes of a hid report"),
+            ));
+        }
+        Ok(())
+    }
+
+    fn read_report(&mut self) -> io::Result<[u8; 64]> {
+        let mut poll_fd = libc::pollfd {
+            fd: self.file.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout_ms = self.read_timeout.as_millis().try_into().unwrap_or(i32::MAX);
+        // SAFETY: poll_fd is a valid pollfd that outlives the call, and we pass a count of 1.
+        match unsafe { libc::poll(&mut poll_fd, 1, timeout_ms) } {
+            -1 => return Err(io::Error::last_os_error()),
+            0 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "hidraw device did not send a report",
+                ));
+            }
+            _ => {}
+        }
+
+        let mut report = [0; 64];
+        let read = self.file.read(&mut report)?;
+        if read != report.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("received a {read} byte hid report, expected 64 bytes"),
+            ));
+        }
+        Ok(report)
+    }
+}
@@ -0,0 +1,248 @@
+//! A host side client for not-webusb devices.
+//!
+//! [`Client`] does the same thing as `web/not_webusb.js` and the browser together:
+//! Requests are split into key handles, sent as U2F authenticate requests framed as CTAPHID messages,
+//! and the response is reassembled from the returned signatures.
+//! Like browsers, CTAP2 getAssertion requests are sent instead if the device supports them, see `ClientOptions::ctap2`.
+//!
+//! The device is reached via a [`Transport`], which only needs to send and receive raw 64 byte HID reports.
+//! On linux, `hidraw::HidrawDevice` talks to real devices without going through a browser.
+//!
+//! With the `rpc` feature, `Client` implements `not_webusb::Caller`, so the methods of a `not_webusb::rpc!` interface can be called on it.
+
+pub mod ctap2;
+pub mod ctaphid;
+pub mod framing;
+#[cfg(all(target_os = "linux", feature = "hidraw"))]
+pub mod hidraw;
+pub mod u2f;
+
+use crate::framing::Exchange;
+use crate::u2f::{ApduEncoding, AuthenticateControl};
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Sends and receives the 64 byte HID reports of a FIDO device.
+pub trait Transport {
+    fn write_report(&mut self, report: &[u8; 64]) -> io::Result<()>;
+
+    /// Blocks until a report is received.
+    /// Should eventually return an `io::ErrorKind::TimedOut` error if the device never sends a report.
+    fn read_report(&mut self) -> io::Result<[u8; 64]>;
+}
+
+impl<T: Transport + ?Sized> Transport for &mut T {
+    fn write_report(&mut self, report: &[u8; 64]) -> io::Result<()> {
+        (**self).write_report(report)
+    }
+
+    fn read_report(&mut self) -> io::Result<[u8; 64]> {
+        (**self).read_report()
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("transport error: {0}")]
+    Transport(#[from] io::Error),
+    #[error("device returned CTAPHID error {0:#04x}")]
+    CtapHid(u8),
+    #[error("device returned U2F status word {0:#06x}")]
+    StatusWord(u16),
+    #[error("device returned CTAP2 status {0:#04x}")]
+    Ctap2(u8),
+    #[error("device rejected the origin")]
+    OriginRejected,
+    #[error("device forbids the request for this origin")]
+    Forbidden,
+    #[error("device did not respond before the timeout")]
+    Timeout,
+    #[error("invalid request: {0}")]
+    InvalidRequest(String),
+    #[error("invalid response: {0}")]
+    InvalidResponse(String),
+    #[cfg(feature = "rpc")]
+    #[error("device rejected the call: {0:?}")]
+    Rpc(not_webusb::RequestError),
+}
+
+/// Configures how `Client` imitates a browser.
+#[derive(Clone, Debug)]
+pub struct ClientOptions {
+    /// The webauthn rpId, its sha256 hash is what the device's `web_origin_filter` receives.
+    pub rp_id: String,
+    /// The origin of the emulated webpage, used in the client data.
+    pub origin: String,
+    pub apdu_encoding: ApduEncoding,
+    /// How long to wait between repeated sign requests while the device indicates user presence is required.
+    pub sign_retry_interval: Duration,
+    /// How long to keep retrying a sign request before giving up with `Error::Timeout`.
+    pub sign_timeout: Duration,
+    /// Send CTAP2 getAssertion requests if the device lists `FIDO_2_0` in its GetInfo response, as browsers do.
+    /// Otherwise, or if this is false, U2F authenticate requests are sent.
+    pub ctap2: bool,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        ClientOptions {
+            rp_id: "localhost".to_owned(),
+            origin: "https://localhost".to_owned(),
+            apdu_encoding: ApduEncoding::default(),
+            sign_retry_interval: Duration::from_millis(100),
+            sign_timeout: Duration::from_secs(30),
+            ctap2: true,
+        }
+    }
+}
+
+/// Communicates with a not-webusb device over a single CTAPHID channel.
+pub struct Client<T: Transport> {
+    transport: T,
+    cid: u32,
+    options: ClientOptions,
+    rp_id_hash: [u8; 32],
+    client_data_hash: [u8; 32],
+    /// True if requests are sent via CTAP2 getAssertion instead of U2F authenticate.
+    ctap2: bool,
+}
+
+impl<T: Transport> Client<T> {
+    /// Allocates a CTAPHID channel on the device and checks whether it supports CTAP2.
+    pub fn new(mut transport: T, options: ClientOptions) -> Result<Self, Error> {
+        let cid = ctaphid::init(&mut transport)?;
+        let ctap2 = options.ctap2
+            && match ctaphid::transact(
+                &mut transport,
+                cid,
+                ctaphid::CMD_CBOR,
+                &ctap2::get_info_request(),
+            ) {
+                Ok((_, response)) => ctap2::supports_fido_2_0(&response),
+                // Devices that only support U2F may reject CBOR messages entirely.
+                Err(Error::CtapHid(_)) => false,
+                Err(err) => return Err(err),
+            };
+        Ok(Client {
+            transport,
+            cid,
+            rp_id_hash: u2f::rp_id_hash(&options.rp_id),
+            client_data_hash: u2f::client_data_hash(&options.origin),
+            options,
+            ctap2,
+        })
+    }
+
+    /// Sends a request to the device and returns its response.
+    pub fn read_write(&mut self, request: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut exchange = Exchange::new(request);
+        while let Some(key_handle) = exchange.next_key_handle() {
+            if self.ctap2 {
+                let assertion = self.get_assertion(key_handle)?;
+                exchange.push_assertion(&assertion.authenticator_data, &assertion.signature)?;
+            } else {
+                let signature = self.authenticate(key_handle)?;
+                exchange.push_signature(&signature)?;
+            }
+        }
+        Ok(exchange.into_response())
+    }
+
+    /// Returns true if requests are sent via CTAP2 getAssertion, see `ClientOptions::ctap2`.
+    pub fn uses_ctap2(&self) -> bool {
+        self.ctap2
+    }
+
+    /// Performs a single `navigator.credentials.get` call via CTAP2, returning the assertion.
+    ///
+    /// Unlike U2F, the device holds the request open until its response is ready, sending keepalives meanwhile.
+    /// The signature is empty if the device rejected the origin.
+    pub fn get_assertion(&mut self, credential_id: &[u8]) -> Result<ctap2::Assertion, Error> {
+        let request = ctap2::get_assertion_request(
+            &self.options.rp_id,
+            &self.client_data_hash,
+            credential_id,
+        );
+        let (_, response) =
+            ctaphid::transact(&mut self.transport, self.cid, ctaphid::CMD_CBOR, &request)?;
+        ctap2::parse_get_assertion_response(&response)
+    }
+
+    /// Performs a single `navigator.credentials.get` call, returning the signature.
+    ///
+    /// The signature is empty if the device rejected the origin.
+    pub fn authenticate(&mut self, key_handle: &[u8]) -> Result<Vec<u8>, Error> {
+        // Like a browser, first check that the device recognizes the key handle.
+        let response = self.send_authenticate(AuthenticateControl::CheckOnly, key_handle)?;
+        match u2f::split_status_word(&response)? {
+            (_, u2f::SW_CONDITIONS_NOT_SATISFIED) => {}
+            (_, status_word) => return Err(Error::StatusWord(status_word)),
+        }
+
+        // Then repeatedly request a signature until the device reports that user presence was confirmed.
+        let start = Instant::now();
+        loop {
+            let response = self
+                .send_authenticate(AuthenticateControl::EnforceUserPresenceAndSign, key_handle)?;
+            match u2f::split_status_word(&response)? {
+                (data, u2f::SW_NO_ERROR) => {
+                    return Ok(u2f::authenticate_signature(data)?.to_vec());
+                }
+                (_, u2f::SW_CONDITIONS_NOT_SATISFIED) => {
+                    if start.elapsed() > self.options.sign_timeout {
+                        return Err(Error::Timeout);
+                    }
+                    thread::sleep(self.options.sign_retry_interval);
+                }
+                (_, status_word) => return Err(Error::StatusWord(status_word)),
+            }
+        }
+    }
+
+    fn send_authenticate(
+        &mut self,
+        control: AuthenticateControl,
+        key_handle: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        let apdu = u2f::authenticate_apdu(
+            control,
+            &self.client_data_hash,
+            &self.rp_id_hash,
+            key_handle,
+            self.options.apdu_encoding,
+        );
+        let (_, response) =
+            ctaphid::transact(&mut self.transport, self.cid, ctaphid::CMD_MSG, &apdu)?;
+        Ok(response)
+    }
+
+    /// The CTAPHID channel allocated to this client.
+    pub fn cid(&self) -> u32 {
+        self.cid
+    }
+
+    pub fn into_transport(self) -> T {
+        self.transport
+    }
+}
+
+#[cfg(feature = "rpc")]
+impl<T: Transport> not_webusb::Caller for Client<T> {
+    type Error = Error;
+
+    fn call<A: serde::Serialize, O: serde::de::DeserializeOwned>(
+        &mut self,
+        method: u16,
+        args: &A,
+    ) -> Result<O, Error> {
+        let request = postcard::to_stdvec(&(method, args))
+            .map_err(|err| Error::InvalidRequest(err.to_string()))?;
+        let response = self.read_write(&request)?;
+        match postcard::from_bytes::<Result<O, not_webusb::RequestError>>(&response) {
+            Ok(Ok(output)) => Ok(output),
+            Ok(Err(err)) => Err(Error::Rpc(err)),
+            Err(err) => Err(Error::InvalidResponse(err.to_string())),
+        }
+    }
+}
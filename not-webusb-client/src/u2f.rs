@@ -0,0 +1,92 @@
+//! The U2F authenticate request/response that not-webusb data is smuggled through.
+
+use crate::Error;
+use sha2::{Digest, Sha256};
+
+const INS_AUTHENTICATE: u8 = 0x02;
+
+/// The status word returned on success.
+pub const SW_NO_ERROR: u16 = 0x9000;
+/// Returned to a check-only request when the key handle is valid, or when user presence is required to sign.
+pub const SW_CONDITIONS_NOT_SATISFIED: u16 = 0x6985;
+
+/// The P1 byte of an authenticate request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum AuthenticateControl {
+    CheckOnly = 0x07,
+    EnforceUserPresenceAndSign = 0x03,
+    DontEnforceUserPresenceAndSign = 0x08,
+}
+
+/// How the length fields of an APDU are encoded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ApduEncoding {
+    /// Use a 1 byte Lc and Le if the request body fits, otherwise fall back to extended length.
+    ShortWhenPossible,
+    /// Always use a 3 byte Lc and 2 byte Le.
+    #[default]
+    Extended,
+}
+
+/// Build an authenticate APDU in the same way a browser does for a webauthn `navigator.credentials.get` call.
+pub fn authenticate_apdu(
+    control: AuthenticateControl,
+    client_data_hash: &[u8; 32],
+    rp_id_hash: &[u8; 32],
+    key_handle: &[u8],
+    encoding: ApduEncoding,
+) -> Vec<u8> {
+    let mut body = Vec::with_capacity(65 + key_handle.len());
+    body.extend_from_slice(client_data_hash);
+    body.extend_from_slice(rp_id_hash);
+    body.push(key_handle.len() as u8);
+    body.extend_from_slice(key_handle);
+
+    let mut apdu = vec![0x00, INS_AUTHENTICATE, control as u8, 0x00];
+    match encoding {
+        ApduEncoding::ShortWhenPossible if body.len() <= 255 => {
+            apdu.push(body.len() as u8);
+            apdu.extend_from_slice(&body);
+            apdu.push(0x00);
+        }
+        _ => {
+            apdu.push(0x00);
+            apdu.extend_from_slice(&(body.len() as u16).to_be_bytes());
+            apdu.extend_from_slice(&body);
+            apdu.extend_from_slice(&[0x00, 0x00]);
+        }
+    }
+    apdu
+}
+
+/// Split a U2F response into its data and status word.
+pub fn split_status_word(response: &[u8]) -> Result<(&[u8], u16), Error> {
+    match response.split_last_chunk() {
+        Some((data, status_word)) => Ok((data, u16::from_be_bytes(*status_word))),
+        None => Err(Error::InvalidResponse(format!(
+            "U2F response is missing the status word {response:?}"
+        ))),
+    }
+}
+
+/// Returns the signature of a successful authenticate response.
+pub fn authenticate_signature(data: &[u8]) -> Result<&[u8], Error> {
+    // skip user presence and counter
+    data.get(5..).ok_or_else(|| {
+        Error::InvalidResponse(format!("authenticate response is too short {data:?}"))
+    })
+}
+
+/// The `application_parameter` the browser sends for the given webauthn rpId.
+pub fn rp_id_hash(rp_id: &str) -> [u8; 32] {
+    Sha256::digest(rp_id.as_bytes()).into()
+}
+
+/// The `challenge_parameter` the browser sends for a `navigator.credentials.get` call with an empty challenge.
+pub fn client_data_hash(origin: &str) -> [u8; 32] {
+    let client_data = format!(
+        r#"{{"type":"webauthn.get","challenge":"","origin":"{origin}","crossOrigin":false}}"#
+    );
+    Sha256::digest(client_data.as_bytes()).into()
+}
@@ -0,0 +1,154 @@
+use not_webusb_client::framing::*;
+
+#[test]
+fn key_handles() {
+    assert_eq!(request_key_handles(&[]), vec![vec![2]]);
+    assert_eq!(request_key_handles(&[5, 6]), vec![vec![2, 5, 6]]);
+
+    let request = vec![7; REQUEST_CHUNK_LEN + 1];
+    let key_handles = request_key_handles(&request);
+    assert_eq!(key_handles.len(), 2);
+    assert_eq!(key_handles[0].len(), MAX_KEY_HANDLE_LEN);
+    assert_eq!(key_handles[0][0], 0);
+    assert_eq!(key_handles[1], vec![2, 7]);
+}
+
+fn signature(first: &[u8], second: &[u8]) -> Vec<u8> {
+    let mut signature = vec![0x30, 0x44, 0x02, 0x20, 0x7f];
+    signature.extend(first.iter().copied().chain(std::iter::repeat(0)).take(31));
+    signature.extend([0x02, 0x20, 0x7f]);
+    signature.extend(second.iter().copied().chain(std::iter::repeat(0)).take(31));
+    signature
+}
+
+#[test]
+fn decode_response() {
+    let mut decoder = ResponseDecoder::new();
+    decoder
+        .push_signature(&signature(&[0, 0, 0, 70, 1, 2, 3], &[4, 5]))
+        .unwrap();
+    assert!(!decoder.is_complete());
+    decoder.push_signature(&signature(&[9; 31], &[])).unwrap();
+    assert!(decoder.is_complete());
+
+    let response = decoder.into_response();
+    assert_eq!(response.len(), 70);
+    assert_eq!(response[0..3], [1, 2, 3]);
+    assert_eq!(response[27..29], [4, 5]);
+    assert_eq!(response[58..70], [9; 12]);
+}
+
+#[test]
+fn decode_invalid_signature() {
+    let mut decoder = ResponseDecoder::new();
+    assert!(decoder.push_signature(&[]).is_err());
+    let mut truncated = signature(&[0, 0, 0, 1], &[]);
+    truncated.pop();
+    assert!(decoder.push_signature(&truncated).is_err());
+}
+
+#[test]
+fn exchange() {
+    let request = vec![3; REQUEST_CHUNK_LEN + 1];
+    let mut exchange = Exchange::new(&request);
+
+    assert_eq!(exchange.next_key_handle().unwrap()[0], 0);
+    // The signature for an initial request key handle is ignored.
+    exchange.push_signature(&signature(&[], &[])).unwrap();
+    assert_eq!(exchange.next_key_handle(), Some(&[2, 3][..]));
+    exchange
+        .push_signature(&signature(&[0, 0, 0, 60], &[]))
+        .unwrap();
+    assert_eq!(exchange.next_key_handle(), Some(&[1][..]));
+    exchange.push_signature(&signature(&[5; 31], &[])).unwrap();
+    assert_eq!(exchange.next_key_handle(), None);
+
+    let response = exchange.into_response();
+    assert_eq!(response.len(), 60);
+    assert_eq!(response[58..], [5, 5]);
+}
+
+#[test]
+fn exchange_origin_rejected() {
+    let mut exchange = Exchange::new(b"request");
+    assert!(matches!(
+        exchange.push_signature(&[]),
+        Err(not_webusb_client::Error::OriginRejected)
+    ));
+}
+
+#[test]
+fn exchange_forbidden() {
+    let mut exchange = Exchange::new(b"request");
+    assert!(matches!(
+        exchange.push_signature(&[0x30, 0x00]),
+        Err(not_webusb_client::Error::Forbidden)
+    ));
+}
+
+fn authenticator_data(sign_count: u32) -> Vec<u8> {
+    let mut authenticator_data = vec![0; 33];
+    authenticator_data.extend(sign_count.to_be_bytes());
+    authenticator_data
+}
+
+#[test]
+fn decode_long_form_signature() {
+    let mut integer = vec![0x02, 0x82, 0x01, 0x00, 0x7f];
+    integer.extend(300u32.to_be_bytes());
+    integer.extend([8; 251]);
+    let mut signature = vec![0x30, 0x82, 0x02, 0x08];
+    signature.extend(&integer);
+    signature.extend([0x02, 0x82, 0x01, 0x00, 0x7f]);
+    signature.extend([9; 255]);
+
+    let mut decoder = ResponseDecoder::new();
+    decoder.push_signature(&signature).unwrap();
+    assert!(decoder.is_complete());
+    let response = decoder.into_response();
+    assert_eq!(response.len(), 300);
+    assert_eq!(response[250..252], [8, 9]);
+}
+
+#[test]
+fn key_handle_len_from_sign_count() {
+    assert_eq!(max_key_handle_len(&[]), MAX_KEY_HANDLE_LEN);
+    assert_eq!(
+        max_key_handle_len(&authenticator_data(0)),
+        MAX_KEY_HANDLE_LEN
+    );
+    assert_eq!(max_key_handle_len(&authenticator_data(600)), 600);
+    assert_eq!(
+        max_key_handle_len(&authenticator_data(u32::MAX)),
+        MAX_CREDENTIAL_ID_LEN
+    );
+}
+
+#[test]
+fn exchange_longer_key_handles() {
+    let request = vec![3; REQUEST_CHUNK_LEN + 2 * MAX_CREDENTIAL_ID_LEN];
+    let mut exchange = Exchange::new(&request);
+
+    assert_eq!(
+        exchange.next_key_handle().unwrap().len(),
+        MAX_KEY_HANDLE_LEN
+    );
+    exchange
+        .push_assertion(
+            &authenticator_data(MAX_CREDENTIAL_ID_LEN as u32),
+            &signature(&[], &[]),
+        )
+        .unwrap();
+    // The remaining 2046 bytes fill two key handles of 1022 bytes each after the header, with 2 bytes left over.
+    assert_eq!(
+        exchange.next_key_handle().unwrap().len(),
+        MAX_CREDENTIAL_ID_LEN
+    );
+    exchange.push_signature(&signature(&[], &[])).unwrap();
+    assert_eq!(
+        exchange.next_key_handle().unwrap().len(),
+        MAX_CREDENTIAL_ID_LEN
+    );
+    exchange.push_signature(&signature(&[], &[])).unwrap();
+    assert_eq!(exchange.next_key_handle(), Some(&[2, 3, 3][..]));
+}
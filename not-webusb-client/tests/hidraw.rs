@@ -0,0 +1,25 @@
+#![cfg(all(target_os = "linux", feature = "hidraw"))]
+
+use not_webusb_client::hidraw::is_fido_report_descriptor;
+use usbd_human_interface_device::device::fido::FIDO_REPORT_DESCRIPTOR;
+use usbd_human_interface_device::device::keyboard::NKRO_BOOT_KEYBOARD_REPORT_DESCRIPTOR;
+use usbd_human_interface_device::device::mouse::WHEEL_MOUSE_REPORT_DESCRIPTOR;
+
+#[test]
+fn fido_report_descriptor() {
+    assert!(is_fido_report_descriptor(FIDO_REPORT_DESCRIPTOR));
+
+    assert!(!is_fido_report_descriptor(
+        NKRO_BOOT_KEYBOARD_REPORT_DESCRIPTOR
+    ));
+    assert!(!is_fido_report_descriptor(WHEEL_MOUSE_REPORT_DESCRIPTOR));
+    assert!(!is_fido_report_descriptor(&[]));
+
+    // The usage page may come after a long item, which must be skipped over entirely.
+    let mut descriptor = vec![0xFE, 0x02, 0x00, 0x06, 0xD0];
+    descriptor.extend_from_slice(FIDO_REPORT_DESCRIPTOR);
+    assert!(is_fido_report_descriptor(&descriptor));
+
+    // A truncated usage page item is ignored.
+    assert!(!is_fido_report_descriptor(&[0x09, 0x01, 0x06, 0xD0]));
+}
@@ -0,0 +1,382 @@
+use crate::{BusState, READ_REPORT_MAX_POLLS, client_options};
+use arrayvec::ArrayVec;
+use embassy_time::MockDriver;
+use embassy_usb::Builder;
+use embassy_usb::class::hid::State;
+use embassy_usb::driver::{
+    Bus, ControlPipe, Direction, Driver, Endpoint, EndpointAddress, EndpointAllocError,
+    EndpointError, EndpointIn, EndpointInfo, EndpointOut, EndpointType, Event, Unsupported,
+};
+use not_webusb::{Duration, EmbassyNotWebUsb, NotWebUsbStorage, OriginFilter, Timeouts};
+use not_webusb_client::{Client, ClientOptions, Transport};
+use std::collections::VecDeque;
+use std::future::{Future, pending, poll_fn};
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use usbd_human_interface_device::device::fido::RawFidoReport;
+
+/// An embassy-usb [`Driver`] that lives entirely in memory, the embassy equivalent of [`crate::SimulatedUsbBus`].
+///
+/// Interrupt endpoints behave the same as those of `SimulatedUsbBus`, except that they wait instead of returning `WouldBlock`.
+/// The control pipe never receives a setup packet, so the device is never enumerated, which the FIDO endpoints do not depend on.
+///
+/// Endpoints never register a waker, so futures using them must be polled repeatedly, as `SimulatedEmbassyDevice::poll` does.
+pub struct SimulatedEmbassyDriver {
+    state: Arc<Mutex<BusState>>,
+}
+
+impl SimulatedEmbassyDriver {
+    fn alloc_endpoint(
+        &mut self,
+        direction: Direction,
+        ep_type: EndpointType,
+        ep_addr: Option<EndpointAddress>,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> SimulatedEndpoint {
+        let mut state = self.state.lock().unwrap();
+        let addr = ep_addr.unwrap_or_else(|| {
+            state.next_endpoint_index += 1;
+            EndpointAddress::from_parts(state.next_endpoint_index, direction)
+        });
+        SimulatedEndpoint {
+            state: self.state.clone(),
+            info: EndpointInfo {
+                addr,
+                ep_type,
+                max_packet_size,
+                interval_ms,
+            },
+        }
+    }
+}
+
+impl<'a> Driver<'a> for SimulatedEmbassyDriver {
+    type EndpointOut = SimulatedEndpoint;
+    type EndpointIn = SimulatedEndpoint;
+    type ControlPipe = SimulatedControlPipe;
+    type Bus = SimulatedBus;
+
+    fn alloc_endpoint_out(
+        &mut self,
+        ep_type: EndpointType,
+        ep_addr: Option<EndpointAddress>,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<SimulatedEndpoint, EndpointAllocError> {
+        Ok(self.alloc_endpoint(
+            Direction::Out,
+            ep_type,
+            ep_addr,
+            max_packet_size,
+            interval_ms,
+        ))
+    }
+
+    fn alloc_endpoint_in(
+        &mut self,
+        ep_type: EndpointType,
+        ep_addr: Option<EndpointAddress>,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<SimulatedEndpoint, EndpointAllocError> {
+        Ok(self.alloc_endpoint(
+            Direction::In,
+            ep_type,
+            ep_addr,
+            max_packet_size,
+            interval_ms,
+        ))
+    }
+
+    fn start(self, control_max_packet_size: u16) -> (SimulatedBus, SimulatedControlPipe) {
+        (
+            SimulatedBus,
+            SimulatedControlPipe {
+                max_packet_size: usize::from(control_max_packet_size),
+            },
+        )
+    }
+}
+
+/// An interrupt endpoint of [`SimulatedEmbassyDriver`].
+pub struct SimulatedEndpoint {
+    state: Arc<Mutex<BusState>>,
+    info: EndpointInfo,
+}
+
+impl Endpoint for SimulatedEndpoint {
+    fn info(&self) -> &EndpointInfo {
+        &self.info
+    }
+
+    async fn wait_enabled(&mut self) {}
+}
+
+impl EndpointOut for SimulatedEndpoint {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, EndpointError> {
+        poll_fn(|_| {
+            let mut state = self.state.lock().unwrap();
+            match state.to_device.front() {
+                None => Poll::Pending,
+                Some(report) if buf.len() < report.packet.len() => {
+                    Poll::Ready(Err(EndpointError::BufferOverflow))
+                }
+                Some(_) => {
+                    let report = state.to_device.pop_front().unwrap();
+                    buf[..report.packet.len()].copy_from_slice(&report.packet);
+                    Poll::Ready(Ok(report.packet.len()))
+                }
+            }
+        })
+        .await
+    }
+}
+
+impl EndpointIn for SimulatedEndpoint {
+    async fn write(&mut self, buf: &[u8]) -> Result<(), EndpointError> {
+        poll_fn(|_| {
+            let mut state = self.state.lock().unwrap();
+            if state.from_device.is_some() {
+                return Poll::Pending;
+            }
+            let mut report = RawFidoReport::default();
+            if buf.len() > report.packet.len() {
+                return Poll::Ready(Err(EndpointError::BufferOverflow));
+            }
+            report.packet[..buf.len()].copy_from_slice(buf);
+            state.from_device = Some(report);
+            Poll::Ready(Ok(()))
+        })
+        .await
+    }
+}
+
+/// The control pipe of [`SimulatedEmbassyDriver`], which never receives any requests.
+pub struct SimulatedControlPipe {
+    max_packet_size: usize,
+}
+
+impl ControlPipe for SimulatedControlPipe {
+    fn max_packet_size(&self) -> usize {
+        self.max_packet_size
+    }
+
+    async fn setup(&mut self) -> [u8; 8] {
+        pending().await
+    }
+
+    async fn data_out(
+        &mut self,
+        _buf: &mut [u8],
+        _first: bool,
+        _last: bool,
+    ) -> Result<usize, EndpointError> {
+        Ok(0)
+    }
+
+    async fn data_in(
+        &mut self,
+        _data: &[u8],
+        _first: bool,
+        _last: bool,
+    ) -> Result<(), EndpointError> {
+        Ok(())
+    }
+
+    async fn accept(&mut self) {}
+
+    async fn reject(&mut self) {}
+
+    async fn accept_set_address(&mut self, _addr: u8) {}
+}
+
+/// The bus of [`SimulatedEmbassyDriver`], which never has any events.
+pub struct SimulatedBus;
+
+impl Bus for SimulatedBus {
+    async fn enable(&mut self) {}
+
+    async fn disable(&mut self) {}
+
+    async fn poll(&mut self) -> Event {
+        pending().await
+    }
+
+    fn endpoint_set_enabled(&mut self, _ep_addr: EndpointAddress, _enabled: bool) {}
+
+    fn endpoint_set_stalled(&mut self, _ep_addr: EndpointAddress, _stalled: bool) {}
+
+    fn endpoint_is_stalled(&mut self, _ep_addr: EndpointAddress) -> bool {
+        false
+    }
+
+    async fn remote_wakeup(&mut self) -> Result<(), Unsupported> {
+        Err(Unsupported)
+    }
+}
+
+/// An [`EmbassyNotWebUsb`] instance connected to a [`SimulatedEmbassyDriver`], the embassy equivalent of [`crate::SimulatedDevice`].
+///
+/// The firmware is simulated as a task that answers every request via `EmbassyNotWebUsb::next_request` and `EmbassyNotWebUsb::respond`,
+/// [`SimulatedEmbassyDevice::poll`] runs the task until it is waiting on the host again.
+///
+/// Time is simulated via the embassy-time `MockDriver`, which is global, so `SimulatedEmbassyDevice::advance_time` affects all devices in the process.
+pub struct SimulatedEmbassyDevice {
+    bus_state: Arc<Mutex<BusState>>,
+    firmware: Pin<Box<dyn Future<Output = ()>>>,
+    received: VecDeque<RawFidoReport>,
+}
+
+impl SimulatedEmbassyDevice {
+    /// Create a new simulated device, `web_origin_filter` is passed to `EmbassyNotWebUsb::new`.
+    /// Every request is passed to `handler` and the returned bytes are sent as the response.
+    ///
+    /// The usb-device buffers, HID state and `NotWebUsbStorage` are leaked, since `EmbassyNotWebUsb` must borrow them for its entire lifetime.
+    pub fn new<const MAX_MESSAGE_LEN: usize>(
+        web_origin_filter: impl OriginFilter + 'static,
+        handler: impl FnMut(&[u8]) -> ArrayVec<u8, MAX_MESSAGE_LEN> + 'static,
+    ) -> Self {
+        Self::build(web_origin_filter, None, handler)
+    }
+
+    /// Create a new simulated device with `EmbassyNotWebUsb::with_timeouts` enabled, using the simulated clock controlled by `SimulatedEmbassyDevice::advance_time`.
+    pub fn with_timeouts<const MAX_MESSAGE_LEN: usize>(
+        web_origin_filter: impl OriginFilter + 'static,
+        timeouts: Timeouts,
+        handler: impl FnMut(&[u8]) -> ArrayVec<u8, MAX_MESSAGE_LEN> + 'static,
+    ) -> Self {
+        Self::build(web_origin_filter, Some(timeouts), handler)
+    }
+
+    /// Create a new simulated device whose firmware task is the future returned by `firmware`, e.g. to answer calls via `rpc!`.
+    ///
+    /// `firmware` receives the `EmbassyNotWebUsb` instance, created with `web_origin_filter`.
+    pub fn with_firmware<const MAX_MESSAGE_LEN: usize, F: OriginFilter + 'static, Fut>(
+        web_origin_filter: F,
+        firmware: impl FnOnce(
+            EmbassyNotWebUsb<'static, SimulatedEmbassyDriver, MAX_MESSAGE_LEN, F>,
+        ) -> Fut,
+    ) -> Self
+    where
+        Fut: Future<Output = ()> + 'static,
+    {
+        let bus_state = Arc::new(Mutex::new(BusState::default()));
+        let not_webusb = Self::not_webusb(bus_state.clone(), web_origin_filter);
+        SimulatedEmbassyDevice {
+            bus_state,
+            firmware: Box::pin(firmware(not_webusb)),
+            received: VecDeque::new(),
+        }
+    }
+
+    fn build<const MAX_MESSAGE_LEN: usize>(
+        web_origin_filter: impl OriginFilter + 'static,
+        timeouts: Option<Timeouts>,
+        mut handler: impl FnMut(&[u8]) -> ArrayVec<u8, MAX_MESSAGE_LEN> + 'static,
+    ) -> Self {
+        Self::with_firmware(web_origin_filter, move |mut not_webusb| async move {
+            if let Some(timeouts) = timeouts {
+                not_webusb = not_webusb.with_timeouts(timeouts);
+            }
+            loop {
+                let response = handler(not_webusb.next_request().await.payload);
+                not_webusb
+                    .respond(response)
+                    .await
+                    .expect("there is a pending request");
+            }
+        })
+    }
+
+    fn not_webusb<const MAX_MESSAGE_LEN: usize, F: OriginFilter + 'static>(
+        bus_state: Arc<Mutex<BusState>>,
+        web_origin_filter: F,
+    ) -> EmbassyNotWebUsb<'static, SimulatedEmbassyDriver, MAX_MESSAGE_LEN, F> {
+        let driver = SimulatedEmbassyDriver { state: bus_state };
+        let mut builder = Builder::new(
+            driver,
+            embassy_usb::Config::new(0x1209, 0x0001),
+            Box::leak(Box::new([0; 256])),
+            Box::leak(Box::new([0; 256])),
+            Box::leak(Box::new([0; 256])),
+            Box::leak(Box::new([0; 64])),
+        );
+        let state = Box::leak(Box::new(State::new()));
+        let storage = Box::leak(Box::new(NotWebUsbStorage::new()));
+        let not_webusb = EmbassyNotWebUsb::new(&mut builder, state, storage, web_origin_filter);
+        // The `UsbDevice` would only handle control requests, which are never received.
+        builder.build();
+        not_webusb
+    }
+
+    /// Move the simulated clock forward by `duration`.
+    pub fn advance_time(&mut self, duration: Duration) {
+        MockDriver::get().advance(embassy_time::Duration::from_micros(duration.ticks()));
+    }
+
+    /// Queue a packet to be sent from the host to the device.
+    /// The device will not observe it until the next call to `SimulatedEmbassyDevice::poll`.
+    pub fn send_report(&mut self, report: RawFidoReport) {
+        self.bus_state.lock().unwrap().to_device.push_back(report);
+    }
+
+    /// Returns the oldest packet sent from the device to the host that has not yet been received.
+    pub fn receive_report(&mut self) -> Option<RawFidoReport> {
+        self.received.pop_front()
+    }
+
+    /// Run the firmware task until it is waiting on the host.
+    ///
+    /// Afterwards the host collects any packet written by the device, making it available to `SimulatedEmbassyDevice::receive_report`.
+    pub fn poll(&mut self) {
+        let mut context = Context::from_waker(Waker::noop());
+        let _ = self.firmware.as_mut().poll(&mut context);
+
+        if let Some(report) = self.bus_state.lock().unwrap().from_device.take() {
+            self.received.push_back(report);
+        }
+    }
+
+    /// Repeatedly call `SimulatedEmbassyDevice::poll` until the device sends a packet, returning the packet.
+    /// Returns `None` if no packet was sent after `max_polls` iterations.
+    pub fn poll_until_report(&mut self, max_polls: usize) -> Option<RawFidoReport> {
+        for _ in 0..max_polls {
+            if let Some(report) = self.receive_report() {
+                return Some(report);
+            }
+            self.poll();
+        }
+        self.receive_report()
+    }
+
+    /// Create a `not_webusb_client::Client` talking to this device with [`client_options`].
+    pub fn client(&mut self) -> Client<&mut Self> {
+        self.client_with(client_options())
+    }
+
+    /// Create a `not_webusb_client::Client` talking to this device with `options`.
+    pub fn client_with(&mut self, options: ClientOptions) -> Client<&mut Self> {
+        Client::new(self, options).expect("simulated device responds to INIT")
+    }
+}
+
+impl Transport for SimulatedEmbassyDevice {
+    fn write_report(&mut self, report: &[u8; 64]) -> io::Result<()> {
+        self.send_report(RawFidoReport { packet: *report });
+        Ok(())
+    }
+
+    fn read_report(&mut self) -> io::Result<[u8; 64]> {
+        self.poll_until_report(READ_REPORT_MAX_POLLS)
+            .map(|report| report.packet)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "simulated device did not send a report",
+                )
+            })
+    }
+}
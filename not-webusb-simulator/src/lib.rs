@@ -0,0 +1,414 @@
+//! Run [`NotWebUsb`] on the host, without a microcontroller.
+//!
+//! [`SimulatedUsbBus`] implements [`UsbBus`] by storing the packets written to and read from the FIDO HID endpoints in memory.
+//! [`SimulatedDevice`] wraps it all up with a [`NotWebUsb`] instance and drives it the same way a firmware main loop would.
+//! This allows the ctaphid/u2f/user data state machines and your own request handlers to be tested with a plain `cargo test`.
+//!
+//! `SimulatedDevice` implements [`not_webusb_client::Transport`], so a `not_webusb_client::Client` can talk to it just like a real device,
+//! [`SimulatedDevice::client`] creates one.
+//!
+//! Time only passes when [`SimulatedDevice::advance_time`] is called, so timeouts can be tested without sleeping.
+//!
+//! With the `embassy` feature, [`SimulatedEmbassyDevice`] does the same for `EmbassyNotWebUsb`.
+
+use arrayvec::ArrayVec;
+use not_webusb::{
+    Duration, Instant, NotWebUsb, NotWebUsbBuilder, NotWebUsbError, NotWebUsbStorage, OriginFilter,
+    OriginStore, OriginVerdict, Timeouts,
+};
+use not_webusb_client::{Client, ClientOptions, Transport};
+use std::collections::VecDeque;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use usb_device::bus::{PollResult, UsbBus, UsbBusAllocator};
+use usb_device::device::{UsbDevice, UsbDeviceBuilder, UsbVidPid};
+use usb_device::endpoint::{EndpointAddress, EndpointType};
+use usb_device::{UsbDirection, UsbError};
+use usbd_human_interface_device::device::fido::{RawFidoConfig, RawFidoReport};
+use usbd_human_interface_device::prelude::*;
+
+#[cfg(feature = "embassy")]
+mod embassy;
+
+#[cfg(feature = "embassy")]
+pub use embassy::{
+    SimulatedBus, SimulatedControlPipe, SimulatedEmbassyDevice, SimulatedEmbassyDriver,
+    SimulatedEndpoint,
+};
+
+/// A [`UsbBus`] implementation that lives entirely in memory.
+///
+/// The interrupt OUT endpoint reads packets queued by the simulated host.
+/// The interrupt IN endpoint holds a single packet until the simulated host collects it, just like real hardware,
+/// so the device will observe `UsbError::WouldBlock` if it writes faster than the host reads.
+///
+/// Control endpoints accept and discard all writes and never have data to read.
+pub struct SimulatedUsbBus {
+    state: Arc<Mutex<BusState>>,
+}
+
+#[derive(Default)]
+struct BusState {
+    next_endpoint_index: usize,
+    interrupt_in: Option<EndpointAddress>,
+    interrupt_out: Option<EndpointAddress>,
+    /// Packets sent by the host that the device has not read yet.
+    to_device: VecDeque<RawFidoReport>,
+    /// The packet written by the device that the host has not collected yet.
+    from_device: Option<RawFidoReport>,
+}
+
+impl UsbBus for SimulatedUsbBus {
+    fn alloc_ep(
+        &mut self,
+        ep_dir: UsbDirection,
+        ep_addr: Option<EndpointAddress>,
+        ep_type: EndpointType,
+        _max_packet_size: u16,
+        _interval: u8,
+    ) -> usb_device::Result<EndpointAddress> {
+        let mut state = self.state.lock().unwrap();
+        let address = match (ep_addr, ep_type) {
+            (Some(address), _) => address,
+            (None, EndpointType::Control) => EndpointAddress::from_parts(0, ep_dir),
+            (None, _) => {
+                state.next_endpoint_index += 1;
+                EndpointAddress::from_parts(state.next_endpoint_index, ep_dir)
+            }
+        };
+        if let EndpointType::Interrupt = ep_type {
+            match ep_dir {
+                UsbDirection::In => state.interrupt_in = Some(address),
+                UsbDirection::Out => state.interrupt_out = Some(address),
+            }
+        }
+        Ok(address)
+    }
+
+    fn enable(&mut self) {}
+
+    fn reset(&self) {}
+
+    fn set_device_address(&self, _addr: u8) {}
+
+    fn write(&self, ep_addr: EndpointAddress, buf: &[u8]) -> usb_device::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        if state.interrupt_in != Some(ep_addr) {
+            return Ok(buf.len());
+        }
+        if state.from_device.is_some() {
+            return Err(UsbError::WouldBlock);
+        }
+        let mut report = RawFidoReport::default();
+        if buf.len() > report.packet.len() {
+            return Err(UsbError::BufferOverflow);
+        }
+        report.packet[..buf.len()].copy_from_slice(buf);
+        state.from_device = Some(report);
+        Ok(buf.len())
+    }
+
+    fn read(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> usb_device::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        if state.interrupt_out != Some(ep_addr) {
+            return Err(UsbError::WouldBlock);
+        }
+        match state.to_device.front() {
+            None => Err(UsbError::WouldBlock),
+            Some(report) if buf.len() < report.packet.len() => Err(UsbError::BufferOverflow),
+            Some(_) => {
+                let report = state.to_device.pop_front().unwrap();
+                buf[..report.packet.len()].copy_from_slice(&report.packet);
+                Ok(report.packet.len())
+            }
+        }
+    }
+
+    fn set_stalled(&self, _ep_addr: EndpointAddress, _stalled: bool) {}
+
+    fn is_stalled(&self, _ep_addr: EndpointAddress) -> bool {
+        false
+    }
+
+    fn suspend(&self) {}
+
+    fn resume(&self) {}
+
+    fn poll(&self) -> PollResult {
+        PollResult::None
+    }
+}
+
+/// A [`NotWebUsb`] instance connected to a [`SimulatedUsbBus`], along with the host side of the bus.
+///
+/// Call [`SimulatedDevice::send_report`] to send a CTAPHID packet to the device,
+/// [`SimulatedDevice::poll`] to run one iteration of the firmware main loop,
+/// and [`SimulatedDevice::receive_report`] to collect any CTAPHID packets the device sent in response.
+pub struct SimulatedDevice<const MAX_MESSAGE_LEN: usize = 1024> {
+    bus_state: Arc<Mutex<BusState>>,
+    /// The current time of the simulated clock in microseconds.
+    time: Arc<AtomicU64>,
+    usb_device: UsbDevice<'static, SimulatedUsbBus>,
+    not_webusb: SimulatedNotWebUsb<MAX_MESSAGE_LEN>,
+    request_handler: Option<RequestHandler<MAX_MESSAGE_LEN>>,
+    poll_handler: Option<PollHandler<MAX_MESSAGE_LEN>>,
+    received: VecDeque<RawFidoReport>,
+    /// Recoverable errors returned by `NotWebUsb::poll` while polling on behalf of `Transport::read_report`.
+    errors: Vec<NotWebUsbError>,
+}
+
+/// The [`NotWebUsb`] instance of a [`SimulatedDevice`].
+pub type SimulatedNotWebUsb<const MAX_MESSAGE_LEN: usize = 1024> =
+    NotWebUsb<'static, SimulatedUsbBus, MAX_MESSAGE_LEN, SimulatedOriginFilter>;
+
+/// The `OriginFilter` of a [`SimulatedDevice`], boxed so that any filter can be simulated.
+pub struct SimulatedOriginFilter(Box<dyn OriginFilter>);
+
+impl OriginFilter for SimulatedOriginFilter {
+    fn check(&mut self, origin_hash: [u8; 32]) -> OriginVerdict {
+        self.0.check(origin_hash)
+    }
+
+    fn permissions(&mut self, origin_hash: [u8; 32]) -> u32 {
+        self.0.permissions(origin_hash)
+    }
+
+    fn origin_store(&mut self) -> Option<&mut dyn OriginStore> {
+        self.0.origin_store()
+    }
+}
+
+type RequestHandler<const MAX_MESSAGE_LEN: usize> =
+    Box<dyn FnMut(&[u8]) -> ArrayVec<u8, MAX_MESSAGE_LEN>>;
+
+type PollHandler<const MAX_MESSAGE_LEN: usize> =
+    Box<dyn FnMut(&mut SimulatedNotWebUsb<MAX_MESSAGE_LEN>)>;
+
+impl<const MAX_MESSAGE_LEN: usize> SimulatedDevice<MAX_MESSAGE_LEN> {
+    /// Create a new simulated device, `web_origin_filter` is passed to `NotWebUsb::new`.
+    ///
+    /// The `UsbBusAllocator` and `NotWebUsbStorage` are leaked, since `NotWebUsb` must borrow them for its entire lifetime.
+    pub fn new(web_origin_filter: impl OriginFilter + 'static) -> Self {
+        Self::build(web_origin_filter, None, |builder| builder)
+    }
+
+    /// Create a new simulated device with `NotWebUsb::with_timeouts` enabled, using a simulated clock controlled by `SimulatedDevice::advance_time`.
+    ///
+    /// The clock closure is leaked, since `NotWebUsb` must borrow it for its entire lifetime.
+    pub fn with_timeouts(
+        web_origin_filter: impl OriginFilter + 'static,
+        timeouts: Timeouts,
+    ) -> Self {
+        Self::build(web_origin_filter, Some(timeouts), |builder| builder)
+    }
+
+    /// Create a new simulated device whose `NotWebUsb` is configured via `configure`, e.g. to give it its own identity.
+    pub fn with_builder(
+        web_origin_filter: impl OriginFilter + 'static,
+        configure: impl FnOnce(
+            NotWebUsbBuilder<'static, MAX_MESSAGE_LEN, SimulatedOriginFilter>,
+        )
+            -> NotWebUsbBuilder<'static, MAX_MESSAGE_LEN, SimulatedOriginFilter>,
+    ) -> Self {
+        Self::build(web_origin_filter, None, configure)
+    }
+
+    fn build(
+        web_origin_filter: impl OriginFilter + 'static,
+        timeouts: Option<Timeouts>,
+        configure: impl FnOnce(
+            NotWebUsbBuilder<'static, MAX_MESSAGE_LEN, SimulatedOriginFilter>,
+        )
+            -> NotWebUsbBuilder<'static, MAX_MESSAGE_LEN, SimulatedOriginFilter>,
+    ) -> Self {
+        let bus_state = Arc::new(Mutex::new(BusState::default()));
+        let usb_bus: &'static UsbBusAllocator<SimulatedUsbBus> =
+            Box::leak(Box::new(UsbBusAllocator::new(SimulatedUsbBus {
+                state: bus_state.clone(),
+            })));
+
+        let fido = UsbHidClassBuilder::new()
+            .add_device(RawFidoConfig::default())
+            .build(usb_bus);
+
+        let usb_device = UsbDeviceBuilder::new(usb_bus, UsbVidPid(0x1209, 0x0001)).build();
+
+        let time = Arc::new(AtomicU64::new(0));
+        let storage = Box::leak(Box::new(NotWebUsbStorage::new()));
+        let web_origin_filter = SimulatedOriginFilter(Box::new(web_origin_filter));
+        let mut builder = configure(NotWebUsbBuilder::new(storage, web_origin_filter));
+        if let Some(timeouts) = timeouts {
+            let clock_time = time.clone();
+            let now: &'static dyn Fn() -> Instant = Box::leak(Box::new(move || {
+                Instant::from_ticks(clock_time.load(Ordering::SeqCst))
+            }));
+            builder = builder.timeouts(now, timeouts);
+        }
+        let not_webusb = builder.build(fido);
+
+        SimulatedDevice {
+            bus_state,
+            time,
+            usb_device,
+            not_webusb,
+            request_handler: None,
+            poll_handler: None,
+            received: VecDeque::new(),
+            errors: vec![],
+        }
+    }
+
+    /// Enable `NotWebUsb::with_wink`, calling `wink` whenever the host sends a CTAPHID_WINK.
+    pub fn with_wink(mut self, wink: &'static dyn Fn()) -> Self {
+        self.not_webusb = self.not_webusb.with_wink(wink);
+        self
+    }
+
+    /// Once set, every pending request is passed to `handler` during `SimulatedDevice::poll` and the returned bytes are sent as the response.
+    ///
+    /// If no handler is set, requests must be handled manually via `SimulatedDevice::not_webusb`.
+    pub fn set_request_handler(
+        &mut self,
+        handler: impl FnMut(&[u8]) -> ArrayVec<u8, MAX_MESSAGE_LEN> + 'static,
+    ) {
+        self.request_handler = Some(Box::new(handler));
+    }
+
+    /// Once set, `handler` is called with the `NotWebUsb` instance during every `SimulatedDevice::poll`, after any request handler.
+    ///
+    /// Use this instead of `SimulatedDevice::set_request_handler` to handle requests the way your firmware main loop does,
+    /// e.g. via `NotWebUsb::check_pending_request_as`, while a `not_webusb_client::Client` is talking to the device.
+    pub fn set_poll_handler(
+        &mut self,
+        handler: impl FnMut(&mut SimulatedNotWebUsb<MAX_MESSAGE_LEN>) + 'static,
+    ) {
+        self.poll_handler = Some(Box::new(handler));
+    }
+
+    /// Direct access to the underlying `NotWebUsb` instance.
+    pub fn not_webusb(&mut self) -> &mut SimulatedNotWebUsb<MAX_MESSAGE_LEN> {
+        &mut self.not_webusb
+    }
+
+    /// Move the simulated clock forward by `duration`.
+    pub fn advance_time(&mut self, duration: Duration) {
+        self.time.fetch_add(duration.ticks(), Ordering::SeqCst);
+    }
+
+    /// Queue a packet to be sent from the host to the device.
+    /// The device will not observe it until the next call to `SimulatedDevice::poll`.
+    pub fn send_report(&mut self, report: RawFidoReport) {
+        self.bus_state.lock().unwrap().to_device.push_back(report);
+    }
+
+    /// Returns the oldest packet sent from the device to the host that has not yet been received.
+    pub fn receive_report(&mut self) -> Option<RawFidoReport> {
+        self.received.pop_front()
+    }
+
+    /// Returns the recoverable errors that `NotWebUsb::poll` returned while a `not_webusb_client::Client` was talking to the device,
+    /// clearing them.
+    pub fn take_errors(&mut self) -> Vec<NotWebUsbError> {
+        std::mem::take(&mut self.errors)
+    }
+
+    /// Run a single iteration of a typical firmware main loop:
+    /// Poll the `UsbDevice` and `NotWebUsb`, then respond to any pending request if a request handler is set and run the poll handler if set.
+    ///
+    /// Afterwards the host collects any packet written by the device, making it available to `SimulatedDevice::receive_report`.
+    pub fn poll(&mut self) -> Result<(), NotWebUsbError> {
+        self.usb_device.poll(&mut [self.not_webusb.fido_class()]);
+        let result = self.not_webusb.poll();
+
+        if let Some(handler) = &mut self.request_handler
+            && let Some(request) = self.not_webusb.check_pending_request()
+        {
+            let response = handler(request.payload);
+            self.not_webusb
+                .send_response(response)
+                .expect("there is a pending request");
+        }
+        if let Some(handler) = &mut self.poll_handler {
+            handler(&mut self.not_webusb);
+        }
+
+        if let Some(report) = self.bus_state.lock().unwrap().from_device.take() {
+            self.received.push_back(report);
+        }
+        result
+    }
+
+    /// Repeatedly call `SimulatedDevice::poll` until the device sends a packet, returning the packet.
+    /// Returns `Ok(None)` if no packet was sent after `max_polls` iterations.
+    pub fn poll_until_report(
+        &mut self,
+        max_polls: usize,
+    ) -> Result<Option<RawFidoReport>, NotWebUsbError> {
+        for _ in 0..max_polls {
+            if let Some(report) = self.receive_report() {
+                return Ok(Some(report));
+            }
+            self.poll()?;
+        }
+        Ok(self.receive_report())
+    }
+
+    /// Create a `not_webusb_client::Client` talking to this device with [`client_options`].
+    pub fn client(&mut self) -> Client<&mut Self> {
+        self.client_with(client_options())
+    }
+
+    /// Create a `not_webusb_client::Client` talking to this device with `options`.
+    ///
+    /// Start from [`client_options`] when overriding only some of the options.
+    pub fn client_with(&mut self, options: ClientOptions) -> Client<&mut Self> {
+        Client::new(self, options).expect("simulated device responds to INIT")
+    }
+}
+
+/// How many times `Transport::read_report` polls the device before giving up.
+const READ_REPORT_MAX_POLLS: usize = 10_000;
+
+/// The `ClientOptions` used by `SimulatedDevice::client`.
+///
+/// The same as `ClientOptions::default`, except that signing is retried immediately,
+/// since the simulated device only makes progress while the client is reading from it.
+pub fn client_options() -> ClientOptions {
+    ClientOptions {
+        sign_retry_interval: std::time::Duration::ZERO,
+        ..ClientOptions::default()
+    }
+}
+
+impl<const MAX_MESSAGE_LEN: usize> Transport for SimulatedDevice<MAX_MESSAGE_LEN> {
+    fn write_report(&mut self, report: &[u8; 64]) -> io::Result<()> {
+        self.send_report(RawFidoReport { packet: *report });
+        Ok(())
+    }
+
+    fn read_report(&mut self) -> io::Result<[u8; 64]> {
+        for _ in 0..READ_REPORT_MAX_POLLS {
+            if let Some(report) = self.receive_report() {
+                return Ok(report.packet);
+            }
+            match self.poll() {
+                Ok(()) => {}
+                Err(err @ NotWebUsbError::UsbError) => {
+                    return Err(io::Error::other(format!("{err:?}")));
+                }
+                // Like a firmware main loop, carry on after recoverable errors.
+                // The device informs the client of them where needed, so they are only recorded for `SimulatedDevice::take_errors`.
+                Err(err) => self.errors.push(err),
+            }
+        }
+        self.receive_report()
+            .map(|report| report.packet)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "simulated device did not send a report",
+                )
+            })
+    }
+}
@@ -0,0 +1,209 @@
+//! Talk to not-webusb devices from a rust wasm webpage.
+//!
+//! This is the rust equivalent of `web/not_webusb.js`:
+//! [`Device::read_write`] sends a request to the device via `navigator.credentials.get` and returns its response.
+//!
+//! The packetization logic lives in `not_webusb_client::framing`, which does not depend on the browser and is tested natively.
+
+pub mod queue;
+
+use crate::queue::Queue;
+use js_sys::{Array, Date, Uint8Array};
+use not_webusb_client::framing::Exchange;
+use std::rc::Rc;
+use std::time::Duration;
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{
+    AuthenticatorAssertionResponse, CredentialRequestOptions, DomException, PublicKeyCredential,
+    PublicKeyCredentialDescriptor, PublicKeyCredentialRequestOptions, PublicKeyCredentialType,
+    UserVerificationRequirement,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("the user cancelled the request")]
+    UserCancelled,
+    #[error("the request timed out")]
+    TimedOut,
+    #[error("the device rejected the origin of this webpage")]
+    OriginRejected,
+    #[error("the device forbids this request for the origin of this webpage")]
+    Forbidden,
+    #[error(
+        "navigator.credentials is unavailable, webauthn requires a browser and a secure context"
+    )]
+    Unsupported,
+    /// Usually indicates that `DeviceOptions::rp_id` is not valid for the origin of the webpage.
+    #[error("security error: {0}")]
+    Security(String),
+    /// The request could not be sent, e.g. because it is longer than the device accepts.
+    #[error("invalid request: {0}")]
+    InvalidRequest(String),
+    #[error("invalid response: {0}")]
+    InvalidResponse(String),
+    /// The device answered with an error status instead of a response.
+    #[error("device error: {0}")]
+    Device(String),
+    /// Any other exception thrown by `navigator.credentials.get`.
+    #[error("{name}: {message}")]
+    Browser { name: String, message: String },
+}
+
+impl Error {
+    /// Classify an exception thrown by `navigator.credentials.get`.
+    ///
+    /// Browsers report both a cancelled prompt and an expired timeout as `NotAllowedError`,
+    /// so `timed_out` must indicate whether the timeout had elapsed when the exception was thrown.
+    pub fn from_dom_exception(name: &str, message: &str, timed_out: bool) -> Self {
+        match name {
+            "NotAllowedError" if timed_out => Error::TimedOut,
+            "NotAllowedError" | "AbortError" => Error::UserCancelled,
+            "SecurityError" => Error::Security(message.to_owned()),
+            _ => Error::Browser {
+                name: name.to_owned(),
+                message: message.to_owned(),
+            },
+        }
+    }
+
+    fn from_js(value: JsValue, timed_out: bool) -> Self {
+        match value.dyn_ref::<DomException>() {
+            Some(exception) => {
+                Error::from_dom_exception(&exception.name(), &exception.message(), timed_out)
+            }
+            None => Error::Browser {
+                name: "Error".to_owned(),
+                message: format!("{value:?}"),
+            },
+        }
+    }
+}
+
+impl From<not_webusb_client::Error> for Error {
+    fn from(err: not_webusb_client::Error) -> Self {
+        match err {
+            not_webusb_client::Error::OriginRejected => Error::OriginRejected,
+            not_webusb_client::Error::Forbidden => Error::Forbidden,
+            not_webusb_client::Error::Timeout => Error::TimedOut,
+            not_webusb_client::Error::InvalidRequest(message) => Error::InvalidRequest(message),
+            not_webusb_client::Error::InvalidResponse(message) => Error::InvalidResponse(message),
+            err => Error::Device(err.to_string()),
+        }
+    }
+}
+
+/// Allows using `?` on `Device::read_write` within `#[wasm_bindgen]` functions.
+impl From<Error> for JsValue {
+    fn from(err: Error) -> Self {
+        js_sys::Error::new(&err.to_string()).into()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DeviceOptions {
+    /// The webauthn rpId, its sha256 hash is what the device's `web_origin_filter` receives.
+    /// Defaults to the browser's default, the domain of the current webpage.
+    pub rp_id: Option<String>,
+    /// How long each individual `navigator.credentials.get` call may take, before failing with `Error::TimedOut`.
+    pub timeout: Duration,
+}
+
+impl Default for DeviceOptions {
+    fn default() -> Self {
+        DeviceOptions {
+            rp_id: None,
+            timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+/// A not-webusb device reached via the browser's webauthn API.
+///
+/// Requests are exchanged over many `navigator.credentials.get` calls that must not be interleaved with other requests.
+/// Instead of failing like `not_webusb.js` does, concurrent calls to `Device::read_write` wait in a queue until earlier requests complete.
+/// Clones of a `Device` share the same queue, so clone a single `Device` instead of creating a new one for each caller.
+#[derive(Clone, Default)]
+pub struct Device {
+    options: DeviceOptions,
+    queue: Rc<Queue>,
+}
+
+impl Device {
+    pub fn new(options: DeviceOptions) -> Self {
+        Device {
+            options,
+            queue: Rc::new(Queue::new()),
+        }
+    }
+
+    /// Sends a request to the device and returns its response.
+    pub async fn read_write(&self, request: &[u8]) -> Result<Vec<u8>, Error> {
+        let _guard = self.queue.lock().await;
+
+        let mut exchange = Exchange::new(request);
+        while let Some(key_handle) = exchange.next_key_handle() {
+            let (authenticator_data, signature) = self.get_assertion(key_handle).await?;
+            exchange.push_assertion(&authenticator_data, &signature)?;
+        }
+        Ok(exchange.into_response())
+    }
+
+    /// Calls `navigator.credentials.get` with `key_handle` as the only allowed credential, returning the authenticatorData and signature.
+    async fn get_assertion(&self, key_handle: &[u8]) -> Result<(Vec<u8>, Vec<u8>), Error> {
+        let credentials = web_sys::window()
+            .ok_or(Error::Unsupported)?
+            .navigator()
+            .credentials();
+
+        let allow_credential = PublicKeyCredentialDescriptor::new(
+            &Uint8Array::from(key_handle),
+            PublicKeyCredentialType::PublicKey,
+        );
+        allow_credential.set_transports(&Array::of1(&JsValue::from_str("usb")));
+
+        let timeout_ms = self
+            .options
+            .timeout
+            .as_millis()
+            .try_into()
+            .unwrap_or(u32::MAX);
+        let public_key = PublicKeyCredentialRequestOptions::new(&Uint8Array::new_with_length(0));
+        public_key.set_allow_credentials(&Array::of1(&allow_credential));
+        public_key.set_user_verification(UserVerificationRequirement::Discouraged);
+        public_key.set_timeout(timeout_ms);
+        if let Some(rp_id) = &self.options.rp_id {
+            public_key.set_rp_id(rp_id);
+        }
+        let options = CredentialRequestOptions::new();
+        options.set_public_key(&public_key);
+
+        let start = Date::now();
+        let timed_out = || Date::now() - start >= timeout_ms as f64;
+        let promise = credentials
+            .get_with_options(&options)
+            .map_err(|err| Error::from_js(err, timed_out()))?;
+        let credential = JsFuture::from(promise)
+            .await
+            .map_err(|err| Error::from_js(err, timed_out()))?;
+
+        let response = credential
+            .dyn_into::<PublicKeyCredential>()
+            .ok()
+            .and_then(|credential| {
+                credential
+                    .response()
+                    .dyn_into::<AuthenticatorAssertionResponse>()
+                    .ok()
+            })
+            .ok_or_else(|| {
+                Error::InvalidResponse(
+                    "navigator.credentials.get did not return an assertion".into(),
+                )
+            })?;
+        Ok((
+            Uint8Array::new(&response.authenticator_data()).to_vec(),
+            Uint8Array::new(&response.signature()).to_vec(),
+        ))
+    }
+}
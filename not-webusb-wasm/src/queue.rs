@@ -0,0 +1,117 @@
+//! A first-in first-out async lock, used to give each device exclusive access for the duration of a request.
+//!
+//! wasm is single threaded so this only needs a `RefCell`, and it has no dependency on the browser so it can be tested natively.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
+
+#[derive(Default)]
+pub struct Queue {
+    state: RefCell<QueueState>,
+}
+
+#[derive(Default)]
+struct QueueState {
+    /// The ticket that will be handed to the next call to `Queue::lock`.
+    next_ticket: u64,
+    /// The ticket that currently holds the lock, or will be the next to acquire it.
+    serving: u64,
+    /// Tickets whose `Lock` future was dropped before acquiring the lock.
+    abandoned: BTreeSet<u64>,
+    /// Wakers of the `Lock` futures that are waiting for their turn.
+    waiting: BTreeMap<u64, Waker>,
+}
+
+impl QueueState {
+    fn advance(&mut self) {
+        self.serving += 1;
+        while self.abandoned.remove(&self.serving) {
+            self.serving += 1;
+        }
+        if let Some(waker) = self.waiting.remove(&self.serving) {
+            waker.wake();
+        }
+    }
+}
+
+impl Queue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Waits until every earlier call to `Queue::lock` has released the lock, then acquires it.
+    pub fn lock(&self) -> Lock<'_> {
+        let mut state = self.state.borrow_mut();
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        Lock {
+            queue: self,
+            ticket,
+            acquired: false,
+        }
+    }
+
+    /// The amount of `Lock`s that are holding or waiting for the lock.
+    pub fn len(&self) -> usize {
+        let state = self.state.borrow();
+        (state.next_ticket - state.serving) as usize - state.abandoned.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Resolves to a [`QueueGuard`] once it is this lock's turn.
+///
+/// Dropping it before it resolves gives up its place in the queue.
+pub struct Lock<'a> {
+    queue: &'a Queue,
+    ticket: u64,
+    acquired: bool,
+}
+
+impl<'a> Future for Lock<'a> {
+    type Output = QueueGuard<'a>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.queue.state.borrow_mut();
+        if state.serving == self.ticket {
+            drop(state);
+            self.acquired = true;
+            Poll::Ready(QueueGuard { queue: self.queue })
+        } else {
+            state.waiting.insert(self.ticket, cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for Lock<'_> {
+    fn drop(&mut self) {
+        if self.acquired {
+            return;
+        }
+        let mut state = self.queue.state.borrow_mut();
+        state.waiting.remove(&self.ticket);
+        if state.serving == self.ticket {
+            state.advance();
+        } else {
+            state.abandoned.insert(self.ticket);
+        }
+    }
+}
+
+/// Releases the lock to the next waiting `Lock` when dropped.
+pub struct QueueGuard<'a> {
+    queue: &'a Queue,
+}
+
+impl Drop for QueueGuard<'_> {
+    fn drop(&mut self) {
+        self.queue.state.borrow_mut().advance();
+    }
+}
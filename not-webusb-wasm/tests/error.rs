@@ -0,0 +1,49 @@
+use not_webusb_wasm::Error;
+
+#[test]
+fn dom_exception() {
+    assert!(matches!(
+        Error::from_dom_exception("NotAllowedError", "", false),
+        Error::UserCancelled
+    ));
+    assert!(matches!(
+        Error::from_dom_exception("NotAllowedError", "", true),
+        Error::TimedOut
+    ));
+    assert!(matches!(
+        Error::from_dom_exception("AbortError", "", true),
+        Error::UserCancelled
+    ));
+    assert!(matches!(
+        Error::from_dom_exception("SecurityError", "invalid rpId", false),
+        Error::Security(message) if message == "invalid rpId"
+    ));
+    assert!(matches!(
+        Error::from_dom_exception("InvalidStateError", "", false),
+        Error::Browser { name, .. } if name == "InvalidStateError"
+    ));
+}
+
+#[test]
+fn client_error() {
+    assert!(matches!(
+        Error::from(not_webusb_client::Error::OriginRejected),
+        Error::OriginRejected
+    ));
+    assert!(matches!(
+        Error::from(not_webusb_client::Error::Forbidden),
+        Error::Forbidden
+    ));
+    assert!(matches!(
+        Error::from(not_webusb_client::Error::Timeout),
+        Error::TimedOut
+    ));
+    assert!(matches!(
+        Error::from(not_webusb_client::Error::InvalidRequest("too long".to_owned())),
+        Error::InvalidRequest(message) if message == "too long"
+    ));
+    assert!(matches!(
+        Error::from(not_webusb_client::Error::StatusWord(0x6a80)),
+        Error::Device(_)
+    ));
+}
@@ -0,0 +1,80 @@
+use not_webusb_wasm::queue::Queue;
+use std::future::Future;
+use std::pin::pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll, Wake, Waker};
+
+#[derive(Default)]
+struct Flag(AtomicBool);
+
+impl Wake for Flag {
+    fn wake(self: Arc<Self>) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+impl Flag {
+    fn take(&self) -> bool {
+        self.0.swap(false, Ordering::SeqCst)
+    }
+}
+
+fn poll<F: Future>(future: std::pin::Pin<&mut F>, flag: &Arc<Flag>) -> Poll<F::Output> {
+    let waker = Waker::from(flag.clone());
+    future.poll(&mut Context::from_waker(&waker))
+}
+
+#[test]
+fn first_in_first_out() {
+    let queue = Queue::new();
+    let (flag_a, flag_b, flag_c) = (Arc::default(), Arc::default(), Arc::default());
+
+    let mut a = pin!(queue.lock());
+    let mut b = pin!(queue.lock());
+    let mut c = pin!(queue.lock());
+    assert_eq!(queue.len(), 3);
+
+    // c is polled before b, but b still acquires the lock first.
+    assert!(poll(c.as_mut(), &flag_c).is_pending());
+    assert!(poll(b.as_mut(), &flag_b).is_pending());
+    let Poll::Ready(guard_a) = poll(a.as_mut(), &flag_a) else {
+        panic!("first lock should be acquired immediately")
+    };
+
+    drop(guard_a);
+    assert!(flag_b.take());
+    assert!(!flag_c.take());
+    let Poll::Ready(guard_b) = poll(b.as_mut(), &flag_b) else {
+        panic!("second lock should be acquired after the first is released")
+    };
+    assert!(poll(c.as_mut(), &flag_c).is_pending());
+
+    drop(guard_b);
+    assert!(flag_c.take());
+    assert!(poll(c.as_mut(), &flag_c).is_ready());
+}
+
+#[test]
+fn abandoned_lock() {
+    let queue = Queue::new();
+    let flag = Arc::default();
+
+    let guard = queue.lock();
+    let guard = pin!(guard);
+    let Poll::Ready(guard) = poll(guard, &flag) else {
+        panic!("first lock should be acquired immediately")
+    };
+
+    // A request that is cancelled while waiting must not block the requests behind it.
+    let abandoned = queue.lock();
+    let mut waiting = pin!(queue.lock());
+    assert!(poll(waiting.as_mut(), &flag).is_pending());
+    drop(abandoned);
+    assert_eq!(queue.len(), 2);
+
+    drop(guard);
+    assert!(flag.take());
+    assert!(poll(waiting.as_mut(), &flag).is_ready());
+    assert!(queue.is_empty());
+}
@@ -0,0 +1,210 @@
+//! Built-in admin messages for managing the allowed origins remotely, see `NotWebUsbBuilder::admin_origin`.
+
+use crate::{OriginFilter, OriginStore, OriginVerdict, RequestHeader, UserDataState};
+use arrayvec::ArrayVec;
+
+/// Every admin message starts with these bytes, which tell it apart from requests meant for the application.
+///
+/// Once an admin origin is configured, requests starting with this prefix never reach `NotWebUsb::check_pending_request`.
+/// Only requests starting with this prefix bypass the `OriginFilter` for the admin origin.
+pub const ADMIN_PREFIX: &[u8] = b"\xffnot-webusb-admin";
+
+/// The length of the longest encoded `AdminRequest`.
+pub const MAXIMUM_ADMIN_REQUEST_LEN: usize = ADMIN_PREFIX.len() + 1 + 32;
+
+/// A message from the admin origin managing the origins stored by the `OriginFilter`, see `OriginFilter::origin_store`.
+///
+/// Send the bytes from `AdminRequest::encode` as a regular request.
+/// The response starts with an `AdminStatus` byte, which for `AdminRequest::List` is followed by the listed origin hashes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AdminRequest {
+    /// List the stored origin hashes, starting from the `start`th one.
+    /// As many as fit in `MAX_MESSAGE_LEN` are returned, send another `List` starting after the last one to get the rest.
+    List { start: u16 },
+    /// Allow the origin whose rpId hashes to the given hash, see `origin_hash`.
+    Add([u8; 32]),
+    /// Stop allowing the origin whose rpId hashes to the given hash.
+    Remove([u8; 32]),
+}
+
+impl AdminRequest {
+    /// Encode the request as a message, including `ADMIN_PREFIX`.
+    pub fn encode(&self) -> ArrayVec<u8, MAXIMUM_ADMIN_REQUEST_LEN> {
+        let mut message = ArrayVec::new();
+        message.try_extend_from_slice(ADMIN_PREFIX).unwrap();
+        match self {
+            AdminRequest::List { start } => {
+                message.push(0);
+                message.try_extend_from_slice(&start.to_le_bytes()).unwrap();
+            }
+            AdminRequest::Add(origin_hash) => {
+                message.push(1);
+                message.try_extend_from_slice(origin_hash).unwrap();
+            }
+            AdminRequest::Remove(origin_hash) => {
+                message.push(2);
+                message.try_extend_from_slice(origin_hash).unwrap();
+            }
+        }
+        message
+    }
+
+    /// Decode a message produced by `AdminRequest::encode`, returns `None` if it is malformed.
+    pub fn decode(message: &[u8]) -> Option<Self> {
+        match message.strip_prefix(ADMIN_PREFIX)? {
+            [0, start @ ..] => Some(AdminRequest::List {
+                start: u16::from_le_bytes(start.try_into().ok()?),
+            }),
+            [1, origin_hash @ ..] => Some(AdminRequest::Add(origin_hash.try_into().ok()?)),
+            [2, origin_hash @ ..] => Some(AdminRequest::Remove(origin_hash.try_into().ok()?)),
+            _ => None,
+        }
+    }
+}
+
+/// The first byte of the response to an admin message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AdminStatus {
+    /// The request was carried out.
+    /// Adding an origin that is already stored or removing one that is not also succeeds.
+    Ok = 0,
+    /// The request was not sent by the admin origin.
+    Forbidden = 1,
+    /// The request started with `ADMIN_PREFIX` but could not be decoded.
+    Malformed = 2,
+    /// The `OriginFilter` has no `OriginStore` to manage.
+    Unsupported = 3,
+    /// The `OriginStore` could not store the change, e.g. because it is full or writing to flash failed.
+    Failed = 4,
+}
+
+impl AdminStatus {
+    /// Returns the status encoded as `byte`, or `None` if it is not a known status.
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(AdminStatus::Ok),
+            1 => Some(AdminStatus::Forbidden),
+            2 => Some(AdminStatus::Malformed),
+            3 => Some(AdminStatus::Unsupported),
+            4 => Some(AdminStatus::Failed),
+            _ => None,
+        }
+    }
+}
+
+/// Decides whether a not-webusb key handle is received, based on both its origin and its contents.
+pub(crate) trait KeyHandleFilter {
+    fn check(&mut self, origin_hash: [u8; 32], key_handle: &[u8]) -> OriginVerdict;
+}
+
+/// Allows admin messages from the admin origin regardless of `filter`, so that removing it from the store cannot lock the admin out.
+///
+/// Every other key handle, including application requests from the admin origin, is checked by `filter`.
+pub(crate) struct AdminBypass<'f, F: OriginFilter> {
+    pub admin_origin: Option<[u8; 32]>,
+    /// True while the response being sent is the answer to an admin message, see `Protocol::admin_response`.
+    pub admin_response: bool,
+    pub filter: &'f mut F,
+}
+
+impl<F: OriginFilter> KeyHandleFilter for AdminBypass<'_, F> {
+    fn check(&mut self, origin_hash: [u8; 32], key_handle: &[u8]) -> OriginVerdict {
+        // Admin messages always fit in a single `RequestHeader::FinalRequest` key handle,
+        // while their responses may be fetched via `RequestHeader::NeedMoreResponseData`.
+        let is_admin_message = match key_handle {
+            [header, request @ ..] if *header == RequestHeader::FinalRequest as u8 => {
+                request.starts_with(ADMIN_PREFIX)
+            }
+            [header, ..] if *header == RequestHeader::NeedMoreResponseData as u8 => {
+                self.admin_response
+            }
+            _ => false,
+        };
+        if is_admin_message && self.admin_origin == Some(origin_hash) {
+            OriginVerdict::Allow
+        } else {
+            self.filter.check(origin_hash)
+        }
+    }
+}
+
+/// If the fully received request in `buffer` is an admin message, carries it out and writes the response over it.
+/// Returns true if it was an admin message, in which case `user_data` is now sending the response.
+///
+/// Requests are never treated as admin messages when there is no admin origin.
+pub(crate) fn handle_request<const MAX_MESSAGE_LEN: usize>(
+    admin_origin: Option<[u8; 32]>,
+    user_data: &mut UserDataState,
+    buffer: &mut ArrayVec<u8, MAX_MESSAGE_LEN>,
+    origin_hash: [u8; 32],
+    filter: &mut dyn OriginFilter,
+) -> bool {
+    let Some(admin_origin) = admin_origin else {
+        return false;
+    };
+    if !matches!(user_data, UserDataState::ReceivedRequest) || !buffer.starts_with(ADMIN_PREFIX) {
+        return false;
+    }
+
+    let request = AdminRequest::decode(buffer);
+    // The request is at least as long as `ADMIN_PREFIX`, so there is room for the status.
+    buffer.clear();
+    buffer.push(AdminStatus::Ok as u8);
+    let status = if origin_hash != admin_origin {
+        warn!("admin message from an origin other than the admin origin");
+        AdminStatus::Forbidden
+    } else if let Some(request) = request {
+        match filter.origin_store() {
+            Some(store) => execute(request, store, buffer),
+            None => {
+                warn!("admin message received but the origin filter has no origin store");
+                AdminStatus::Unsupported
+            }
+        }
+    } else {
+        warn!("malformed admin message");
+        AdminStatus::Malformed
+    };
+    if status != AdminStatus::Ok {
+        buffer.truncate(1);
+    }
+    buffer[0] = status as u8;
+    *user_data = UserDataState::SendingResponse { bytes_sent: 0 };
+    true
+}
+
+fn execute<const MAX_MESSAGE_LEN: usize>(
+    request: AdminRequest,
+    store: &mut dyn OriginStore,
+    buffer: &mut ArrayVec<u8, MAX_MESSAGE_LEN>,
+) -> AdminStatus {
+    match request {
+        AdminRequest::List { start } => {
+            info!("admin listing origins from {}", start);
+            for origin_hash in (start as usize..).map_while(|index| store.get(index)) {
+                if buffer.try_extend_from_slice(&origin_hash).is_err() {
+                    break;
+                }
+            }
+            AdminStatus::Ok
+        }
+        AdminRequest::Add(origin_hash) => {
+            info!("admin adding origin {}", origin_hash);
+            if store.contains(origin_hash) || store.insert(origin_hash) {
+                AdminStatus::Ok
+            } else {
+                AdminStatus::Failed
+            }
+        }
+        AdminRequest::Remove(origin_hash) => {
+            info!("admin removing origin {}", origin_hash);
+            if !store.contains(origin_hash) || store.remove(origin_hash) {
+                AdminStatus::Ok
+            } else {
+                AdminStatus::Failed
+            }
+        }
+    }
+}
@@ -0,0 +1,173 @@
+#[cfg(feature = "embassy")]
+use crate::EmbassyNotWebUsb;
+use crate::{Instant, NotWebUsb, NotWebUsbStorage, OriginFilter, Protocol, Timeouts, u2f};
+use frunk::{HCons, HNil};
+use usb_device::bus::UsbBus;
+use usbd_human_interface_device::device::fido::RawFido;
+use usbd_human_interface_device::prelude::*;
+
+/// A unique aaguid for not-webusb, used unless `NotWebUsbBuilder::aaguid` is called.
+pub const DEFAULT_AAGUID: [u8; 16] = [
+    0xe3, 0xb1, 0x76, 0x8b, 0x55, 0x91, 0x4a, 0xd7, 0xb4, 0x6e, 0xac, 0xc7, 0x60, 0x84, 0x0b, 0x3e,
+];
+
+/// How the device describes itself to the OS and browsers, see `NotWebUsbBuilder`.
+#[derive(Clone, Copy)]
+pub(crate) struct DeviceIdentity {
+    /// Sent in the CBOR GetInfo response.
+    pub aaguid: [u8; 16],
+    /// The major, minor and build device version sent in the CTAPHID init response.
+    pub device_version: [u8; 3],
+    /// Sent in response to U2F version requests.
+    pub u2f_version: &'static str,
+    /// Whether CTAP2 is advertised and getAssertion requests are answered.
+    #[cfg(feature = "ctap2")]
+    pub ctap2: bool,
+}
+
+impl DeviceIdentity {
+    pub const fn new() -> Self {
+        DeviceIdentity {
+            aaguid: DEFAULT_AAGUID,
+            device_version: [0, 0, 0],
+            u2f_version: "U2F_V2",
+            #[cfg(feature = "ctap2")]
+            ctap2: true,
+        }
+    }
+
+    #[cfg(feature = "ctap2")]
+    pub fn ctap2_enabled(&self) -> bool {
+        self.ctap2
+    }
+
+    /// Always false without the `ctap2` feature.
+    #[cfg(not(feature = "ctap2"))]
+    pub fn ctap2_enabled(&self) -> bool {
+        false
+    }
+}
+
+/// Configures and creates a `NotWebUsb`, or with the `embassy` feature an `EmbassyNotWebUsb`.
+///
+/// By default every device built on not-webusb looks identical to the OS and to browsers,
+/// the builder allows giving each product its own identity:
+/// ```ignore
+/// let not_webusb = NotWebUsbBuilder::new(storage, &|_| true)
+///     .aaguid(MY_PRODUCT_AAGUID)
+///     .device_version(1, 2, 0)
+///     .timeouts(&|| timer.get_counter(), Timeouts::default())
+///     .build(fido);
+/// ```
+pub struct NotWebUsbBuilder<
+    'a,
+    const MAX_MESSAGE_LEN: usize = 1024,
+    F: OriginFilter = &'a dyn Fn([u8; 32]) -> bool,
+> {
+    protocol: Protocol<'a, MAX_MESSAGE_LEN, F>,
+}
+
+impl<'a, const MAX_MESSAGE_LEN: usize, F: OriginFilter> NotWebUsbBuilder<'a, MAX_MESSAGE_LEN, F> {
+    /// See `NotWebUsb::new` for details on `web_origin_filter` and `NotWebUsbStorage` for `storage`.
+    pub fn new(storage: &'a mut NotWebUsbStorage<MAX_MESSAGE_LEN>, web_origin_filter: F) -> Self {
+        NotWebUsbBuilder {
+            protocol: Protocol::new(storage, web_origin_filter),
+        }
+    }
+
+    /// Expire requests and responses that the client has stopped sending or receiving, see `NotWebUsb::with_timeouts`.
+    ///
+    /// `EmbassyNotWebUsb` measures time via `embassy_time` instead, use `EmbassyNotWebUsb::with_timeouts` for it.
+    pub fn timeouts(mut self, now: &'a dyn Fn() -> Instant, timeouts: Timeouts) -> Self {
+        self.protocol.set_timeouts(now, timeouts);
+        self
+    }
+
+    /// Advertise support for CTAPHID_WINK, calling `wink` whenever a client sends one, see `NotWebUsb::with_wink`.
+    pub fn wink(mut self, wink: &'a dyn Fn()) -> Self {
+        self.protocol.wink = Some(wink);
+        self
+    }
+
+    /// The permissions that each request requires, as a bitmask of application defined permissions, e.g. the `required_permissions` function generated by `rpc!`.
+    /// Defaults to requiring no permissions.
+    ///
+    /// A fully received request is only handed to the application if its origin is granted all of the permissions it requires,
+    /// see `OriginFilter::permissions` and `OriginPolicy`.
+    /// Other requests are discarded and answered with a forbidden response, which clients report separately from a rejected origin.
+    pub fn required_permissions(mut self, required_permissions: &'a dyn Fn(&[u8]) -> u32) -> Self {
+        self.protocol.required_permissions = Some(required_permissions);
+        self
+    }
+
+    /// Allow the origin whose rpId hashes to `origin_hash` to send admin messages, which list, add and remove the origins
+    /// stored by the `OriginFilter`, see `AdminRequest` and `OriginFilter::origin_store`.
+    ///
+    /// Admin messages are answered by NotWebUsb itself and never reach `NotWebUsb::check_pending_request`.
+    /// Admin messages from any other origin are answered with `AdminStatus::Forbidden`.
+    /// The admin origin is always allowed to send admin messages, regardless of the `OriginFilter`,
+    /// while its other requests are allowed or denied by the `OriginFilter` like those of any other origin.
+    pub fn admin_origin(mut self, origin_hash: [u8; 32]) -> Self {
+        self.protocol.admin_origin = Some(origin_hash);
+        self
+    }
+
+    /// The aaguid sent in the CBOR GetInfo response, which identifies the make and model of the device.
+    /// Defaults to `DEFAULT_AAGUID`.
+    ///
+    /// Generate a random one for each product, e.g. via `uuidgen`.
+    pub fn aaguid(mut self, aaguid: [u8; 16]) -> Self {
+        self.protocol.identity.aaguid = aaguid;
+        self
+    }
+
+    /// The device version sent in the CTAPHID init response, defaults to 0.0.0.
+    pub fn device_version(mut self, major: u8, minor: u8, build: u8) -> Self {
+        self.protocol.identity.device_version = [major, minor, build];
+        self
+    }
+
+    /// The version string sent in response to U2F version requests, defaults to `U2F_V2`.
+    ///
+    /// Browsers only use devices that respond with `U2F_V2`, so this is only useful when talking to a device via a custom client.
+    ///
+    /// Panics if `version` is longer than 64 bytes.
+    pub fn u2f_version(mut self, version: &'static str) -> Self {
+        assert!(
+            version.len() <= u2f::MAXIMUM_VERSION_LEN,
+            "U2F version must not be longer than 64 bytes"
+        );
+        self.protocol.identity.u2f_version = version;
+        self
+    }
+
+    /// Whether to advertise CTAP2 support and answer CTAP2 getAssertion requests, defaults to true.
+    ///
+    /// When disabled, the device only supports U2F, exactly as if the `ctap2` feature were disabled.
+    #[cfg(feature = "ctap2")]
+    pub fn ctap2(mut self, enabled: bool) -> Self {
+        self.protocol.identity.ctap2 = enabled;
+        self
+    }
+
+    /// Create a `NotWebUsb` communicating via `fido`.
+    pub fn build<UsbBusT: UsbBus>(
+        self,
+        fido: UsbHidClass<'a, UsbBusT, HCons<RawFido<'a, UsbBusT>, HNil>>,
+    ) -> NotWebUsb<'a, UsbBusT, MAX_MESSAGE_LEN, F> {
+        NotWebUsb {
+            fido,
+            protocol: self.protocol,
+        }
+    }
+
+    /// Create an `EmbassyNotWebUsb`, adding a FIDO HID interface to `builder`.
+    #[cfg(feature = "embassy")]
+    pub fn build_embassy<D: embassy_usb::driver::Driver<'a>>(
+        self,
+        builder: &mut embassy_usb::Builder<'a, D>,
+        state: &'a mut embassy_usb::class::hid::State<'a>,
+    ) -> EmbassyNotWebUsb<'a, D, MAX_MESSAGE_LEN, F> {
+        EmbassyNotWebUsb::from_protocol(builder, state, self.protocol)
+    }
+}
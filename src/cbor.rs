@@ -0,0 +1,236 @@
+//! Just enough CBOR to decode CTAP2 requests and encode CTAP2 responses.
+//!
+//! Only the definite length encodings that CTAP2 allows are supported, see the "CTAP2 canonical CBOR encoding form" in the CTAP spec.
+
+// Without the `ctap2` feature, only the GetInfo response is encoded and nothing is decoded.
+#![cfg_attr(not(feature = "ctap2"), allow(dead_code))]
+
+/// CTAP2 messages nest at most 4 levels deep, anything deeper is rejected when skipped.
+const MAXIMUM_DEPTH: usize = 4;
+
+const MAJOR_UNSIGNED: u8 = 0;
+const MAJOR_NEGATIVE: u8 = 1;
+const MAJOR_BYTES: u8 = 2;
+const MAJOR_TEXT: u8 = 3;
+const MAJOR_ARRAY: u8 = 4;
+const MAJOR_MAP: u8 = 5;
+const MAJOR_SIMPLE: u8 = 7;
+
+const SIMPLE_FALSE: u64 = 20;
+const SIMPLE_TRUE: u64 = 21;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CborError {
+    /// A well formed item of a different type than was expected.
+    UnexpectedType,
+    /// The data is not well formed CBOR, or uses an encoding that CTAP2 does not allow.
+    Invalid,
+    /// The encoded item does not fit in the buffer.
+    BufferFull,
+}
+
+/// Reads CBOR items from the start of a slice, one at a time.
+pub struct Decoder<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Decoder { data }
+    }
+
+    /// Returns the major type and argument of the next item.
+    fn header(&mut self) -> Result<(u8, u64), CborError> {
+        let (&initial, rest) = self.data.split_first().ok_or(CborError::Invalid)?;
+        let additional = initial & 0x1f;
+        let (argument, rest) = match additional {
+            0..24 => (additional as u64, rest),
+            24..28 => {
+                let len = 1 << (additional - 24);
+                if rest.len() < len {
+                    return Err(CborError::Invalid);
+                }
+                let (bytes, rest) = rest.split_at(len);
+                let argument = bytes
+                    .iter()
+                    .fold(0, |argument, byte| argument << 8 | *byte as u64);
+                (argument, rest)
+            }
+            // Indefinite lengths and reserved values.
+            _ => return Err(CborError::Invalid),
+        };
+        self.data = rest;
+        Ok((initial >> 5, argument))
+    }
+
+    fn expect(&mut self, major: u8) -> Result<u64, CborError> {
+        match self.header()? {
+            (actual, argument) if actual == major => Ok(argument),
+            _ => Err(CborError::UnexpectedType),
+        }
+    }
+
+    fn take(&mut self, len: u64) -> Result<&'a [u8], CborError> {
+        let len = usize::try_from(len).map_err(|_| CborError::Invalid)?;
+        if self.data.len() < len {
+            return Err(CborError::Invalid);
+        }
+        let (taken, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    pub fn unsigned(&mut self) -> Result<u64, CborError> {
+        self.expect(MAJOR_UNSIGNED)
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8], CborError> {
+        let len = self.expect(MAJOR_BYTES)?;
+        self.take(len)
+    }
+
+    pub fn text(&mut self) -> Result<&'a str, CborError> {
+        let len = self.expect(MAJOR_TEXT)?;
+        core::str::from_utf8(self.take(len)?).map_err(|_| CborError::Invalid)
+    }
+
+    pub fn bool(&mut self) -> Result<bool, CborError> {
+        match self.expect(MAJOR_SIMPLE)? {
+            SIMPLE_FALSE => Ok(false),
+            SIMPLE_TRUE => Ok(true),
+            _ => Err(CborError::UnexpectedType),
+        }
+    }
+
+    /// Returns the number of items in the array, which must each be decoded or skipped in turn.
+    pub fn array(&mut self) -> Result<u64, CborError> {
+        self.expect(MAJOR_ARRAY)
+    }
+
+    /// Returns the number of entries in the map, whose keys and values must each be decoded or skipped in turn.
+    pub fn map(&mut self) -> Result<u64, CborError> {
+        self.expect(MAJOR_MAP)
+    }
+
+    /// Skips over the next item, including everything nested within it.
+    pub fn skip(&mut self) -> Result<(), CborError> {
+        self.skip_nested(0)
+    }
+
+    fn skip_nested(&mut self, depth: usize) -> Result<(), CborError> {
+        if depth > MAXIMUM_DEPTH {
+            warn!("CBOR nests more than {} levels deep", MAXIMUM_DEPTH);
+            return Err(CborError::Invalid);
+        }
+        match self.header()? {
+            (MAJOR_UNSIGNED | MAJOR_NEGATIVE | MAJOR_SIMPLE, _) => {}
+            (MAJOR_BYTES | MAJOR_TEXT, len) => {
+                self.take(len)?;
+            }
+            (MAJOR_ARRAY, len) => {
+                for _ in 0..len {
+                    self.skip_nested(depth + 1)?;
+                }
+            }
+            (MAJOR_MAP, len) => {
+                for _ in 0..len {
+                    self.skip_nested(depth + 1)?;
+                    self.skip_nested(depth + 1)?;
+                }
+            }
+            // Tags, which CTAP2 does not use.
+            _ => return Err(CborError::Invalid),
+        }
+        Ok(())
+    }
+}
+
+/// Writes CBOR items to a buffer, one after another.
+pub struct Encoder<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Encoder<'a> {
+    pub fn new(buffer: &'a mut [u8]) -> Self {
+        Encoder { buffer, len: 0 }
+    }
+
+    /// The number of bytes written so far.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Reserves the next `len` bytes of the buffer, returning them to be written to.
+    fn reserve(&mut self, len: usize) -> Result<&mut [u8], CborError> {
+        let start = self.len;
+        let reserved = self
+            .buffer
+            .get_mut(start..start + len)
+            .ok_or(CborError::BufferFull)?;
+        self.len += len;
+        Ok(reserved)
+    }
+
+    /// Writes bytes as they are, e.g. the CTAP2 status byte preceding the CBOR of a response.
+    pub fn raw(&mut self, bytes: &[u8]) -> Result<(), CborError> {
+        self.reserve(bytes.len())?.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    /// Writes the shortest header for the major type and argument, as required by the canonical encoding.
+    fn header(&mut self, major: u8, argument: u64) -> Result<(), CborError> {
+        let major = major << 5;
+        match argument {
+            0..24 => self.raw(&[major | argument as u8]),
+            24..0x100 => self.raw(&[major | 24, argument as u8]),
+            0x100..0x1_0000 => {
+                self.raw(&[major | 25])?;
+                self.raw(&(argument as u16).to_be_bytes())
+            }
+            0x1_0000..0x1_0000_0000 => {
+                self.raw(&[major | 26])?;
+                self.raw(&(argument as u32).to_be_bytes())
+            }
+            _ => {
+                self.raw(&[major | 27])?;
+                self.raw(&argument.to_be_bytes())
+            }
+        }
+    }
+
+    pub fn unsigned(&mut self, value: u64) -> Result<(), CborError> {
+        self.header(MAJOR_UNSIGNED, value)
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) -> Result<(), CborError> {
+        self.bytes_mut(bytes.len())?.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    /// Writes the header of a byte string of `len` bytes, returning the bytes to be filled in by the caller.
+    pub fn bytes_mut(&mut self, len: usize) -> Result<&mut [u8], CborError> {
+        self.header(MAJOR_BYTES, len as u64)?;
+        self.reserve(len)
+    }
+
+    pub fn text(&mut self, text: &str) -> Result<(), CborError> {
+        self.header(MAJOR_TEXT, text.len() as u64)?;
+        self.raw(text.as_bytes())
+    }
+
+    pub fn bool(&mut self, value: bool) -> Result<(), CborError> {
+        self.header(MAJOR_SIMPLE, if value { SIMPLE_TRUE } else { SIMPLE_FALSE })
+    }
+
+    /// Starts an array, the `len` items must be written next.
+    pub fn array(&mut self, len: usize) -> Result<(), CborError> {
+        self.header(MAJOR_ARRAY, len as u64)
+    }
+
+    /// Starts a map, the `len` keys and values must be written next, with keys in canonical order.
+    pub fn map(&mut self, len: usize) -> Result<(), CborError> {
+        self.header(MAJOR_MAP, len as u64)
+    }
+}
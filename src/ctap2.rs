@@ -0,0 +1,454 @@
+//! CTAP2 requests, which are sent in CTAPHID CBOR messages.
+//!
+//! Without the `ctap2` feature, only GetInfo is answered, to tell browsers that only U2F is supported.
+//!
+//! With the `ctap2` feature, authenticatorGetAssertion carries not-webusb key handles as the credential ID and responses in the assertion signature.
+//! It works just like the U2F authenticate request/response in `u2f.rs`,
+//! but since CTAP2 allows far longer credential IDs and signatures, each round trip carries much more data.
+//! Browsers use CTAP2 once the device lists `FIDO_2_0` in its GetInfo response, falling back to U2F otherwise.
+
+use crate::admin::KeyHandleFilter;
+use crate::builder::DeviceIdentity;
+#[cfg(feature = "ctap2")]
+use crate::cbor::Decoder;
+use crate::cbor::{CborError, Encoder};
+use crate::outgoing::OutgoingMessage;
+use crate::u2f::Received;
+#[cfg(feature = "ctap2")]
+use crate::u2f::UserKeyHandle;
+#[cfg(feature = "ctap2")]
+use crate::{MAXIMUM_REQUEST_MESSAGE, OriginVerdict, max, origin_hash};
+use crate::{NotWebUsbError, u2f};
+
+/// The longest credential ID that browsers allow.
+///
+/// It is also sent as the signCount of every assertion, which browsers pass on to the webpage,
+/// telling clients that they can use credential IDs this long instead of the 255 bytes U2F is limited to.
+#[cfg(feature = "ctap2")]
+pub const MAXIMUM_CREDENTIAL_ID_LEN: usize = 1023;
+
+/// The longest getAssertion request that not-webusb handles:
+/// A credential ID of `MAXIMUM_CREDENTIAL_ID_LEN` plus room for the rpId, clientDataHash and whatever options and extensions the browser adds.
+#[cfg(feature = "ctap2")]
+pub const MAXIMUM_REQUEST_LEN: usize = 1 + MAXIMUM_CREDENTIAL_ID_LEN + 512;
+
+/// Without the `ctap2` feature, GetInfo is the only request that needs to be received in full, which is a lone command byte.
+#[cfg(not(feature = "ctap2"))]
+pub const MAXIMUM_REQUEST_LEN: usize = 1;
+
+/// The response bytes smuggled in each of the two ASN.1 integers of the signature, after the 0x7f prefix.
+#[cfg(feature = "ctap2")]
+const INTEGER_PAYLOAD_LEN: usize = 511;
+
+/// The length of the DER encoding of an ASN.1 integer holding the 0x7f prefix and `INTEGER_PAYLOAD_LEN` bytes.
+#[cfg(feature = "ctap2")]
+const INTEGER_LEN: usize = 4 + 1 + INTEGER_PAYLOAD_LEN;
+
+/// The length of the signature that responses are smuggled in: an ASN.1 sequence of two integers.
+#[cfg(feature = "ctap2")]
+const SIGNATURE_LEN: usize = 4 + 2 * INTEGER_LEN;
+
+/// The length of the authenticatorData: rpIdHash, flags and signCount.
+#[cfg(feature = "ctap2")]
+const AUTHENTICATOR_DATA_LEN: usize = 32 + 1 + 4;
+
+/// The length of a getAssertion response.
+#[cfg(feature = "ctap2")]
+const ASSERTION_LEN: usize = 1 + 1 + 1 + 2 + AUTHENTICATOR_DATA_LEN + 1 + 3 + SIGNATURE_LEN;
+
+/// An upper bound on the length of the GetInfo response, encoding it fails if it is any longer.
+/// Everything but the U2F version fits in 64 bytes.
+const MAXIMUM_GET_INFO_LEN: usize = 64 + u2f::MAXIMUM_VERSION_LEN;
+
+/// The longest CTAP2 response that not-webusb sends.
+#[cfg(feature = "ctap2")]
+pub const MAXIMUM_RESPONSE_LEN: usize = max(ASSERTION_LEN, MAXIMUM_GET_INFO_LEN);
+#[cfg(not(feature = "ctap2"))]
+pub const MAXIMUM_RESPONSE_LEN: usize = MAXIMUM_GET_INFO_LEN;
+
+const COMMAND_MAKE_CREDENTIAL: u8 = 0x01;
+#[cfg(feature = "ctap2")]
+const COMMAND_GET_ASSERTION: u8 = 0x02;
+const COMMAND_GET_INFO: u8 = 0x04;
+const COMMAND_RESET: u8 = 0x07;
+const COMMAND_GET_NEXT_ASSERTION: u8 = 0x08;
+const COMMAND_SELECTION: u8 = 0x0B;
+
+/// The user present flag of the authenticatorData.
+#[cfg(feature = "ctap2")]
+const FLAG_USER_PRESENT: u8 = 0x01;
+
+/// The fields of the authenticatorGetInfo response.
+///
+/// Fields that are empty or `None` are left out of the response.
+struct Info<'a> {
+    u2f_version: &'a str,
+    /// Whether `FIDO_2_0` is listed as a version after `u2f_version`.
+    fido_2_0: bool,
+    aaguid: &'a [u8; 16],
+    /// Must be in CTAP2 canonical order: shorter keys first, then sorted.
+    options: &'static [(&'static str, bool)],
+    max_msg_size: Option<usize>,
+    max_credential_id_length: Option<usize>,
+}
+
+impl<'a> Info<'a> {
+    #[cfg(feature = "ctap2")]
+    fn new(identity: &'a DeviceIdentity) -> Self {
+        if identity.ctap2_enabled() {
+            Info {
+                u2f_version: identity.u2f_version,
+                fido_2_0: true,
+                aaguid: &identity.aaguid,
+                // There are no resident keys to discover, and every assertion is made with the user present,
+                // which for not-webusb just means that the application answered.
+                options: &[("rk", false), ("up", true)],
+                max_msg_size: Some(MAXIMUM_REQUEST_MESSAGE),
+                max_credential_id_length: Some(MAXIMUM_CREDENTIAL_ID_LEN),
+            }
+        } else {
+            Info::u2f_only(identity)
+        }
+    }
+
+    #[cfg(not(feature = "ctap2"))]
+    fn new(identity: &'a DeviceIdentity) -> Self {
+        Info::u2f_only(identity)
+    }
+
+    /// Only listing the U2F version tells browsers that CTAP2 requests will not be answered, which is all that windows needs to use U2F.
+    fn u2f_only(identity: &'a DeviceIdentity) -> Self {
+        Info {
+            u2f_version: identity.u2f_version,
+            fido_2_0: false,
+            aaguid: &identity.aaguid,
+            options: &[],
+            max_msg_size: None,
+            max_credential_id_length: None,
+        }
+    }
+
+    fn encode(&self, encoder: &mut Encoder) -> Result<(), CborError> {
+        let fields = [
+            true,
+            true,
+            !self.options.is_empty(),
+            self.max_msg_size.is_some(),
+            self.max_credential_id_length.is_some(),
+        ];
+        encoder.map(fields.iter().filter(|field| **field).count())?;
+
+        encoder.unsigned(0x01)?;
+        if self.fido_2_0 {
+            encoder.array(2)?;
+            encoder.text(self.u2f_version)?;
+            encoder.text("FIDO_2_0")?;
+        } else {
+            encoder.array(1)?;
+            encoder.text(self.u2f_version)?;
+        }
+
+        encoder.unsigned(0x03)?;
+        encoder.bytes(self.aaguid)?;
+
+        if !self.options.is_empty() {
+            encoder.unsigned(0x04)?;
+            encoder.map(self.options.len())?;
+            for (option, value) in self.options {
+                encoder.text(option)?;
+                encoder.bool(*value)?;
+            }
+        }
+        if let Some(max_msg_size) = self.max_msg_size {
+            encoder.unsigned(0x05)?;
+            encoder.unsigned(max_msg_size as u64)?;
+        }
+        if let Some(max_credential_id_length) = self.max_credential_id_length {
+            encoder.unsigned(0x08)?;
+            encoder.unsigned(max_credential_id_length as u64)?;
+        }
+        Ok(())
+    }
+}
+
+/// The status byte that starts every CTAP2 response.
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Status {
+    Success = 0x00,
+    InvalidCommand = 0x01,
+    InvalidLength = 0x03,
+    CborUnexpectedType = 0x11,
+    InvalidCbor = 0x12,
+    #[cfg(feature = "ctap2")]
+    MissingParameter = 0x14,
+    /// The request is well formed, but not-webusb will never carry it out.
+    OperationDenied = 0x27,
+    /// None of the credentials in the allowList are valid.
+    #[cfg(feature = "ctap2")]
+    NoCredentials = 0x2E,
+    NotAllowed = 0x30,
+    Other = 0x7F,
+}
+
+impl From<CborError> for Status {
+    fn from(error: CborError) -> Self {
+        match error {
+            CborError::UnexpectedType => Status::CborUnexpectedType,
+            CborError::Invalid => Status::InvalidCbor,
+            CborError::BufferFull => Status::Other,
+        }
+    }
+}
+
+/// Receives and responds to incoming requests, see `u2f::receive_user_request`.
+/// If a tunnelled not-webusb request is present, instead of responding to it, the credential ID of the tunneled request is returned.
+#[cfg_attr(not(feature = "ctap2"), allow(unused_variables))]
+pub fn receive_user_request<'r>(
+    message_data: &'r [u8],
+    tx: &mut OutgoingMessage,
+    web_origin_filter: &mut dyn KeyHandleFilter,
+    identity: &DeviceIdentity,
+) -> Result<Received<'r>, NotWebUsbError> {
+    let Some((&command, parameters)) = message_data.split_first() else {
+        warn!("received empty CTAP2 request");
+        send_error_response(tx, Status::InvalidLength)?;
+        return Ok(Received::Answered);
+    };
+
+    match command {
+        COMMAND_GET_INFO => {
+            info!("received CTAP2 request: GetInfo");
+            write_response(tx, |encoder| {
+                encoder.raw(&[Status::Success as u8])?;
+                Info::new(identity).encode(encoder)
+            })?;
+        }
+        #[cfg(feature = "ctap2")]
+        COMMAND_GET_ASSERTION if identity.ctap2_enabled() => {
+            let request = match GetAssertion::decode(parameters) {
+                Ok(request) => request,
+                Err(status) => {
+                    warn!("received malformed CTAP2 getAssertion request {}", status);
+                    send_error_response(tx, status)?;
+                    return Ok(Received::Answered);
+                }
+            };
+            info!(
+                "received CTAP2 request: getAssertion rp_id={} credential_id={} user_presence={}",
+                request.rp_id, request.credential_id, request.user_presence
+            );
+
+            let application_parameter = origin_hash(request.rp_id);
+            if !request.user_presence {
+                // Browsers check which credentials are valid without user presence before asking for an assertion.
+                info!("answering getAssertion request without user presence");
+                write_assertion(tx, &application_parameter, false, 0, |_| {})?;
+            } else {
+                match web_origin_filter.check(application_parameter, request.credential_id) {
+                    OriginVerdict::Allow => {
+                        return Ok(Received::UserKeyHandle(UserKeyHandle {
+                            key_handle: request.credential_id,
+                            application_parameter,
+                        }));
+                    }
+                    OriginVerdict::Deny => {
+                        // web_origin_filter failed, so send a valid response, but dont give any user data.
+                        info!("getAssertion request filtered by web_origin_filter");
+                        write_assertion(tx, &application_parameter, true, 0, |_| {})?;
+                    }
+                    OriginVerdict::Ask => {
+                        info!("getAssertion request awaiting a verdict from web_origin_filter");
+                        return Ok(Received::AwaitingVerdict);
+                    }
+                }
+            }
+        }
+        COMMAND_MAKE_CREDENTIAL => {
+            // Browsers also send makeCredential to find out which authenticator the user touches,
+            // so it is refused rather than reported as unsupported.
+            info!("received CTAP2 request: makeCredential, not-webusb never creates credentials");
+            send_error_response(tx, Status::OperationDenied)?;
+        }
+        COMMAND_GET_NEXT_ASSERTION => {
+            // Only a single credential is ever returned by getAssertion.
+            info!("received CTAP2 request: getNextAssertion");
+            send_error_response(tx, Status::NotAllowed)?;
+        }
+        COMMAND_RESET | COMMAND_SELECTION => {
+            // There is nothing to reset, and no user presence to wait for.
+            info!("received CTAP2 request: {}", command);
+            write_response(tx, |encoder| encoder.raw(&[Status::Success as u8]))?;
+        }
+        command => {
+            warn!("unsupported CTAP2 command {}", command);
+            send_error_response(tx, Status::InvalidCommand)?;
+        }
+    }
+    Ok(Received::Answered)
+}
+
+/// Sends the next part of `response` in the signature of a getAssertion response, see `u2f::send_user_response`.
+///
+/// `rp_id_hash` must be the `application_parameter` of the key handle being answered, since browsers check it against the rpId they sent.
+#[cfg(feature = "ctap2")]
+pub fn send_user_response(
+    response: &[u8],
+    payload_written_bytes: &mut u32,
+    rp_id_hash: &[u8; 32],
+    tx: &mut OutgoingMessage,
+) -> Result<(), NotWebUsbError> {
+    write_assertion(tx, rp_id_hash, true, SIGNATURE_LEN, |signature| {
+        // The integers use the long form DER length, and like U2F start with 0x7f to keep them positive.
+        let sequence_len = (SIGNATURE_LEN as u16 - 4).to_be_bytes();
+        let integer_len = (INTEGER_LEN as u16 - 4).to_be_bytes();
+        let (sequence_header, integers) = signature.split_at_mut(4);
+        sequence_header.copy_from_slice(&[0x30, 0x82, sequence_len[0], sequence_len[1]]);
+
+        for (i, integer) in integers.chunks_exact_mut(INTEGER_LEN).enumerate() {
+            let (integer_header, mut payload) = integer.split_at_mut(5);
+            integer_header.copy_from_slice(&[0x02, 0x82, integer_len[0], integer_len[1], 0x7f]);
+            if i == 0 && *payload_written_bytes == 0 {
+                let (len, rest) = payload.split_at_mut(4);
+                len.copy_from_slice(&(response.len() as u32).to_be_bytes());
+                payload = rest;
+            }
+            let unsent = &response[*payload_written_bytes as usize..];
+            let payload_bytes_to_write = unsent.len().min(payload.len());
+            payload[..payload_bytes_to_write].copy_from_slice(&unsent[..payload_bytes_to_write]);
+            payload[payload_bytes_to_write..].fill(0);
+            *payload_written_bytes += payload_bytes_to_write as u32;
+        }
+    })
+}
+
+/// Answers a not-webusb credential ID whose request requires permissions that its origin is not granted, see `u2f::send_forbidden_response`.
+#[cfg(feature = "ctap2")]
+pub fn send_forbidden_response(
+    tx: &mut OutgoingMessage,
+    rp_id_hash: &[u8; 32],
+) -> Result<(), NotWebUsbError> {
+    let signature = u2f::FORBIDDEN_SIGNATURE;
+    write_assertion(tx, rp_id_hash, true, signature.len(), |written| {
+        written.copy_from_slice(&signature)
+    })
+}
+
+/// Responds to the current request with a CTAP2 error status.
+pub fn send_error_response(tx: &mut OutgoingMessage, status: Status) -> Result<(), NotWebUsbError> {
+    write_response(tx, |encoder| encoder.raw(&[status as u8]))
+}
+
+/// Writes a getAssertion response, whose signature of `signature_len` bytes is filled in by `write_signature`.
+#[cfg(feature = "ctap2")]
+fn write_assertion(
+    tx: &mut OutgoingMessage,
+    rp_id_hash: &[u8; 32],
+    user_presence: bool,
+    signature_len: usize,
+    write_signature: impl FnOnce(&mut [u8]),
+) -> Result<(), NotWebUsbError> {
+    write_response(tx, |encoder| {
+        encoder.raw(&[Status::Success as u8])?;
+        // The credential (0x01) may be left out, since the allowList only contains the credential being answered.
+        encoder.map(2)?;
+
+        encoder.unsigned(0x02)?;
+        let authenticator_data = encoder.bytes_mut(AUTHENTICATOR_DATA_LEN)?;
+        authenticator_data[..32].copy_from_slice(rp_id_hash);
+        authenticator_data[32] = if user_presence { FLAG_USER_PRESENT } else { 0 };
+        authenticator_data[33..].copy_from_slice(&(MAXIMUM_CREDENTIAL_ID_LEN as u32).to_be_bytes());
+
+        encoder.unsigned(0x03)?;
+        write_signature(encoder.bytes_mut(signature_len)?);
+        Ok(())
+    })
+}
+
+fn write_response(
+    tx: &mut OutgoingMessage,
+    encode: impl FnOnce(&mut Encoder) -> Result<(), CborError>,
+) -> Result<(), NotWebUsbError> {
+    let mut granted = tx.grant()?;
+    let mut encoder = Encoder::new(&mut granted);
+    if let Err(error) = encode(&mut encoder) {
+        error!("Failed to encode CTAP2 response {}", error);
+        return Err(NotWebUsbError::InternalError);
+    }
+    let len = encoder.len();
+    debug!("CTAP2 response raw {}", &granted[..len]);
+    granted.commit(len);
+    Ok(())
+}
+
+/// The parts of an authenticatorGetAssertion request that not-webusb uses.
+#[cfg(feature = "ctap2")]
+struct GetAssertion<'r> {
+    rp_id: &'r str,
+    /// The first credential of the allowList, not-webusb clients only ever send one.
+    credential_id: &'r [u8],
+    /// The `up` option, which defaults to true.
+    user_presence: bool,
+}
+
+#[cfg(feature = "ctap2")]
+impl<'r> GetAssertion<'r> {
+    /// Returns the status to respond with if the request is malformed.
+    fn decode(parameters: &'r [u8]) -> Result<Self, Status> {
+        let mut decoder = Decoder::new(parameters);
+        let mut rp_id = None;
+        let mut client_data_hash = None;
+        let mut credential_id = None;
+        let mut user_presence = true;
+
+        for _ in 0..decoder.map()? {
+            match decoder.unsigned()? {
+                0x01 => rp_id = Some(decoder.text()?),
+                0x02 => client_data_hash = Some(decoder.bytes()?),
+                // allowList
+                0x03 => {
+                    for _ in 0..decoder.array()? {
+                        let id = decode_credential_descriptor(&mut decoder)?;
+                        credential_id = credential_id.or(Some(id));
+                    }
+                }
+                // options
+                0x05 => {
+                    for _ in 0..decoder.map()? {
+                        match decoder.text()? {
+                            "up" => user_presence = decoder.bool()?,
+                            _ => decoder.skip()?,
+                        }
+                    }
+                }
+                _ => decoder.skip()?,
+            }
+        }
+
+        let (Some(rp_id), Some(_)) = (rp_id, client_data_hash) else {
+            return Err(Status::MissingParameter);
+        };
+        // not-webusb has no discoverable credentials, so an empty allowList can never be answered.
+        let Some(credential_id) = credential_id else {
+            return Err(Status::NoCredentials);
+        };
+        Ok(GetAssertion {
+            rp_id,
+            credential_id,
+            user_presence,
+        })
+    }
+}
+
+/// Returns the id of a PublicKeyCredentialDescriptor.
+#[cfg(feature = "ctap2")]
+fn decode_credential_descriptor<'r>(decoder: &mut Decoder<'r>) -> Result<&'r [u8], Status> {
+    let mut id = None;
+    for _ in 0..decoder.map()? {
+        match decoder.text()? {
+            "id" => id = Some(decoder.bytes()?),
+            _ => decoder.skip()?,
+        }
+    }
+    id.ok_or(Status::MissingParameter)
+}
@@ -1,9 +1,86 @@
-use crate::u2f::{receive_user_request, send_user_response};
-use crate::{MAXIMUM_CTAPHID_MESSAGE, MAXIMUM_CTAPHID_MESSAGE_X2};
+#[cfg(feature = "ctap2")]
+use crate::Duration;
+use crate::admin::KeyHandleFilter;
+use crate::builder::DeviceIdentity;
+use crate::ctap2;
+use crate::outgoing::OutgoingMessage;
+use crate::u2f::{Received, receive_user_request};
+use crate::{Instant, MAXIMUM_REQUEST_MESSAGE, NotWebUsbError};
 use arrayvec::ArrayVec;
-use bbqueue::Producer;
 use usbd_human_interface_device::device::fido::RawFidoReport;
 
+/// The CID that clients send CTAPHID_INIT on to allocate a channel.
+pub const BROADCAST_CID: u32 = 0xFFFFFFFF;
+
+/// The capability flag advertised in the init response when CTAPHID_WINK is supported.
+pub const CAPABILITY_WINK: u8 = 0x01;
+
+/// The capability flag advertised in the init response when CTAP2 requests are supported, browsers only send them when it is set.
+pub const CAPABILITY_CBOR: u8 = 0x04;
+
+/// How often a keepalive is sent while a CTAP2 request waits on the application, as recommended by the CTAP spec.
+#[cfg(feature = "ctap2")]
+pub const KEEPALIVE_INTERVAL: Duration = Duration::millis(100);
+
+/// How many channels can be allocated at once.
+/// Each client allocates its own channel, e.g. the OS FIDO stack and each browser process.
+const MAXIMUM_CHANNELS: usize = 8;
+
+/// The channels allocated to clients via CTAPHID_INIT.
+///
+/// Requests are only accepted on allocated channels.
+/// Once every slot is in use, allocating a new channel evicts the oldest one.
+pub struct Channels {
+    /// The allocated CIDs, oldest first.
+    allocated: ArrayVec<u32, MAXIMUM_CHANNELS>,
+    /// The CID to try allocating next.
+    /// It keeps counting up across resets, so that a client holding a CID from before a reset is not handed over to a new client.
+    cid_next: u32,
+}
+
+impl Channels {
+    pub fn new() -> Self {
+        Channels {
+            allocated: ArrayVec::new(),
+            // Start at CID 1, since CID 0 is reserved
+            cid_next: 1,
+        }
+    }
+
+    pub fn is_allocated(&self, cid: u32) -> bool {
+        self.allocated.contains(&cid)
+    }
+
+    /// Allocates a new channel, returning its CID.
+    ///
+    /// `busy_cid` is the channel of the in progress transaction, which is never evicted.
+    pub fn allocate(&mut self, busy_cid: Option<u32>) -> u32 {
+        if self.allocated.is_full() {
+            let evict = self
+                .allocated
+                .iter()
+                .position(|cid| Some(*cid) != busy_cid)
+                .unwrap_or(0);
+            let evicted = self.allocated.remove(evict);
+            info!("All CTAPHID channels in use, evicting channel {}", evicted);
+        }
+
+        // The reserved CIDs and any CIDs still allocated after wrapping around are skipped.
+        let mut cid = self.cid_next;
+        while cid == 0 || cid == BROADCAST_CID || self.is_allocated(cid) {
+            cid = cid.wrapping_add(1);
+        }
+        self.cid_next = cid.wrapping_add(1);
+        self.allocated.push(cid);
+        cid
+    }
+
+    /// Frees all channels, clients must allocate new channels to continue.
+    pub fn clear(&mut self) {
+        self.allocated.clear();
+    }
+}
+
 /// Represents the state of an in progress transaction.
 /// The term `transaction` comes from the CTAP spec, referring to the processing of a request/response pair.
 pub struct InProgressTransaction {
@@ -11,19 +88,31 @@ pub struct InProgressTransaction {
     pub cid: u32,
     /// valid values are 0-127
     pub request_sequence: u8,
-    pub request_buffer: [u8; MAXIMUM_CTAPHID_MESSAGE],
     pub request_payload_size: usize,
     pub request_payload_bytes_written: usize,
     pub response_continuation_state: ContinuationState,
     pub response_ready_to_send: bool,
     pub response_final_packet_is_ready_to_send: bool,
+    /// When a packet of this transaction was last received from or sent to the client.
+    pub last_activity: Instant,
+    /// True if the request is a not-webusb key handle, so aborting the transaction also aborts the user data transfer.
+    pub carries_user_data: bool,
+    /// True while a CTAP2 request is held open until the application sends its response.
+    /// CTAP2 has no way to ask the client to retry later, so instead the client is sent keepalives meanwhile.
+    #[cfg(feature = "ctap2")]
+    pub awaiting_user_response: bool,
+    /// True while a CTAP2 request is held open until the `OriginFilter` decides on its origin, see `OriginVerdict::Ask`.
+    /// The request is received again from `Protocol::request_buffer` until then, with keepalives sent meanwhile.
+    #[cfg(feature = "ctap2")]
+    pub awaiting_origin_verdict: bool,
 }
 
 #[derive(Clone, Copy)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum MessageType {
     /// The new messages for CTAP2.
-    /// We implement only a very tiny part of this to get U2F messages working
+    /// We implement only a very tiny part of this, GetInfo to get U2F messages working,
+    /// and with the `ctap2` feature, getAssertion for smuggling larger amounts of user data in.
     Cbor,
     /// The older style messages for CTAP1.
     /// This is what we use for smuggling user data in.
@@ -37,86 +126,82 @@ pub enum ContinuationState {
 }
 
 impl InProgressTransaction {
-    pub fn new(message_type: MessageType, cid: u32, request_payload_size: u16) -> Self {
+    pub fn new(
+        message_type: MessageType,
+        cid: u32,
+        request_payload_size: u16,
+        now: Instant,
+    ) -> Self {
         InProgressTransaction {
             message_type,
             cid,
             request_sequence: 0,
-            request_buffer: [0; MAXIMUM_CTAPHID_MESSAGE],
             request_payload_size: request_payload_size as usize,
             request_payload_bytes_written: 0,
             response_continuation_state: ContinuationState::Initial,
             response_ready_to_send: false,
             response_final_packet_is_ready_to_send: false,
+            last_activity: now,
+            carries_user_data: false,
+            #[cfg(feature = "ctap2")]
+            awaiting_user_response: false,
+            #[cfg(feature = "ctap2")]
+            awaiting_origin_verdict: false,
         }
     }
 
+    /// Returns true if the transaction cannot progress until the client sends or receives more packets.
+    /// Otherwise the transaction is waiting on the device, e.g. for the application to call `NotWebUsb::send_response`.
+    pub fn is_waiting_on_client(&self) -> bool {
+        self.is_receiving_request() || self.response_ready_to_send
+    }
+
+    /// Returns true if the client has not yet sent all packets of the request.
+    pub fn is_receiving_request(&self) -> bool {
+        self.request_payload_bytes_written < self.request_payload_size
+    }
+
     /// Returns true if the request has finished parsing and the response was sent
-    pub fn receive_user_request(
+    ///
+    /// Once the request is complete, passing empty `data` receives it again from `request_buffer`.
+    pub fn receive_user_request<'r>(
         &mut self,
         data: &[u8],
-        tx: &mut Producer<MAXIMUM_CTAPHID_MESSAGE_X2>,
-        web_origin_filter: &dyn Fn([u8; 32]) -> bool,
-    ) -> Option<ArrayVec<u8, 255>> {
-        self.request_buffer
+        request_buffer: &'r mut [u8; MAXIMUM_REQUEST_MESSAGE],
+        tx: &mut OutgoingMessage,
+        web_origin_filter: &mut dyn KeyHandleFilter,
+        identity: &DeviceIdentity,
+    ) -> Result<Received<'r>, NotWebUsbError> {
+        // The final packet is padded with zeroes past the end of the payload.
+        let remaining = self
+            .request_payload_size
+            .saturating_sub(self.request_payload_bytes_written);
+        let data = &data[..data.len().min(remaining)];
+        request_buffer
             [self.request_payload_bytes_written..self.request_payload_bytes_written + data.len()]
             .copy_from_slice(data);
 
         // if we have completely received the request, respond to it.
         self.request_payload_bytes_written += data.len();
         if self.request_payload_bytes_written >= self.request_payload_size {
-            let request = &self.request_buffer[..self.request_payload_size];
+            let request = &request_buffer[..self.request_payload_size];
             match self.message_type {
                 MessageType::Cbor => {
-                    let mut granted = tx.grant_exact(MAXIMUM_CTAPHID_MESSAGE).unwrap();
-
-                    // For browsers like chrome on linux it is sufficent to simply reply to CBOR messages with `CtapHidError::InvalidCommand`.
-                    // However all browsers using the webauthn.dll (all browsers running on windows) will give up on us unless we can tell them we only support U2F by handling the CBOR GetInfo request. 🙃
-                    // For all other CBOR messages we can just return InvalidCommand, which we do at an earlier stage.
-
-                    // To avoid pulling in an entire CBOR implementation, we just hardcode this CBOR GetInfo response which is generated like this:
-                    //
-                    // ```rust
-                    //#[derive(Debug, Serialize, Deserialize)]
-                    //struct GetInfo {
-                    //    versions: Vec<String>,
-                    //    #[serde(with = "serde_bytes")]
-                    //    aaguid: Vec<u8>,
-                    //}
-                    //let get_info = GetInfo {
-                    //    versions: vec!["U2F_V2".to_owned()],
-                    //    // a unique aaguid for not-webusb
-                    //    aaguid: vec![
-                    //        0xe3, 0xb1, 0x76, 0x8b, 0x55, 0x91, 0x4a, 0xd7, 0xb4, 0x6e, 0xac, 0xc7, 0x60, 0x84,
-                    //        0x0b, 0x3e,
-                    //    ],
-                    //};
-                    //let bytes: Vec<u8> = serde_cbor::to_vec(&get_info).unwrap();
-                    //```
-                    let get_info_response = [
-                        162, 104, 118, 101, 114, 115, 105, 111, 110, 115, 129, 102, 85, 50, 70, 95,
-                        86, 50, 102, 97, 97, 103, 117, 105, 100, 80, 227, 177, 118, 139, 85, 145,
-                        74, 215, 180, 110, 172, 199, 96, 132, 11, 62,
-                    ];
-                    let len = get_info_response.len();
-                    granted[..len].copy_from_slice(&get_info_response);
-                    granted.commit(len);
+                    // Browsers on windows, which all use webauthn.dll, give up on devices that do not answer GetInfo,
+                    // so it is answered even without the `ctap2` feature, to tell them that only U2F is supported.
+                    return ctap2::receive_user_request(request, tx, web_origin_filter, identity);
                 }
                 MessageType::U2f => {
-                    return receive_user_request(request, tx, web_origin_filter);
+                    return receive_user_request(
+                        request,
+                        tx,
+                        web_origin_filter,
+                        identity.u2f_version,
+                    );
                 }
             }
         }
-        None
-    }
-
-    pub fn send_user_response(
-        &mut self,
-        response: &[u8],
-        bytes_sent: &mut u32,
-        tx: &mut Producer<MAXIMUM_CTAPHID_MESSAGE_X2>,
-    ) {
-        send_user_response(response, bytes_sent, tx);
+        Ok(Received::Answered)
     }
 }
 
@@ -145,6 +230,7 @@ impl CtapHidRequest {
                     data: packet[7..].try_into().unwrap(),
                     ty: MessageType::U2f,
                 },
+                0x08 => CtapHidRequestTy::Wink,
                 0x06 => CtapHidRequestTy::Init {
                     nonce8: packet[7..15].try_into().unwrap(),
                 },
@@ -171,6 +257,8 @@ pub enum CtapHidRequestTy {
     },
     /// Send the entire raw request back as is.
     Ping,
+    /// Perform a vendor defined action that lets the user identify the device.
+    Wink,
     /// A U2F  or CBOR message.
     MessageInitial {
         /// Full length of the payload, possibly this packet and one or more continuation packets.
@@ -216,23 +304,38 @@ pub enum CtapHidResponseTy<'a> {
         /// Is the message CBOR or U2F?
         ty: MessageType,
     },
+    /// The empty response to a Wink
+    Wink,
+    /// Tells the client that a CTAP2 request is still being processed.
+    #[cfg(feature = "ctap2")]
+    KeepAlive(KeepAliveStatus),
     /// Use this to provide a response to a Ping or if you need to construct a custom response for any reason.
     RawReport(RawFidoReport),
     Error(CtapHidError),
 }
 
+/// Why a CTAP2 request is not answered yet, sent in a keepalive.
+#[cfg(feature = "ctap2")]
+#[derive(Clone, Copy)]
+pub enum KeepAliveStatus {
+    /// The application is preparing its response.
+    Processing = 0x01,
+    /// The `OriginFilter` is waiting for the user, see `OriginVerdict::Ask`.
+    UserPresenceNeeded = 0x02,
+}
+
 #[derive(Clone, Copy)]
 pub enum CtapHidError {
     InvalidCommand = 0x01,
     //InvalidParameter = 0x02,
     InvalidLen = 0x03,
     InvalidSeq = 0x04,
-    //MessageTimeout = 0x05,
+    MessageTimeout = 0x05,
     ChannelBusy = 0x06,
     //LockRequired = 0x0A,
-    //InvalidChannel = 0x0B,
+    InvalidChannel = 0x0B,
     KeepAliveCancel = 0x2D,
-    //Other = 0x7F,
+    Other = 0x7F,
 }
 
 pub struct InitResponse {
@@ -265,11 +368,11 @@ impl CtapHidResponse<'_> {
                 let data = &mut report.packet[7..];
                 data[0..8].copy_from_slice(&response.nonce_8_bytes);
                 data[8..12].copy_from_slice(&response.channel_id);
-                data[13] = response.protocol_version;
-                data[14] = response.device_version_major;
-                data[15] = response.device_version_minor;
-                data[16] = response.device_version_build;
-                data[17] = response.capabilities;
+                data[12] = response.protocol_version;
+                data[13] = response.device_version_major;
+                data[14] = response.device_version_minor;
+                data[15] = response.device_version_build;
+                data[16] = response.capabilities;
             }
             CtapHidResponseTy::Message { length, data, ty } => match self.continuation_state {
                 ContinuationState::Initial => {
@@ -282,14 +385,15 @@ impl CtapHidResponse<'_> {
                         bcnt: *length,
                     }
                     .encode(report);
-                    if data.len() > report.packet.len() - 7 {
-                        panic!(
-                            "message data is too long for one initial packet, was {} but must be less than or equal to {}",
-                            data.len(),
-                            report.packet.len() - 7
-                        );
-                    }
-                    report.packet[7..7 + data.len()].copy_from_slice(data);
+                    debug_assert!(
+                        data.len() <= report.packet.len() - 7,
+                        "message data is too long for one initial packet, was {} but must be less than or equal to {}",
+                        data.len(),
+                        report.packet.len() - 7
+                    );
+                    // The firmware must never panic, so in release builds excess data is truncated instead.
+                    let len = data.len().min(report.packet.len() - 7);
+                    report.packet[7..7 + len].copy_from_slice(&data[..len]);
                 }
                 ContinuationState::Continuation { sequence } => {
                     CtapHeaderContinuation {
@@ -297,21 +401,40 @@ impl CtapHidResponse<'_> {
                         seq: sequence,
                     }
                     .encode(report);
-                    if data.len() > report.packet.len() - 5 {
-                        panic!(
-                            "message data is too long for one continuation packet, was {} but must be less than or equal to {}",
-                            data.len(),
-                            report.packet.len() - 5
-                        );
-                    }
-                    report.packet[5..5 + data.len()].copy_from_slice(data);
+                    debug_assert!(
+                        data.len() <= report.packet.len() - 5,
+                        "message data is too long for one continuation packet, was {} but must be less than or equal to {}",
+                        data.len(),
+                        report.packet.len() - 5
+                    );
+                    // The firmware must never panic, so in release builds excess data is truncated instead.
+                    let len = data.len().min(report.packet.len() - 5);
+                    report.packet[5..5 + len].copy_from_slice(&data[..len]);
                 }
             },
+            CtapHidResponseTy::Wink => {
+                CtapHeaderInitialization {
+                    cid: self.cid,
+                    cmd: 0x88,
+                    bcnt: 0,
+                }
+                .encode(report);
+            }
+            #[cfg(feature = "ctap2")]
+            CtapHidResponseTy::KeepAlive(status) => {
+                CtapHeaderInitialization {
+                    cid: self.cid,
+                    cmd: 0xBB,
+                    bcnt: 1,
+                }
+                .encode(report);
+                report.packet[7] = *status as u8;
+            }
             CtapHidResponseTy::RawReport(raw) => *report = *raw,
             CtapHidResponseTy::Error(error) => {
                 CtapHeaderInitialization {
                     cid: self.cid,
-                    cmd: 0x3F,
+                    cmd: 0xBF,
                     bcnt: 1,
                 }
                 .encode(report);
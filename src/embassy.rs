@@ -0,0 +1,255 @@
+#[cfg(feature = "postcard")]
+use crate::{Call, Callee};
+use crate::{
+    Instant, NotWebUsbBuilder, NotWebUsbError, NotWebUsbStorage, OriginFilter, Protocol, Request,
+    Timeouts,
+};
+use arrayvec::ArrayVec;
+use embassy_usb::Builder;
+use embassy_usb::class::hid::{
+    Config, HidBootProtocol, HidReader, HidReaderWriter, HidSubclass, HidWriter, ReadError, State,
+};
+use embassy_usb::driver::{Driver, EndpointError};
+use usbd_human_interface_device::device::fido::{FIDO_REPORT_DESCRIPTOR, RawFidoReport};
+
+/// How often `EmbassyNotWebUsb` stops waiting for a report to check for stalled transfers, when timeouts are enabled,
+/// and to progress a CTAP2 request held open for the `OriginFilter` or the application, see `Protocol::progress_awaiting_transaction`.
+const EXPIRY_CHECK_INTERVAL: embassy_time::Duration = embassy_time::Duration::from_millis(100);
+
+/// not-webusb for [embassy-usb](https://docs.rs/embassy-usb), with the same protocol handling as `NotWebUsb`.
+///
+/// Instead of polling, wait for requests via `EmbassyNotWebUsb::next_request` and answer them via `EmbassyNotWebUsb::respond`:
+/// ```ignore
+/// let mut not_webusb = EmbassyNotWebUsb::new(&mut builder, &mut state, storage, &|_| true);
+/// // build and run the `UsbDevice` in a separate task
+/// loop {
+///     let response = handle(not_webusb.next_request().await.payload);
+///     not_webusb.respond(response).await.unwrap();
+/// }
+/// ```
+pub struct EmbassyNotWebUsb<
+    'd,
+    D: Driver<'d>,
+    const MAX_MESSAGE_LEN: usize = 1024,
+    F: OriginFilter = &'d dyn Fn([u8; 32]) -> bool,
+> {
+    reader: HidReader<'d, D, 64>,
+    writer: HidWriter<'d, D, 64>,
+    protocol: Protocol<'d, MAX_MESSAGE_LEN, F>,
+}
+
+impl<'d, D: Driver<'d>, const MAX_MESSAGE_LEN: usize, F: OriginFilter>
+    EmbassyNotWebUsb<'d, D, MAX_MESSAGE_LEN, F>
+{
+    /// Create a new EmbassyNotWebUsb instance, adding a FIDO HID interface to `builder`.
+    ///
+    /// See `NotWebUsb::new` for details on `web_origin_filter` and `NotWebUsbStorage` for `storage`.
+    /// To give the device its own identity, e.g. an aaguid, construct it via `NotWebUsbBuilder::build_embassy` instead.
+    pub fn new(
+        builder: &mut Builder<'d, D>,
+        state: &'d mut State<'d>,
+        storage: &'d mut NotWebUsbStorage<MAX_MESSAGE_LEN>,
+        web_origin_filter: F,
+    ) -> Self {
+        NotWebUsbBuilder::new(storage, web_origin_filter).build_embassy(builder, state)
+    }
+
+    pub(crate) fn from_protocol(
+        builder: &mut Builder<'d, D>,
+        state: &'d mut State<'d>,
+        protocol: Protocol<'d, MAX_MESSAGE_LEN, F>,
+    ) -> Self {
+        let config = Config {
+            report_descriptor: FIDO_REPORT_DESCRIPTOR,
+            request_handler: None,
+            poll_ms: 5,
+            max_packet_size: 64,
+            hid_subclass: HidSubclass::No,
+            hid_boot_protocol: HidBootProtocol::None,
+        };
+        let (reader, writer) = HidReaderWriter::new(builder, state, config).split();
+        EmbassyNotWebUsb {
+            reader,
+            writer,
+            protocol,
+        }
+    }
+
+    /// Expire requests and responses that the client has stopped sending or receiving, see `NotWebUsb::with_timeouts`.
+    ///
+    /// Time is measured via `embassy_time`, so a time driver is required.
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.protocol.set_timeouts(&embassy_now, timeouts);
+        self
+    }
+
+    /// Advertise support for CTAPHID_WINK, calling `wink` whenever a client sends one, see `NotWebUsb::with_wink`.
+    pub fn with_wink(mut self, wink: &'d dyn Fn()) -> Self {
+        self.protocol.wink = Some(wink);
+        self
+    }
+
+    /// The `web_origin_filter` passed to `EmbassyNotWebUsb::new`.
+    pub fn origin_filter(&self) -> &F {
+        &self.protocol.web_origin_filter
+    }
+
+    /// The `web_origin_filter` passed to `EmbassyNotWebUsb::new`, e.g. to update the state of a stateful `OriginFilter`.
+    pub fn origin_filter_mut(&mut self) -> &mut F {
+        &mut self.protocol.web_origin_filter
+    }
+
+    /// Handles CTAPHID requests until a user request has been fully received, then returns it.
+    /// Calling this does not consume the request, if a request is already pending it is returned immediately.
+    ///
+    /// USB errors and recoverable errors, as described by `NotWebUsbError`, are logged and otherwise handled internally.
+    /// If the USB connection is lost, this waits for it to be reestablished.
+    pub async fn next_request(&mut self) -> Request<'_> {
+        while self.protocol.check_pending_request().is_none() {
+            self.handle_next_report().await;
+        }
+        // A request is pending, so this never panics.
+        self.protocol.check_pending_request().unwrap()
+    }
+
+    /// Handles CTAPHID requests until a user request decoded as an RPC call is pending, then returns it, see `NotWebUsb::check_pending_call`.
+    ///
+    /// Requests that do not start with a method ID are answered with `RequestError::DecodeFailed` and skipped.
+    /// The response of the returned `Call` is sent as the client asks for it during the next `EmbassyNotWebUsb::next_request` or `EmbassyNotWebUsb::next_call`.
+    /// To answer calls via the `dispatch` function generated by `rpc!`, call it after `EmbassyNotWebUsb::next_request` instead.
+    #[cfg(feature = "postcard")]
+    pub async fn next_call(&mut self) -> Call<'_, 'd, MAX_MESSAGE_LEN, F> {
+        loop {
+            let payload = self.next_request().await.payload;
+            if crate::method_id(payload).is_some() {
+                break;
+            }
+            // Answers the request with `RequestError::DecodeFailed`.
+            self.protocol.check_pending_call();
+        }
+        // A request starting with a method ID is pending, so this never panics.
+        self.protocol.check_pending_call().unwrap()
+    }
+
+    /// Returns the current request decoded as an RPC call, if there is one, without waiting, see `NotWebUsb::check_pending_call`.
+    #[cfg(feature = "postcard")]
+    pub fn check_pending_call(&mut self) -> Option<Call<'_, 'd, MAX_MESSAGE_LEN, F>> {
+        self.protocol.check_pending_call()
+    }
+
+    /// Sends a response to the currently pending request.
+    /// Calling this consumes the request.
+    ///
+    /// The response is sent as the client asks for it during `EmbassyNotWebUsb::next_request`, see `NotWebUsb::send_response`.
+    ///
+    /// Returns `NotWebUsbError::NoPendingRequest` if there is no request to respond to.
+    pub async fn respond(
+        &mut self,
+        message: ArrayVec<u8, MAX_MESSAGE_LEN>,
+    ) -> Result<(), NotWebUsbError> {
+        self.protocol.send_response(message)?;
+        self.flush().await;
+        Ok(())
+    }
+
+    /// Sends a response to the currently pending request by writing it directly into the message buffer, see `NotWebUsb::send_response_with`.
+    /// Calling this consumes the request.
+    ///
+    /// Returns `NotWebUsbError::NoPendingRequest` if there is no request to respond to, in which case `write` is not called.
+    pub async fn respond_with(
+        &mut self,
+        write: impl FnOnce(&mut [u8]) -> usize,
+    ) -> Result<(), NotWebUsbError> {
+        self.protocol.send_response_with(write)?;
+        self.flush().await;
+        Ok(())
+    }
+
+    /// Sends a copy of `message` as the response to the currently pending request.
+    /// Calling this consumes the request.
+    ///
+    /// Returns `NotWebUsbError::NoPendingRequest` if there is no request to respond to.
+    /// Returns `NotWebUsbError::ResponseTooLong` if `message` is longer than `MAX_MESSAGE_LEN`, in which case the request remains pending.
+    pub async fn respond_from_slice(&mut self, message: &[u8]) -> Result<(), NotWebUsbError> {
+        self.protocol.send_response_from_slice(message)?;
+        self.flush().await;
+        Ok(())
+    }
+
+    /// Waits for the next report from the client and handles it.
+    async fn handle_next_report(&mut self) {
+        if let Err(err) = self.protocol.expire_stalled() {
+            warn!("not-webusb error {}", err);
+        }
+        self.flush().await;
+
+        let mut report = RawFidoReport::default();
+        let read = self.reader.read(&mut report.packet);
+        let result = if self.protocol.clock.is_some() || self.protocol.is_awaiting() {
+            match embassy_time::with_timeout(EXPIRY_CHECK_INTERVAL, read).await {
+                Ok(result) => result,
+                // Return to check for stalled transfers
+                Err(embassy_time::TimeoutError) => return,
+            }
+        } else {
+            read.await
+        };
+        match result {
+            Ok(_) => {
+                if let Err(err) = self.protocol.receive_report(&report) {
+                    warn!("not-webusb error {}", err);
+                }
+                self.flush().await;
+            }
+            Err(ReadError::Disabled) => self.wait_for_connection().await,
+            Err(err) => {
+                warn!("Failed to read fido report: {:?} - ignoring it", err);
+            }
+        }
+    }
+
+    /// Writes all reports that are ready to be sent.
+    async fn flush(&mut self) {
+        loop {
+            if let Some(report) = self.protocol.direct_response() {
+                if let Err(EndpointError::Disabled) = self.writer.write(&report.packet).await {
+                    return self.wait_for_connection().await;
+                }
+                self.protocol.direct_response_sent();
+                continue;
+            }
+            match self.protocol.prepare_message_report() {
+                Ok(Some(report)) => {
+                    if let Err(EndpointError::Disabled) = self.writer.write(&report.packet).await {
+                        return self.wait_for_connection().await;
+                    }
+                    self.protocol.message_report_sent();
+                }
+                Ok(None) => return,
+                Err(err) => {
+                    // The transaction was aborted and an error queued as a direct response.
+                    warn!("not-webusb error {}", err);
+                }
+            }
+        }
+    }
+
+    async fn wait_for_connection(&mut self) {
+        error!("USB endpoint disabled - resetting NotWebusb state");
+        self.protocol.reset_state();
+        self.reader.ready().await;
+    }
+}
+
+#[cfg(feature = "postcard")]
+impl<'d, D: Driver<'d>, const MAX_MESSAGE_LEN: usize, F: OriginFilter>
+    Callee<'d, MAX_MESSAGE_LEN, F> for EmbassyNotWebUsb<'d, D, MAX_MESSAGE_LEN, F>
+{
+    fn check_pending_call(&mut self) -> Option<Call<'_, 'd, MAX_MESSAGE_LEN, F>> {
+        self.protocol.check_pending_call()
+    }
+}
+
+fn embassy_now() -> Instant {
+    Instant::from_ticks(embassy_time::Instant::now().as_micros())
+}
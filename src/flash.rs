@@ -0,0 +1,268 @@
+//! An origin allowlist persisted to flash, see `FlashAllowlist`.
+
+use crate::{OriginFilter, OriginStore, OriginVerdict};
+use arrayvec::ArrayVec;
+use embedded_storage::nor_flash::NorFlash;
+
+/// Marks flash written by `FlashAllowlist`, followed by a format version.
+const MAGIC: [u8; 4] = *b"NWA\x02";
+
+/// Each copy of the allowlist is stored as a header block followed by one block per origin hash.
+/// The header holds `MAGIC`, the sequence number, the number of origins and a CRC-32 of all three and the origins.
+const BLOCK_LEN: usize = 32;
+
+/// Writes are staged through a buffer of this size, so `NorFlash::WRITE_SIZE` must divide it.
+const STAGING_LEN: usize = 256;
+
+/// An `OriginFilter` allowing the origins stored in a region of `NorFlash`, which holds up to `N` origins.
+///
+/// Unlike `OriginAllowlist`, the origins can change without a firmware release,
+/// either by the firmware via `OriginStore` or by the admin origin via admin messages, see `NotWebUsbBuilder::admin_origin`.
+///
+/// ```ignore
+/// const ADMIN: [u8; 32] = origin_hash!("admin.example.com");
+/// let allowlist = FlashAllowlist::<_, 16>::new(flash, ALLOWLIST_OFFSET).unwrap();
+/// let not_webusb = NotWebUsbBuilder::new(storage, allowlist)
+///     .admin_origin(ADMIN)
+///     .build(fido);
+/// ```
+///
+/// The region starts at `offset` and spans `FlashAllowlist::region_len` bytes, it must not be used for anything else.
+/// It is split into two slots that changes are written to in turn, each copy with a higher sequence number than the last.
+/// A copy is only loaded if its checksum matches, and its header is written last,
+/// so if the device loses power partway through a change the previous copy is loaded instead.
+pub struct FlashAllowlist<S: NorFlash, const N: usize> {
+    flash: S,
+    offset: u32,
+    origins: ArrayVec<[u8; 32], N>,
+    /// The slot holding the current copy, the next change is written to the other one.
+    slot: usize,
+    /// The sequence number of the current copy.
+    sequence: u32,
+}
+
+impl<S: NorFlash, const N: usize> FlashAllowlist<S, N> {
+    /// Load the allowlist stored at `offset` in `flash`.
+    /// A region that was never written by `FlashAllowlist`, e.g. erased flash, loads as an empty allowlist.
+    ///
+    /// Panics if `offset` is not a multiple of `NorFlash::ERASE_SIZE`, or if the read or write size of `flash` is not supported.
+    pub fn new(flash: S, offset: u32) -> Result<Self, S::Error> {
+        assert!(
+            offset as usize % S::ERASE_SIZE == 0,
+            "FlashAllowlist offset must be aligned to the flash erase size"
+        );
+        assert!(
+            BLOCK_LEN % S::READ_SIZE == 0 && STAGING_LEN % S::WRITE_SIZE == 0,
+            "FlashAllowlist requires a flash read size dividing 32 and a write size dividing 256"
+        );
+
+        let mut allowlist = FlashAllowlist {
+            flash,
+            offset,
+            origins: ArrayVec::new(),
+            // Until a copy is found, the first change is written to slot 0.
+            slot: 1,
+            sequence: 0,
+        };
+        let mut newest: Option<(usize, StoredCopy<N>)> = None;
+        for slot in 0..2 {
+            if let Some(copy) = allowlist.load(slot)?
+                && newest
+                    .as_ref()
+                    .is_none_or(|(_, newest)| is_newer(copy.sequence, newest.sequence))
+            {
+                newest = Some((slot, copy));
+            }
+        }
+        match newest {
+            Some((slot, copy)) => {
+                allowlist.slot = slot;
+                allowlist.sequence = copy.sequence;
+                allowlist.origins = copy.origins;
+            }
+            None => info!("no origin allowlist found in flash"),
+        }
+        Ok(allowlist)
+    }
+
+    /// The number of bytes of flash used from `offset`: two slots, each with space for `N` origins rounded up to `NorFlash::ERASE_SIZE`.
+    pub const fn region_len() -> usize {
+        2 * Self::slot_len()
+    }
+
+    /// The stored origin hashes.
+    pub fn origins(&self) -> &[[u8; 32]] {
+        &self.origins
+    }
+
+    /// Returns true if the origin whose rpId hashes to `origin_hash` is stored.
+    pub fn allows(&self, origin_hash: [u8; 32]) -> bool {
+        self.origins.as_slice().contains(&origin_hash)
+    }
+
+    /// Returns the underlying flash.
+    pub fn into_flash(self) -> S {
+        self.flash
+    }
+
+    const fn slot_len() -> usize {
+        ((N + 1) * BLOCK_LEN).div_ceil(S::ERASE_SIZE) * S::ERASE_SIZE
+    }
+
+    fn slot_offset(&self, slot: usize) -> u32 {
+        self.offset + (slot * Self::slot_len()) as u32
+    }
+
+    /// Read the copy in `slot`, or `None` if it is missing or incomplete.
+    fn load(&mut self, slot: usize) -> Result<Option<StoredCopy<N>>, S::Error> {
+        let slot_offset = self.slot_offset(slot);
+        let mut header = [0; BLOCK_LEN];
+        self.flash.read(slot_offset, &mut header)?;
+        let sequence = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        let count = u32::from_le_bytes(header[8..12].try_into().unwrap()) as usize;
+        let checksum = u32::from_le_bytes(header[12..16].try_into().unwrap());
+        if header[..4] != MAGIC {
+            return Ok(None);
+        }
+        if count > N {
+            warn!("origin allowlist in flash holds more than N origins, ignoring it");
+            return Ok(None);
+        }
+
+        let mut origins = ArrayVec::new();
+        let mut crc = crc32(0, &header[..12]);
+        for block in 1..=count {
+            let mut origin_hash = [0; 32];
+            self.flash
+                .read(slot_offset + (block * BLOCK_LEN) as u32, &mut origin_hash)?;
+            crc = crc32(crc, &origin_hash);
+            origins.push(origin_hash);
+        }
+        if crc != checksum {
+            warn!(
+                "origin allowlist in flash slot {} is incomplete, ignoring it",
+                slot
+            );
+            return Ok(None);
+        }
+        Ok(Some(StoredCopy { sequence, origins }))
+    }
+
+    /// Write `origins` to the slot not holding the current copy, which becomes the current copy once fully written.
+    fn save(&mut self) -> Result<(), S::Error> {
+        let slot = 1 - self.slot;
+        let sequence = self.sequence.wrapping_add(1);
+        let slot_offset = self.slot_offset(slot);
+        self.flash
+            .erase(slot_offset, slot_offset + Self::slot_len() as u32)?;
+
+        let mut header = [0xFF; BLOCK_LEN];
+        header[..4].copy_from_slice(&MAGIC);
+        header[4..8].copy_from_slice(&sequence.to_le_bytes());
+        header[8..12].copy_from_slice(&(self.origins.len() as u32).to_le_bytes());
+        let crc = self
+            .origins
+            .iter()
+            .fold(crc32(0, &header[..12]), |crc, origin_hash| {
+                crc32(crc, origin_hash)
+            });
+        header[12..16].copy_from_slice(&crc.to_le_bytes());
+
+        // The chunk holding the header is written last, so the copy is only found once everything else is written.
+        let blocks = 1 + self.origins.len();
+        let chunks = (blocks * BLOCK_LEN).div_ceil(STAGING_LEN);
+        for chunk in (1..chunks).chain([0]) {
+            let mut staging = [0xFF; STAGING_LEN];
+            let first_block = chunk * STAGING_LEN / BLOCK_LEN;
+            let last_block = blocks.min(first_block + STAGING_LEN / BLOCK_LEN);
+            for block in first_block..last_block {
+                let data = match block {
+                    0 => &header,
+                    _ => &self.origins[block - 1],
+                };
+                let start = (block - first_block) * BLOCK_LEN;
+                staging[start..start + BLOCK_LEN].copy_from_slice(data);
+            }
+            let len =
+                ((last_block - first_block) * BLOCK_LEN).div_ceil(S::WRITE_SIZE) * S::WRITE_SIZE;
+            self.flash
+                .write(slot_offset + (chunk * STAGING_LEN) as u32, &staging[..len])?;
+        }
+
+        self.slot = slot;
+        self.sequence = sequence;
+        Ok(())
+    }
+
+    /// Save the changed origins, reverting them to `previous` if writing fails.
+    fn save_or_revert(&mut self, previous: ArrayVec<[u8; 32], N>) -> bool {
+        match self.save() {
+            Ok(()) => true,
+            Err(_) => {
+                error!("failed to write the origin allowlist to flash");
+                self.origins = previous;
+                false
+            }
+        }
+    }
+}
+
+/// A complete copy of the allowlist read from a slot.
+struct StoredCopy<const N: usize> {
+    sequence: u32,
+    origins: ArrayVec<[u8; 32], N>,
+}
+
+/// Returns true if `sequence` was written after `other`, allowing for the sequence number wrapping around.
+fn is_newer(sequence: u32, other: u32) -> bool {
+    (sequence.wrapping_sub(other) as i32) > 0
+}
+
+/// Continues the CRC-32 (as used by zip and ethernet) `crc` of some bytes with `bytes`, start with a `crc` of 0.
+fn crc32(crc: u32, bytes: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+impl<S: NorFlash, const N: usize> OriginStore for FlashAllowlist<S, N> {
+    fn contains(&mut self, origin_hash: [u8; 32]) -> bool {
+        self.allows(origin_hash)
+    }
+
+    fn insert(&mut self, origin_hash: [u8; 32]) -> bool {
+        let previous = self.origins.clone();
+        if self.origins.try_push(origin_hash).is_err() {
+            warn!("origin allowlist is full");
+            return false;
+        }
+        self.save_or_revert(previous)
+    }
+
+    fn remove(&mut self, origin_hash: [u8; 32]) -> bool {
+        let previous = self.origins.clone();
+        if !OriginStore::remove(&mut self.origins, origin_hash) {
+            return false;
+        }
+        self.save_or_revert(previous)
+    }
+
+    fn get(&mut self, index: usize) -> Option<[u8; 32]> {
+        self.origins.as_slice().get(index).copied()
+    }
+}
+
+impl<S: NorFlash, const N: usize> OriginFilter for FlashAllowlist<S, N> {
+    fn check(&mut self, origin_hash: [u8; 32]) -> OriginVerdict {
+        self.allows(origin_hash).into()
+    }
+
+    fn origin_store(&mut self) -> Option<&mut dyn OriginStore> {
+        Some(self)
+    }
+}
@@ -1,76 +1,272 @@
 #![no_std]
 
-mod ctaphid;
+// This mod MUST go first, so that the others see its macros.
 pub(crate) mod fmt;
+
+mod admin;
+mod builder;
+mod cbor;
+mod ctap2;
+mod ctaphid;
+#[cfg(feature = "embassy")]
+mod embassy;
+mod flash;
+mod origin;
+mod outgoing;
+mod pairing;
+#[cfg(feature = "postcard")]
+mod rpc;
+#[cfg(any(feature = "postcard", feature = "json"))]
+mod typed;
 mod u2f;
 
+pub use crate::admin::{ADMIN_PREFIX, AdminRequest, AdminStatus, MAXIMUM_ADMIN_REQUEST_LEN};
+pub use crate::builder::{DEFAULT_AAGUID, NotWebUsbBuilder};
+#[cfg(feature = "embassy")]
+pub use crate::embassy::EmbassyNotWebUsb;
+pub use crate::flash::FlashAllowlist;
+pub use crate::origin::{OriginAllowlist, OriginFilter, OriginPolicy, OriginVerdict, origin_hash};
+pub use crate::pairing::{ButtonPairing, OriginStore};
+#[cfg(feature = "postcard")]
+pub use crate::rpc::{Call, Callee, Caller, VERSION_METHOD, method_id};
+#[cfg(feature = "json")]
+pub use crate::typed::Json;
+#[cfg(feature = "postcard")]
+pub use crate::typed::Postcard;
+#[cfg(any(feature = "postcard", feature = "json"))]
+pub use crate::typed::{Codec, RequestError};
+
+use crate::admin::AdminBypass;
+use crate::builder::DeviceIdentity;
 use crate::ctaphid::{
-    ContinuationState, CtapHidError, CtapHidRequest, CtapHidRequestTy, CtapHidResponse,
-    CtapHidResponseTy, InProgressTransaction, InitResponse, MessageType,
+    BROADCAST_CID, CAPABILITY_CBOR, CAPABILITY_WINK, Channels, ContinuationState, CtapHidError,
+    CtapHidRequest, CtapHidRequestTy, CtapHidResponse, CtapHidResponseTy, InProgressTransaction,
+    InitResponse, MessageType,
 };
+#[cfg(feature = "ctap2")]
+use crate::ctaphid::{KEEPALIVE_INTERVAL, KeepAliveStatus};
+use crate::outgoing::OutgoingMessage;
+use crate::u2f::{MessageResponseError, Received, UserKeyHandle};
 use arrayvec::ArrayVec;
-use bbqueue::{BBBuffer, Consumer, Producer};
 use frunk::{HCons, HNil};
 use usb_device::{UsbError, bus::UsbBus};
 use usbd_human_interface_device::device::fido::{RawFido, RawFidoReport};
 use usbd_human_interface_device::prelude::*;
 
-// as per FIDO CTAP spec maximum payload size is 7609 bytes
-const MAXIMUM_CTAPHID_MESSAGE: usize = 7609;
-const MAXIMUM_CTAPHID_MESSAGE_X2: usize = MAXIMUM_CTAPHID_MESSAGE * 2;
+// The FIDO CTAP spec allows CTAPHID messages of up to 7609 bytes,
+// but the only messages not-webusb handles are U2F messages, CBOR GetInfo and, with the `ctap2` feature, CBOR getAssertion,
+// so its buffers are only sized for those.
+// Longer requests are rejected with `CtapHidError::InvalidLen` as soon as their first packet arrives.
+const MAXIMUM_REQUEST_MESSAGE: usize = max(u2f::MAXIMUM_REQUEST_LEN, ctap2::MAXIMUM_REQUEST_LEN);
+const MAXIMUM_RESPONSE_MESSAGE: usize = max(u2f::MAXIMUM_RESPONSE_LEN, ctap2::MAXIMUM_RESPONSE_LEN);
+
+const fn max(a: usize, b: usize) -> usize {
+    if a > b { a } else { b }
+}
+
+/// The memory used by a `NotWebUsb` or `EmbassyNotWebUsb` instance for buffering messages.
+///
+/// It is provided by the application, so that it controls where the memory is placed.
+/// Its size is a little over 400 bytes, or about 2.6KB with the `ctap2` feature, plus `MAX_MESSAGE_LEN`, so with a large `MAX_MESSAGE_LEN` it is best kept out of the stack, e.g. in a `static`:
+/// ```ignore
+/// static mut STORAGE: NotWebUsbStorage<1024> = NotWebUsbStorage::new();
+/// // SAFETY: This is the only reference to STORAGE.
+/// let storage = unsafe { &mut *core::ptr::addr_of_mut!(STORAGE) };
+/// let not_webusb = NotWebUsb::new(fido, storage, &|_| true);
+/// ```
+///
+/// Once the instance using it is dropped, the storage can be reused for a new instance, e.g. after a USB reset.
+pub struct NotWebUsbStorage<const MAX_MESSAGE_LEN: usize = 1024> {
+    /// The CTAPHID request message being received.
+    request: [u8; MAXIMUM_REQUEST_MESSAGE],
+    /// The CTAPHID response message being sent.
+    response: [u8; MAXIMUM_RESPONSE_MESSAGE],
+    /// The user request or response, see `Protocol::user_data_buffer`.
+    user_data: ArrayVec<u8, MAX_MESSAGE_LEN>,
+}
+
+impl<const MAX_MESSAGE_LEN: usize> NotWebUsbStorage<MAX_MESSAGE_LEN> {
+    /// Create new storage, this is a `const fn` so that it can initialize a `static`.
+    pub const fn new() -> Self {
+        NotWebUsbStorage {
+            request: [0; MAXIMUM_REQUEST_MESSAGE],
+            response: [0; MAXIMUM_RESPONSE_MESSAGE],
+            user_data: ArrayVec::new_const(),
+        }
+    }
+}
+
+impl<const MAX_MESSAGE_LEN: usize> Default for NotWebUsbStorage<MAX_MESSAGE_LEN> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A point in time, in microseconds, as returned by the clock passed to `NotWebUsb::with_timeouts`.
+/// This is the same type as the `Instant` of HAL timers such as `rp2040_hal::Timer`.
+pub type Instant = fugit::TimerInstantU64<1_000_000>;
+
+/// A duration in microseconds, see `Instant`.
+pub type Duration = fugit::TimerDurationU64<1_000_000>;
+
+/// How long NotWebUsb waits on the client before giving up, see `NotWebUsb::with_timeouts`.
+#[derive(Clone, Copy, Debug)]
+pub struct Timeouts {
+    /// How long a CTAPHID transaction may go without the client sending the next packet of a request or collecting the next packet of a response.
+    pub transaction: Duration,
+    /// How long a partially received request or partially sent response may wait for the client to send its next key handle.
+    /// Each key handle is sent via a separate `navigator.credentials.get` call, so this needs to be much longer than `Timeouts::transaction`.
+    pub user_data: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            transaction: Duration::secs(1),
+            user_data: Duration::secs(30),
+        }
+    }
+}
+
+/// A request received from the client, see `NotWebUsb::check_pending_request`.
+#[derive(Clone, Copy, Debug)]
+pub struct Request<'a> {
+    /// The request message sent by the client.
+    pub payload: &'a [u8],
+    /// The sha256 hash of the rpId of the website that sent the request, as passed to `web_origin_filter`.
+    /// Compare it against `origin_hash!` to tell apart multiple websites accepted by the filter.
+    pub origin_hash: [u8; 32],
+    /// The CTAPHID channel that the final key handle of the request arrived on.
+    pub cid: u32,
+}
 
-// Only contains data for one message at a time.
-// The reader can determine the total length of the message as the initial size of the buffer before it is partially sent.
-// Needs the double the number of CTAPHID message max bytes since the bytes might be marked as used.
-// TODO: consider a better type than BBBuffer for this purpose.
-static OUTGOING_MESSAGE_BYTES: BBBuffer<MAXIMUM_CTAPHID_MESSAGE_X2> = BBBuffer::new();
+struct Clock<'a> {
+    now: &'a dyn Fn() -> Instant,
+    timeouts: Timeouts,
+}
 
 /// The main type for not-webusb.
 /// Construct this via `NotWebUsb::new` and then regularly poll it via `NotWebUsb::poll`.
 /// Check for requests via `NotWebUsb::check_pending_request`, a response must be sent via `NotWebUsb::send_response` once it is ready.
-pub struct NotWebUsb<'a, UsbBusT: UsbBus, const MAX_MESSAGE_LEN: usize = 1024> {
-    cid_next: i32,
+pub struct NotWebUsb<
+    'a,
+    UsbBusT: UsbBus,
+    const MAX_MESSAGE_LEN: usize = 1024,
+    F: OriginFilter = &'a dyn Fn([u8; 32]) -> bool,
+> {
+    fido: UsbHidClass<'a, UsbBusT, HCons<RawFido<'a, UsbBusT>, HNil>>,
+    protocol: Protocol<'a, MAX_MESSAGE_LEN, F>,
+}
+
+/// Returns the permissions that a request requires, see `NotWebUsbBuilder::required_permissions`.
+type RequiredPermissions<'a> = &'a dyn Fn(&[u8]) -> u32;
+
+/// The CTAPHID, U2F and user data state machines, shared by the usb-device and embassy-usb implementations.
+/// The owner is responsible for reading and writing the reports.
+pub(crate) struct Protocol<'a, const MAX_MESSAGE_LEN: usize, F: OriginFilter> {
+    channels: Channels,
     in_progress_transaction: Option<InProgressTransaction>,
-    tx: Producer<'a, MAXIMUM_CTAPHID_MESSAGE_X2>,
-    rx: Consumer<'a, MAXIMUM_CTAPHID_MESSAGE_X2>,
+    /// The request message of `in_progress_transaction`.
+    request_buffer: &'a mut [u8; MAXIMUM_REQUEST_MESSAGE],
+    /// The response message of `in_progress_transaction`.
+    tx: OutgoingMessage<'a>,
     raw_response: RawFidoReport,
-    fido: UsbHidClass<'a, UsbBusT, HCons<RawFido<'a, UsbBusT>, HNil>>,
-    web_origin_filter: &'a dyn Fn([u8; 32]) -> bool,
-    user_data: UserDataState<MAX_MESSAGE_LEN>,
+    /// A response that is not part of a message, e.g. an error or an init response, that is waiting to be sent.
+    /// No further requests are read until it is sent, so that it cannot be overwritten.
+    direct_response: Option<RawFidoReport>,
+    web_origin_filter: F,
+    user_data: UserDataState,
+    /// Holds the request while it is received and then the response while it is sent, as tracked by `user_data`.
+    /// A single buffer is used for both so that a response can be written over its request in place, see `NotWebUsb::send_response_with`.
+    user_data_buffer: &'a mut ArrayVec<u8, MAX_MESSAGE_LEN>,
+    /// The `application_parameter` that the key handles of the current user data transfer were sent with,
+    /// so that a client retrying the final key handle of a request can be told apart from a different website sending its own request.
+    user_data_origin: [u8; 32],
+    /// The CTAPHID channel that the most recent key handle of the current user data transfer arrived on.
+    user_data_cid: u32,
+    /// When `user_data` last progressed, used to expire stale requests and responses.
+    user_data_last_activity: Instant,
+    clock: Option<Clock<'a>>,
+    wink: Option<&'a dyn Fn()>,
+    /// The permissions that each request requires, checked against `OriginFilter::permissions`, see `NotWebUsbBuilder::required_permissions`.
+    /// `None` requires no permissions.
+    required_permissions: Option<RequiredPermissions<'a>>,
+    /// The origin allowed to send admin messages, see `NotWebUsbBuilder::admin_origin`.
+    admin_origin: Option<[u8; 32]>,
+    /// True if the response in `user_data_buffer` answers an admin message, so the admin origin may fetch the rest of it.
+    admin_response: bool,
+    identity: DeviceIdentity,
 }
 
-impl<'a, UsbBusT: UsbBus, const MAX_MESSAGE_LEN: usize> NotWebUsb<'a, UsbBusT, MAX_MESSAGE_LEN> {
+impl<'a, UsbBusT: UsbBus, const MAX_MESSAGE_LEN: usize, F: OriginFilter>
+    NotWebUsb<'a, UsbBusT, MAX_MESSAGE_LEN, F>
+{
     /// Create a new NotWebusb instance.
     ///
+    /// ## storage
+    /// The memory NotWebUsb buffers messages in, see `NotWebUsbStorage`.
+    /// Each instance needs its own storage, so multiple instances can coexist, e.g. for multiple FIDO interfaces.
+    ///
     /// ## web_origin_filter
     /// The `web_origin_filter` is used to limit the websites that can talk to your device.
-    /// The `web_origin_filter` function is called once for every request, if `web_origin_filter` returns true the request is passed on to the user, otherwise the request is dropped.
+    /// The `web_origin_filter` is called once for every request, if it returns `OriginVerdict::Allow` the request is passed on to the user, otherwise the request is dropped,
+    /// or with `OriginVerdict::Ask`, held until the filter decides, see `OriginFilter`.
+    /// A closure returning a bool is a filter, `true` allowing the request.
     /// If you don't care care about limiting the websites that can talk to your device, simply use `&|_| true` as the web_origin_filter to accept all requests, otherwise read on.
     ///
     /// The argument passed to the `web_origin_filter is the sha256 hash of the domain name.
-    /// This could be calculated by e.g. `echo -n "example.com" | sha256 | od -t u1
+    /// It can be calculated at compile time via `origin_hash!("example.com")`,
+    /// and `OriginAllowlist` implements the common case of accepting a fixed set of domains.
     /// The web application can slightly alter the domain used via the webauth [rpId field](https://developer.mozilla.org/en-US/docs/Web/API/PublicKeyCredentialRequestOptions#rpid)
     /// Browsers will only allow this field to reduce scope e.g. `example.com` -> `sub.example.com`
     /// And browsers entirely forbid use of U2F from `http://` websites, `https://`` is required.
     /// This gives us a guarantee that the website the device is talking to is the real website at the hashed domain.
     ///
-    /// Internally NotWebusb uses the `application_parameter` field of the U2F authenticate request as the argument to `web_origin_filter`.
+    /// Internally NotWebusb uses the `application_parameter` field of the U2F authenticate request as the argument to `web_origin_filter`,
+    /// or with the `ctap2` feature, the sha256 hash of the rpId of the CTAP2 getAssertion request, which is the same value.
+    ///
+    /// To give the device its own identity, e.g. an aaguid, construct it via `NotWebUsbBuilder` instead.
     pub fn new(
         fido: UsbHidClass<'a, UsbBusT, HCons<RawFido<'a, UsbBusT>, HNil>>,
-        web_origin_filter: &'a dyn Fn([u8; 32]) -> bool,
+        storage: &'a mut NotWebUsbStorage<MAX_MESSAGE_LEN>,
+        web_origin_filter: F,
     ) -> Self {
-        let (tx, rx) = OUTGOING_MESSAGE_BYTES.try_split().unwrap();
-        NotWebUsb {
-            fido,
-            tx,
-            rx,
-            // Start at CID 1, since CID 0 is reserved
-            cid_next: 1,
-            in_progress_transaction: None,
-            raw_response: RawFidoReport::default(),
-            web_origin_filter,
-            user_data: UserDataState::None,
-        }
+        NotWebUsbBuilder::new(storage, web_origin_filter).build(fido)
+    }
+
+    /// Expire requests and responses that the client has stopped sending or receiving.
+    ///
+    /// Without this, a browser tab closed or a security key dialog dismissed halfway through a request leaves NotWebUsb waiting forever,
+    /// and every later request is rejected as the device is busy.
+    /// Each expiry is reported as an error from `NotWebUsb::poll`, after which NotWebUsb is ready for new requests.
+    ///
+    /// `now` must return the current time of a monotonic clock, e.g. `&|| timer.get_counter()` with an `rp2040_hal::Timer`.
+    ///
+    /// With the `ctap2` feature the clock is also used to send keepalives while a CTAP2 request waits for `NotWebUsb::send_response`,
+    /// without which browsers give up on slow responses.
+    pub fn with_timeouts(mut self, now: &'a dyn Fn() -> Instant, timeouts: Timeouts) -> Self {
+        self.protocol.set_timeouts(now, timeouts);
+        self
+    }
+
+    /// Advertise support for CTAPHID_WINK, calling `wink` whenever a client sends one.
+    ///
+    /// Clients send a wink to identify which of several plugged in devices they are addressing,
+    /// so `wink` should start a brief visual signal, e.g. blinking an LED, and return immediately.
+    /// Without this, winks are answered with an error.
+    pub fn with_wink(mut self, wink: &'a dyn Fn()) -> Self {
+        self.protocol.wink = Some(wink);
+        self
+    }
+
+    /// The `web_origin_filter` passed to `NotWebUsb::new`.
+    pub fn origin_filter(&self) -> &F {
+        &self.protocol.web_origin_filter
+    }
+
+    /// The `web_origin_filter` passed to `NotWebUsb::new`, e.g. to update the state of a stateful `OriginFilter`.
+    pub fn origin_filter_mut(&mut self) -> &mut F {
+        &mut self.protocol.web_origin_filter
     }
 
     /// Use the return value in your call to `UsbDevice::poll`.
@@ -80,14 +276,26 @@ impl<'a, UsbBusT: UsbBus, const MAX_MESSAGE_LEN: usize> NotWebUsb<'a, UsbBusT, M
         &mut self.fido
     }
 
-    fn reset_state(&mut self) {
-        self.cid_next = 0;
-        self.in_progress_transaction = None;
-        if let Ok(read) = self.rx.split_read() {
-            read.release(MAXIMUM_CTAPHID_MESSAGE_X2);
+    /// Attempt to send the queued direct response, leaving it queued if USB would block.
+    fn send_direct_response(&mut self) -> Result<(), NotWebUsbError> {
+        if let Some(report) = self.protocol.direct_response() {
+            match self.fido.device().write_report(report) {
+                Err(UsbHidError::WouldBlock) => {
+                    debug!("Failed to send direct response as usb would block, will retry");
+                }
+                // RawFido does not deduplicate reports, but if it did, the report would already have been sent.
+                Ok(_) | Err(UsbHidError::Duplicate) => self.protocol.direct_response_sent(),
+                Err(e) => {
+                    error!(
+                        "Failed to write fido report: {:?} - resetting NotWebusb state",
+                        e
+                    );
+                    self.protocol.reset_state();
+                    return Err(NotWebUsbError::UsbError);
+                }
+            }
         }
-        self.raw_response = RawFidoReport::default();
-        self.user_data = UserDataState::None;
+        Ok(())
     }
 
     /// This must be called regularly, even when there is no in progress request or response.
@@ -95,8 +303,21 @@ impl<'a, UsbBusT: UsbBus, const MAX_MESSAGE_LEN: usize> NotWebUsb<'a, UsbBusT, M
     /// Performs CTAPHID request/response handling.
     /// If a user request is contained within the CTAPHID requests it will be stored internally such that it is returned by `NotWebUsb::check_pending_request.
     /// If a response is set by `NotWebUsb::send_response` the response will be sent within the CTAPHID responses.
+    ///
+    /// Returns an error when a USB error occurs, the client violates the protocol or, if enabled via `NotWebUsb::with_timeouts`, a stalled transfer expires.
+    /// See `NotWebUsbError` for which errors are recoverable.
     pub fn poll(&mut self) -> Result<(), NotWebUsbError> {
-        match self.fido.device().read_report() {
+        self.protocol.expire_stalled()?;
+        self.send_direct_response()?;
+
+        let mut result = Ok(());
+        let report = if self.protocol.direct_response().is_some() {
+            // Leave further requests unread until the previous direct response has been sent.
+            Err(UsbError::WouldBlock)
+        } else {
+            self.fido.device().read_report()
+        };
+        match report {
             Err(UsbError::WouldBlock) => {
                 // do nothing
             }
@@ -105,360 +326,1025 @@ impl<'a, UsbBusT: UsbBus, const MAX_MESSAGE_LEN: usize> NotWebUsb<'a, UsbBusT, M
                     "Failed to read fido report: {:?} - resetting NotWebusb state",
                     e
                 );
-                self.reset_state();
+                self.protocol.reset_state();
                 return Err(NotWebUsbError::UsbError);
             }
             Ok(report) => {
-                let request = CtapHidRequest::parse(&report);
-                info!("received ctaphid request {:?}", request);
-                let response = match request.ty {
-                    CtapHidRequestTy::Ping => Some(CtapHidResponseTy::RawReport(report)),
-                    CtapHidRequestTy::MessageInitial { length, data, ty } => {
-                        if length as usize > MAXIMUM_CTAPHID_MESSAGE {
-                            error!(
-                                "Received ctaphid request with invalid length was {} but must be less than or equal to {}",
-                                length, MAXIMUM_CTAPHID_MESSAGE
-                            );
-                            Some(CtapHidResponseTy::Error(CtapHidError::InvalidLen))
-                        } else if self.in_progress_transaction.is_some() {
-                            warn!(
-                                "New transaction was requested while a transaction is already in progress"
-                            );
-                            Some(CtapHidResponseTy::Error(CtapHidError::ChannelBusy))
-                        } else {
-                            let is_get_info_request = length == 1 && data[0] == 4;
-                            if let MessageType::Cbor = ty
-                                && !is_get_info_request
-                            {
+                result = self.protocol.receive_report(&report);
+                self.send_direct_response()?;
+            }
+        }
+
+        if let Some(report) = self.protocol.prepare_message_report()? {
+            match self.fido.device().write_report(report) {
+                Err(UsbHidError::WouldBlock) => {
+                    debug!("Failed to send response as usb would block, will retry");
+                }
+                // RawFido does not deduplicate reports, but if it did, the report would already have been sent.
+                Ok(_) | Err(UsbHidError::Duplicate) => self.protocol.message_report_sent(),
+                Err(e) => {
+                    error!(
+                        "Failed to write fido report: {:?} - resetting NotWebusb state",
+                        e
+                    );
+                    self.protocol.reset_state();
+                    return Err(NotWebUsbError::UsbError);
+                }
+            }
+        }
+        result
+    }
+
+    /// Returns the current request if there is one, along with the website and CTAPHID channel that sent it.
+    /// Calling this does not consume the request.
+    pub fn check_pending_request(&self) -> Option<Request<'_>> {
+        self.protocol.check_pending_request()
+    }
+
+    /// Sends a response to the currently pending request.
+    /// Calling this consumes the request.
+    ///
+    /// Returns `NotWebUsbError::NoPendingRequest` if there is no request to respond to, i.e. `NotWebUsb::check_pending_request` returns `None`.
+    ///
+    /// The response does not need to be sent immediately, e.g. the application may erase flash first.
+    /// Until it is sent, the browser is told to retry its request, and the response is sent as soon as a retry arrives.
+    /// The browser keeps retrying until its webauthn timeout is reached.
+    /// With the `ctap2` feature, a CTAP2 request is instead held open and answered as soon as the response is sent.
+    ///
+    /// With a large `MAX_MESSAGE_LEN`, prefer `NotWebUsb::send_response_with` or `NotWebUsb::send_response_from_slice`,
+    /// which do not need a `MAX_MESSAGE_LEN` sized array on the stack.
+    pub fn send_response(
+        &mut self,
+        message: ArrayVec<u8, MAX_MESSAGE_LEN>,
+    ) -> Result<(), NotWebUsbError> {
+        self.protocol.send_response(message)
+    }
+
+    /// Sends a response to the currently pending request by writing it directly into NotWebUsb's message buffer.
+    /// Calling this consumes the request.
+    ///
+    /// `write` is passed the `MAX_MESSAGE_LEN` byte buffer and returns the length of the response written to the start of it.
+    /// The buffer starts with the request, so a request can be transformed into its response in place:
+    /// ```ignore
+    /// if let Some(len) = not_webusb.check_pending_request().map(|request| request.payload.len()) {
+    ///     not_webusb.send_response_with(|buf| {
+    ///         buf[..len].reverse();
+    ///         len
+    ///     })?;
+    /// }
+    /// ```
+    /// A returned length larger than `MAX_MESSAGE_LEN` sends the entire buffer.
+    ///
+    /// Returns `NotWebUsbError::NoPendingRequest` if there is no request to respond to, in which case `write` is not called.
+    pub fn send_response_with(
+        &mut self,
+        write: impl FnOnce(&mut [u8]) -> usize,
+    ) -> Result<(), NotWebUsbError> {
+        self.protocol.send_response_with(write)
+    }
+
+    /// Sends a copy of `message` as the response to the currently pending request.
+    /// Calling this consumes the request.
+    ///
+    /// Returns `NotWebUsbError::NoPendingRequest` if there is no request to respond to.
+    /// Returns `NotWebUsbError::ResponseTooLong` if `message` is longer than `MAX_MESSAGE_LEN`, in which case the request remains pending.
+    pub fn send_response_from_slice(&mut self, message: &[u8]) -> Result<(), NotWebUsbError> {
+        self.protocol.send_response_from_slice(message)
+    }
+
+    /// Returns the current request decoded as a `T` via `codec`, if there is one.
+    /// Calling this does not consume the request, but note that the request is decoded again on every call.
+    ///
+    /// If the request cannot be decoded as a `T`, it is consumed by sending `RequestError::DecodeFailed` to the client and `None` is returned.
+    #[cfg(any(feature = "postcard", feature = "json"))]
+    pub fn check_pending_request_as<T: serde::de::DeserializeOwned>(
+        &mut self,
+        codec: impl Codec,
+    ) -> Option<T> {
+        self.protocol.check_pending_request_as(codec)
+    }
+
+    /// Encodes `response` via `codec` and sends it to the currently pending request.
+    /// Calling this consumes the request.
+    ///
+    /// The client receives the response as a `Result<T, RequestError>`, see `RequestError`.
+    ///
+    /// Returns `NotWebUsbError::NoPendingRequest` if there is no request to respond to.
+    /// Returns `NotWebUsbError::ResponseEncodingFailed` if the encoded response does not fit in `MAX_MESSAGE_LEN` bytes,
+    /// in which case the request remains pending, but is emptied since the response is encoded over it.
+    #[cfg(any(feature = "postcard", feature = "json"))]
+    pub fn send_response_as<T: serde::Serialize>(
+        &mut self,
+        codec: impl Codec,
+        response: &T,
+    ) -> Result<(), NotWebUsbError> {
+        self.protocol.send_response_as(codec, response)
+    }
+}
+impl<'a, const MAX_MESSAGE_LEN: usize, F: OriginFilter> Protocol<'a, MAX_MESSAGE_LEN, F> {
+    fn new(storage: &'a mut NotWebUsbStorage<MAX_MESSAGE_LEN>, web_origin_filter: F) -> Self {
+        storage.user_data.clear();
+        Protocol {
+            request_buffer: &mut storage.request,
+            tx: OutgoingMessage::new(&mut storage.response),
+            channels: Channels::new(),
+            in_progress_transaction: None,
+            raw_response: RawFidoReport::default(),
+            direct_response: None,
+            web_origin_filter,
+            user_data: UserDataState::None,
+            user_data_buffer: &mut storage.user_data,
+            user_data_origin: [0; 32],
+            user_data_cid: 0,
+            user_data_last_activity: Instant::from_ticks(0),
+            clock: None,
+            wink: None,
+            required_permissions: None,
+            admin_origin: None,
+            admin_response: false,
+            identity: DeviceIdentity::new(),
+        }
+    }
+
+    fn set_timeouts(&mut self, now: &'a dyn Fn() -> Instant, timeouts: Timeouts) {
+        self.user_data_last_activity = now();
+        self.clock = Some(Clock { now, timeouts });
+    }
+
+    fn reset_state(&mut self) {
+        self.channels.clear();
+        self.in_progress_transaction = None;
+        self.discard_outgoing_message();
+        self.raw_response = RawFidoReport::default();
+        self.direct_response = None;
+        self.user_data = UserDataState::None;
+    }
+
+    fn discard_outgoing_message(&mut self) {
+        self.tx.discard();
+    }
+
+    fn now(&self) -> Instant {
+        match &self.clock {
+            Some(clock) => (clock.now)(),
+            None => Instant::from_ticks(0),
+        }
+    }
+
+    fn queue_direct_response(&mut self, cid: u32, response: CtapHidResponseTy) {
+        let mut report = RawFidoReport::default();
+        CtapHidResponse {
+            cid,
+            ty: response,
+            continuation_state: ContinuationState::Initial,
+        }
+        .encode(&mut report);
+        info!("queueing direct raw response {}", report.packet);
+        self.direct_response = Some(report);
+    }
+
+    /// The queued direct response, which must be sent before any further requests are passed to `Protocol::receive_report`.
+    fn direct_response(&self) -> Option<&RawFidoReport> {
+        self.direct_response.as_ref()
+    }
+
+    fn direct_response_sent(&mut self) {
+        self.direct_response = None;
+    }
+
+    /// Pass the data of a U2F or CBOR message packet to the in progress transaction.
+    /// If this completes a not-webusb key handle, it is passed on to the user data state.
+    ///
+    /// On `NotWebUsbError::InternalError` the transaction has been aborted and the caller must send the client an error.
+    fn receive_message_data(&mut self, data: &[u8], now: Instant) -> Result<(), NotWebUsbError> {
+        let Some(transaction) = &mut self.in_progress_transaction else {
+            return Ok(());
+        };
+        let result = transaction
+            .receive_user_request(
+                data,
+                self.request_buffer,
+                &mut self.tx,
+                &mut AdminBypass {
+                    admin_origin: self.admin_origin,
+                    admin_response: self.admin_response,
+                    filter: &mut self.web_origin_filter,
+                },
+                &self.identity,
+            )
+            .and_then(|request| match request {
+                Received::UserKeyHandle(request) => {
+                    transaction.carries_user_data = true;
+                    self.user_data_last_activity = now;
+                    let message_type = transaction.message_type;
+                    let was_received = matches!(self.user_data, UserDataState::ReceivedRequest);
+                    let mut reply = self.user_data.receive_request(
+                        &request,
+                        self.user_data_buffer,
+                        &mut self.user_data_origin,
+                    );
+                    if reply.is_ok() {
+                        self.user_data_cid = transaction.cid;
+                    }
+                    // A newly received request is checked here, before it can reach `check_pending_request`.
+                    // Admin messages are answered right away, and requests lacking permissions are rejected.
+                    if let Ok(UserDataReply::NotReady) = reply
+                        && !was_received
+                    {
+                        self.admin_response = admin::handle_request(
+                            self.admin_origin,
+                            &mut self.user_data,
+                            self.user_data_buffer,
+                            self.user_data_origin,
+                            &mut self.web_origin_filter,
+                        );
+                        if self.admin_response {
+                            reply = Ok(UserDataReply::Respond);
+                        } else if let Some(required_permissions) = self.required_permissions {
+                            let required = required_permissions(self.user_data_buffer);
+                            let granted = self.web_origin_filter.permissions(self.user_data_origin);
+                            if granted & required != required {
                                 warn!(
-                                    "Client sent non GetInfo CBOR request but we only support GetInfo requests"
+                                    "user request requires permissions {:x} but its origin is granted {:x}",
+                                    required, granted
                                 );
-                                Some(CtapHidResponseTy::Error(CtapHidError::InvalidCommand))
-                            } else {
-                                self.in_progress_transaction =
-                                    Some(InProgressTransaction::new(ty, request.cid, length));
-                                if let Some(in_progress_message) = &mut self.in_progress_transaction
-                                {
-                                    if let Some(request) = in_progress_message.receive_user_request(
-                                        &data,
-                                        &mut self.tx,
-                                        &self.web_origin_filter,
-                                    ) {
-                                        self.user_data.receive_request(
-                                            request,
-                                            in_progress_message,
-                                            &mut self.tx,
-                                        );
-                                    }
-                                }
-                                None
+                                self.user_data = UserDataState::None;
+                                reply = Ok(UserDataReply::Forbidden);
                             }
                         }
                     }
-                    CtapHidRequestTy::MessageContinuation { data, sequence } => {
-                        if let Some(in_progress_transaction) = &mut self.in_progress_transaction {
-                            if in_progress_transaction.request_sequence != sequence {
-                                error!(
-                                    "Received ctaphid request with invalid sequence number was {} expected {}",
-                                    sequence, in_progress_transaction.request_sequence
-                                );
-                                Some(CtapHidResponseTy::Error(CtapHidError::InvalidSeq))
-                            } else {
-                                in_progress_transaction.request_sequence += 1;
-
-                                if in_progress_transaction.cid == request.cid {
-                                    if let Some(request) = in_progress_transaction
-                                        .receive_user_request(
-                                            &data,
-                                            &mut self.tx,
-                                            &self.web_origin_filter,
-                                        )
-                                    {
-                                        self.user_data.receive_request(
-                                            request,
-                                            in_progress_transaction,
-                                            &mut self.tx,
-                                        );
-                                    }
-                                } else {
-                                    // TODO: error or maybe just drop it
-                                }
-                                None
-                            }
-                        } else {
-                            warn!("Continuation packet with no Initial packet, ignoring");
-                            None
+                    match reply {
+                        // CTAP2 has no way to ask the client to retry, so the request is held open until the response is ready.
+                        #[cfg(feature = "ctap2")]
+                        Ok(UserDataReply::NotReady)
+                            if matches!(message_type, MessageType::Cbor) =>
+                        {
+                            transaction.awaiting_user_response = true;
+                            Ok(())
+                        }
+                        Ok(reply) => self.user_data.send_reply(
+                            reply,
+                            message_type,
+                            &request.application_parameter,
+                            self.user_data_buffer,
+                            &mut self.tx,
+                        ),
+                        Err(error) => {
+                            send_user_rejection(message_type, &mut self.tx)?;
+                            Err(error)
                         }
                     }
-                    CtapHidRequestTy::Init { nonce8 } => {
-                        self.cid_next += 1;
-                        Some(CtapHidResponseTy::Init(InitResponse {
-                            nonce_8_bytes: nonce8,
-                            channel_id: self.cid_next.to_be_bytes(),
-                            protocol_version: 2,
-                            device_version_major: 0,
-                            device_version_minor: 0,
-                            device_version_build: 0,
-                            capabilities: 0,
-                        }))
-                    }
+                }
+                // Received again by `Protocol::progress_awaiting_transaction` until the filter decides.
+                #[cfg(feature = "ctap2")]
+                Received::AwaitingVerdict => {
+                    transaction.awaiting_origin_verdict = true;
+                    Ok(())
+                }
+                Received::Answered => Ok(()),
+            });
+        if let Err(NotWebUsbError::InternalError) = result {
+            self.abort_transaction();
+        }
+        result
+    }
 
-                    CtapHidRequestTy::Cancel => {
-                        let will_cancel = self.in_progress_transaction.is_some();
-                        self.in_progress_transaction = None;
+    /// Drop the in progress transaction along with any user data transfer that it was a part of.
+    fn abort_transaction(&mut self) {
+        let carries_user_data = self
+            .in_progress_transaction
+            .take()
+            .is_some_and(|transaction| transaction.carries_user_data);
+        self.discard_outgoing_message();
+        if carries_user_data && !matches!(self.user_data, UserDataState::ReceivedRequest) {
+            self.user_data = UserDataState::None;
+        }
+    }
 
-                        if will_cancel {
-                            Some(CtapHidResponseTy::Error(CtapHidError::KeepAliveCancel))
-                        } else {
-                            None
-                        }
+    /// Abort the in progress transaction or user data transfer if the client has stalled for longer than the configured `Timeouts`.
+    fn expire_stalled(&mut self) -> Result<(), NotWebUsbError> {
+        let Some(clock) = &self.clock else {
+            return Ok(());
+        };
+        let now = (clock.now)();
+        let timeouts = clock.timeouts;
+        let expired = |last_activity: Instant, timeout: Duration| {
+            now.checked_duration_since(last_activity)
+                .is_some_and(|elapsed| elapsed > timeout)
+        };
+
+        if let Some(transaction) = &self.in_progress_transaction
+            && transaction.is_waiting_on_client()
+            && expired(transaction.last_activity, timeouts.transaction)
+        {
+            warn!("CTAPHID transaction timed out, aborting it");
+            let cid = transaction.cid;
+            let is_receiving_request = transaction.is_receiving_request();
+            self.in_progress_transaction = None;
+            self.discard_outgoing_message();
+
+            // There is no point telling the client if it is the one that stopped collecting our packets.
+            if is_receiving_request {
+                self.queue_direct_response(
+                    cid,
+                    CtapHidResponseTy::Error(CtapHidError::MessageTimeout),
+                );
+            }
+            return Err(NotWebUsbError::TransactionTimeout);
+        }
+
+        let error = match self.user_data {
+            UserDataState::ReceivingRequest => NotWebUsbError::RequestTimeout,
+            UserDataState::SendingResponse { .. } => NotWebUsbError::ResponseTimeout,
+            UserDataState::ReceivedRequest | UserDataState::None => return Ok(()),
+        };
+        if expired(self.user_data_last_activity, timeouts.user_data) {
+            warn!("User data transfer timed out, discarding it");
+            self.user_data = UserDataState::None;
+            return Err(error);
+        }
+        Ok(())
+    }
+
+    /// Handle a report received from the client, queueing a direct response if required.
+    fn receive_report(&mut self, report: &RawFidoReport) -> Result<(), NotWebUsbError> {
+        let now = self.now();
+        let mut result = Ok(());
+        let request = CtapHidRequest::parse(report);
+        info!("received ctaphid request {:?}", request);
+        let response = match request.ty {
+            CtapHidRequestTy::Init { nonce8 } => self.receive_init(request.cid, nonce8),
+            _ if !self.channels.is_allocated(request.cid) => {
+                warn!(
+                    "Received ctaphid request on channel {} which was not allocated via CTAPHID_INIT",
+                    request.cid
+                );
+                Some(CtapHidResponseTy::Error(CtapHidError::InvalidChannel))
+            }
+            CtapHidRequestTy::Ping => Some(CtapHidResponseTy::RawReport(*report)),
+            CtapHidRequestTy::Wink => match self.wink {
+                Some(wink) => {
+                    info!("Received CTAPHID_WINK");
+                    wink();
+                    Some(CtapHidResponseTy::Wink)
+                }
+                None => {
+                    warn!("Received CTAPHID_WINK but no wink callback is configured");
+                    Some(CtapHidResponseTy::Error(CtapHidError::InvalidCommand))
+                }
+            },
+            CtapHidRequestTy::MessageInitial { length, data, ty } => {
+                if length as usize > MAXIMUM_REQUEST_MESSAGE {
+                    error!(
+                        "Received ctaphid request with invalid length was {} but must be less than or equal to {}",
+                        length, MAXIMUM_REQUEST_MESSAGE
+                    );
+                    Some(CtapHidResponseTy::Error(CtapHidError::InvalidLen))
+                } else if let Some(transaction) = &self.in_progress_transaction {
+                    warn!(
+                        "New transaction was requested on channel {} while a transaction is already in progress on channel {}",
+                        request.cid, transaction.cid
+                    );
+                    Some(CtapHidResponseTy::Error(CtapHidError::ChannelBusy))
+                } else {
+                    self.in_progress_transaction =
+                        Some(InProgressTransaction::new(ty, request.cid, length, now));
+                    self.receive_message_data_or_error(&data, now, &mut result)
+                }
+            }
+            CtapHidRequestTy::MessageContinuation { data, sequence } => {
+                match &mut self.in_progress_transaction {
+                    Some(in_progress_transaction) if in_progress_transaction.cid != request.cid => {
+                        // The initial packet on this channel was already answered with ChannelBusy.
+                        warn!(
+                            "Continuation packet received on channel {} while channel {} is busy, ignoring",
+                            request.cid, in_progress_transaction.cid
+                        );
+                        None
                     }
-                    CtapHidRequestTy::Unknown { cmd } => {
-                        warn!("Unknown CTAPHID command {}", cmd);
-                        Some(CtapHidResponseTy::Error(CtapHidError::InvalidCommand))
+                    Some(in_progress_transaction)
+                        if !in_progress_transaction.is_receiving_request() =>
+                    {
+                        warn!(
+                            "Continuation packet received after the request was complete, ignoring"
+                        );
+                        None
                     }
-                };
-
-                if let Some(response) = response {
-                    CtapHidResponse {
-                        cid: request.cid,
-                        ty: response,
-                        continuation_state: ContinuationState::Initial,
+                    Some(in_progress_transaction)
+                        if in_progress_transaction.request_sequence != sequence =>
+                    {
+                        error!(
+                            "Received ctaphid request with invalid sequence number was {} expected {}",
+                            sequence, in_progress_transaction.request_sequence
+                        );
+                        Some(CtapHidResponseTy::Error(CtapHidError::InvalidSeq))
                     }
-                    .encode(&mut self.raw_response);
-                    info!("sending direct raw response {}", self.raw_response.packet);
-                    match self.fido.device().write_report(&self.raw_response) {
-                        Err(UsbHidError::WouldBlock) => todo!("error handling"),
-                        Err(UsbHidError::Duplicate) => todo!("What does this mean?"),
-                        Ok(_) => {}
-                        Err(e) => {
-                            error!(
-                                "Failed to write fido report: {:?} - resetting NotWebusb state",
-                                e
-                            );
-                            self.reset_state();
-                            return Err(NotWebUsbError::UsbError);
-                        }
+                    Some(in_progress_transaction) => {
+                        in_progress_transaction.request_sequence += 1;
+                        in_progress_transaction.last_activity = now;
+                        self.receive_message_data_or_error(&data, now, &mut result)
+                    }
+                    None => {
+                        warn!("Continuation packet with no Initial packet, ignoring");
+                        None
                     }
                 }
             }
+            CtapHidRequestTy::Cancel => {
+                let will_cancel = self
+                    .in_progress_transaction
+                    .as_ref()
+                    .is_some_and(|transaction| transaction.cid == request.cid);
+                if will_cancel {
+                    self.in_progress_transaction = None;
+                    self.discard_outgoing_message();
+                    Some(CtapHidResponseTy::Error(CtapHidError::KeepAliveCancel))
+                } else {
+                    None
+                }
+            }
+            CtapHidRequestTy::Unknown { cmd } => {
+                warn!("Unknown CTAPHID command {}", cmd);
+                Some(CtapHidResponseTy::Error(CtapHidError::InvalidCommand))
+            }
+        };
+
+        if let Some(response) = response {
+            self.queue_direct_response(request.cid, response);
         }
+        result
+    }
 
-        if let Some(in_progress_transaction) = &mut self.in_progress_transaction {
-            if let UserDataState::SendingResponse {
-                data,
-                bytes_sent,
-                pending_request,
-            } = &mut self.user_data
+    /// Handle a CTAPHID_INIT request, returning the response to send.
+    ///
+    /// On the broadcast channel a new channel is allocated.
+    /// On an allocated channel the channel is resynchronized, aborting any transaction in progress on it.
+    fn receive_init(&mut self, cid: u32, nonce8: [u8; 8]) -> Option<CtapHidResponseTy<'static>> {
+        let channel_id = if cid == BROADCAST_CID {
+            let busy_cid = self
+                .in_progress_transaction
+                .as_ref()
+                .map(|transaction| transaction.cid);
+            let channel_id = self.channels.allocate(busy_cid);
+            info!("Allocated CTAPHID channel {}", channel_id);
+            channel_id
+        } else if self.channels.is_allocated(cid) {
+            if self
+                .in_progress_transaction
+                .as_ref()
+                .is_some_and(|transaction| transaction.cid == cid)
             {
-                if *pending_request {
-                    in_progress_transaction.send_user_response(data, bytes_sent, &mut self.tx);
-                    *pending_request = false;
-                }
+                warn!(
+                    "CTAPHID_INIT received on channel {} during a transaction, aborting it",
+                    cid
+                );
+                self.abort_transaction();
+            }
+            cid
+        } else {
+            warn!(
+                "CTAPHID_INIT received on channel {} which was not allocated",
+                cid
+            );
+            return Some(CtapHidResponseTy::Error(CtapHidError::InvalidChannel));
+        };
+        Some(CtapHidResponseTy::Init(InitResponse {
+            nonce_8_bytes: nonce8,
+            channel_id: channel_id.to_be_bytes(),
+            protocol_version: 2,
+            device_version_major: self.identity.device_version[0],
+            device_version_minor: self.identity.device_version[1],
+            device_version_build: self.identity.device_version[2],
+            capabilities: if self.wink.is_some() {
+                CAPABILITY_WINK
+            } else {
+                0
+            } | if self.identity.ctap2_enabled() {
+                CAPABILITY_CBOR
+            } else {
+                0
+            },
+        }))
+    }
 
-                if *bytes_sent >= data.len() as u32 {
-                    self.user_data = UserDataState::None;
-                }
+    /// Progress a CTAP2 request held open by `InProgressTransaction::awaiting_origin_verdict` or `InProgressTransaction::awaiting_user_response`.
+    ///
+    /// The `OriginFilter` is asked again until it decides on the origin of the request.
+    /// Once the application has sent its response, the first part of it is sent,
+    /// until then a keepalive is sent every `KEEPALIVE_INTERVAL`, as long as a clock is configured.
+    #[cfg(feature = "ctap2")]
+    fn progress_awaiting_transaction(&mut self) -> Result<(), NotWebUsbError> {
+        let now = self.now();
+        let Some(transaction) = &mut self.in_progress_transaction else {
+            return Ok(());
+        };
+        if transaction.response_ready_to_send {
+            return Ok(());
+        }
+        let cid = transaction.cid;
+        if transaction.awaiting_origin_verdict {
+            // Receiving the request again sets `awaiting_origin_verdict` again if the filter still cannot decide.
+            transaction.awaiting_origin_verdict = false;
+            let result = self.receive_message_data(&[], now);
+            if let Err(NotWebUsbError::InternalError) = result {
+                self.queue_direct_response(cid, CtapHidResponseTy::Error(CtapHidError::Other));
+            }
+            if self
+                .in_progress_transaction
+                .as_ref()
+                .is_some_and(|transaction| transaction.awaiting_origin_verdict)
+            {
+                self.send_keepalive_when_due(now, KeepAliveStatus::UserPresenceNeeded);
+            }
+            return result;
+        }
+        if !transaction.awaiting_user_response {
+            return Ok(());
+        }
+        let result = match self.user_data {
+            UserDataState::ReceivedRequest => {
+                self.send_keepalive_when_due(now, KeepAliveStatus::Processing);
+                return Ok(());
+            }
+            UserDataState::SendingResponse { .. } => {
+                info!("responding to the held open CTAP2 request");
+                transaction.awaiting_user_response = false;
+                self.user_data.send_reply(
+                    UserDataReply::Respond,
+                    MessageType::Cbor,
+                    &self.user_data_origin,
+                    self.user_data_buffer,
+                    &mut self.tx,
+                )
+            }
+            UserDataState::ReceivingRequest | UserDataState::None => {
+                warn!("the request of the held open CTAP2 request was discarded");
+                transaction.awaiting_user_response = false;
+                ctap2::send_error_response(&mut self.tx, ctap2::Status::Other)
             }
+        };
+        if let Err(NotWebUsbError::InternalError) = result {
+            self.abort_transaction();
+            self.queue_direct_response(cid, CtapHidResponseTy::Error(CtapHidError::Other));
+        }
+        result
+    }
 
+    /// Returns true while a CTAP2 request is held open, which `Protocol::progress_awaiting_transaction` must progress
+    /// even when the client sends nothing.
+    #[cfg(all(feature = "embassy", feature = "ctap2"))]
+    fn is_awaiting(&self) -> bool {
+        self.in_progress_transaction
+            .as_ref()
+            .is_some_and(|transaction| {
+                transaction.awaiting_origin_verdict || transaction.awaiting_user_response
+            })
+    }
+
+    /// Always false without the `ctap2` feature, since U2F requests are never held open.
+    #[cfg(all(feature = "embassy", not(feature = "ctap2")))]
+    fn is_awaiting(&self) -> bool {
+        false
+    }
+
+    /// Prepare a keepalive for the held open CTAP2 request, if `KEEPALIVE_INTERVAL` has passed since its last packet.
+    #[cfg(feature = "ctap2")]
+    fn send_keepalive_when_due(&mut self, now: Instant, status: KeepAliveStatus) {
+        if let Some(transaction) = &mut self.in_progress_transaction
+            && now
+                .checked_duration_since(transaction.last_activity)
+                .is_some_and(|elapsed| elapsed >= KEEPALIVE_INTERVAL)
+        {
+            CtapHidResponse {
+                cid: transaction.cid,
+                ty: CtapHidResponseTy::KeepAlive(status),
+                continuation_state: ContinuationState::Initial,
+            }
+            .encode(&mut self.raw_response);
+            transaction.response_ready_to_send = true;
+        }
+    }
+
+    /// Prepare the next packet of the in progress message response, returning it if there is one ready to be sent.
+    /// Once it has been sent `Protocol::message_report_sent` must be called.
+    fn prepare_message_report(&mut self) -> Result<Option<&RawFidoReport>, NotWebUsbError> {
+        #[cfg(feature = "ctap2")]
+        self.progress_awaiting_transaction()?;
+        if let Some(in_progress_transaction) = &mut self.in_progress_transaction {
             // USB may have been blocked, leading to a response already being created but left unsent.
             if !in_progress_transaction.response_ready_to_send {
-                match self.rx.read() {
-                    Ok(granted) => {
-                        let remaining_u2f_size = granted.len();
-                        let packet_size = if let ContinuationState::Initial =
-                            in_progress_transaction.response_continuation_state
-                        {
-                            remaining_u2f_size.min(57)
-                        } else {
-                            remaining_u2f_size.min(59)
-                        };
-                        in_progress_transaction.response_final_packet_is_ready_to_send =
-                            remaining_u2f_size == packet_size;
-                        CtapHidResponse {
-                            cid: in_progress_transaction.cid,
-                            ty: CtapHidResponseTy::Message {
-                                // only used in the initial message where it is treated as the full u2f/cbor size.
-                                length: remaining_u2f_size as u16,
-                                data: &granted[..packet_size],
-                                ty: in_progress_transaction.message_type,
-                            },
-                            continuation_state: in_progress_transaction.response_continuation_state,
-                        }
-                        .encode(&mut self.raw_response);
-                        info!(
-                            "one ctaphid response packet has been prepared {}",
-                            &self.raw_response.packet
-                        );
+                let unsent = self.tx.unsent();
+                // This is expected to be empty when the response has not been created yet.
+                // A final packet that is completely filled is still marked as final, since the check below compares against
+                // the remaining length rather than looking for a partially filled packet, so the transaction always ends.
+                if !unsent.is_empty() {
+                    let remaining_u2f_size = unsent.len();
+                    let packet_size = if let ContinuationState::Initial =
+                        in_progress_transaction.response_continuation_state
+                    {
+                        remaining_u2f_size.min(57)
+                    } else {
+                        remaining_u2f_size.min(59)
+                    };
+                    in_progress_transaction.response_final_packet_is_ready_to_send =
+                        remaining_u2f_size == packet_size;
+                    CtapHidResponse {
+                        cid: in_progress_transaction.cid,
+                        ty: CtapHidResponseTy::Message {
+                            // only used in the initial message where it is treated as the full u2f/cbor size.
+                            length: remaining_u2f_size as u16,
+                            data: &unsent[..packet_size],
+                            ty: in_progress_transaction.message_type,
+                        },
+                        continuation_state: in_progress_transaction.response_continuation_state,
+                    }
+                    .encode(&mut self.raw_response);
+                    info!(
+                        "one ctaphid response packet has been prepared {}",
+                        &self.raw_response.packet
+                    );
 
-                        // step sequence state
-                        match &mut in_progress_transaction.response_continuation_state {
-                            ContinuationState::Continuation { sequence } => {
-                                *sequence += 1;
-                            }
-                            ContinuationState::Initial => {
-                                in_progress_transaction.response_continuation_state =
-                                    ContinuationState::Continuation { sequence: 0 }
-                            }
+                    // step sequence state
+                    match &mut in_progress_transaction.response_continuation_state {
+                        ContinuationState::Continuation { sequence } => {
+                            *sequence += 1;
+                        }
+                        ContinuationState::Initial => {
+                            in_progress_transaction.response_continuation_state =
+                                ContinuationState::Continuation { sequence: 0 }
                         }
-                        in_progress_transaction.response_ready_to_send = true;
-
-                        granted.release(packet_size);
                     }
-                    Err(bbqueue::Error::InsufficientSize) => {
-                        // This is expected when there are no bytes to read.
+                    in_progress_transaction.response_ready_to_send = true;
 
-                        // TODO: This logic is a bit sus, could it lead to deadlock if we completely fill the final packet?
-                    }
-                    #[cfg(feature = "defmt")]
-                    Err(error) => panic!("Unexpected bbq error {}", error),
-                    #[cfg(not(feature = "defmt"))]
-                    Err(_) => panic!("Unexpected bbq error"),
+                    self.tx.mark_sent(packet_size);
                 }
             }
 
             if in_progress_transaction.response_ready_to_send {
-                match self.fido.device().write_report(&self.raw_response) {
-                    Err(UsbHidError::WouldBlock) => {
-                        debug!("Failed to send response as usb would block, will retry");
-                    }
-                    Err(UsbHidError::Duplicate) => todo!("What does this mean?"),
-                    Ok(_) => {
-                        in_progress_transaction.response_ready_to_send = false;
-
-                        if in_progress_transaction.response_final_packet_is_ready_to_send {
-                            // finished!!!
-                            info!("all packets for the in progress message have been sent");
-                            self.in_progress_transaction = None;
-                        } else {
-                            info!("one ctaphid packet was sent, but more remain to be sent");
-                        }
-                    }
-                    Err(e) => {
-                        panic!("Failed to write fido report: {:?}", e)
-                    }
-                }
+                return Ok(Some(&self.raw_response));
             }
         }
-        Ok(())
+        Ok(None)
     }
 
-    /// Returns the current request if there is one.
-    /// Calling this does not consume the request.
-    pub fn check_pending_request(&self) -> Option<&[u8]> {
-        if let UserDataState::ReceivedRequest(request) = &self.user_data {
-            Some(request.as_slice())
+    fn message_report_sent(&mut self) {
+        let now = self.now();
+        if let Some(in_progress_transaction) = &mut self.in_progress_transaction {
+            in_progress_transaction.response_ready_to_send = false;
+            in_progress_transaction.last_activity = now;
+
+            if in_progress_transaction.response_final_packet_is_ready_to_send {
+                // finished!!!
+                info!("all packets for the in progress message have been sent");
+                self.in_progress_transaction = None;
+            } else {
+                info!("one ctaphid packet was sent, but more remain to be sent");
+            }
+        }
+    }
+
+    /// Calls `NotWebUsb::receive_message_data`, returning the CTAPHID response to send if the transaction had to be aborted.
+    /// Any error is stored in `result` to be returned once `NotWebUsb::poll` has finished.
+    fn receive_message_data_or_error(
+        &mut self,
+        data: &[u8],
+        now: Instant,
+        result: &mut Result<(), NotWebUsbError>,
+    ) -> Option<CtapHidResponseTy<'static>> {
+        match self.receive_message_data(data, now) {
+            Ok(()) => None,
+            Err(NotWebUsbError::InternalError) => {
+                *result = Err(NotWebUsbError::InternalError);
+                Some(CtapHidResponseTy::Error(CtapHidError::Other))
+            }
+            Err(error) => {
+                *result = Err(error);
+                None
+            }
+        }
+    }
+
+    fn check_pending_request(&self) -> Option<Request<'_>> {
+        if let UserDataState::ReceivedRequest = self.user_data {
+            Some(Request {
+                payload: self.user_data_buffer.as_slice(),
+                origin_hash: self.user_data_origin,
+                cid: self.user_data_cid,
+            })
         } else {
             None
         }
     }
 
-    /// Sends a response to the currently pending request.
-    /// Calling this consumes the request.
-    pub fn send_response(&mut self, message: ArrayVec<u8, MAX_MESSAGE_LEN>) {
-        if !matches!(self.user_data, UserDataState::ReceivedRequest(_)) {
-            panic!("Cannot call NotWebusb::send_response until a request has been received.");
+    fn send_response(
+        &mut self,
+        message: ArrayVec<u8, MAX_MESSAGE_LEN>,
+    ) -> Result<(), NotWebUsbError> {
+        self.send_response_from_slice(&message)
+    }
+
+    fn send_response_from_slice(&mut self, message: &[u8]) -> Result<(), NotWebUsbError> {
+        if message.len() > MAX_MESSAGE_LEN {
+            warn!("user response is longer than MAX_MESSAGE_LEN");
+            return Err(NotWebUsbError::ResponseTooLong);
         }
-        self.user_data = UserDataState::SendingResponse {
-            data: message,
-            bytes_sent: 0,
-            pending_request: true,
+        self.send_response_with(|buf| {
+            buf[..message.len()].copy_from_slice(message);
+            message.len()
+        })
+    }
+
+    fn send_response_with(
+        &mut self,
+        write: impl FnOnce(&mut [u8]) -> usize,
+    ) -> Result<(), NotWebUsbError> {
+        if !matches!(self.user_data, UserDataState::ReceivedRequest) {
+            warn!("NotWebusb::send_response was called without a pending request");
+            return Err(NotWebUsbError::NoPendingRequest);
+        }
+        let buffer = &mut self.user_data_buffer;
+        buffer.extend(core::iter::repeat_n(0, buffer.remaining_capacity()));
+        let len = write(buffer);
+        buffer.truncate(len);
+        // The response is sent once the client retries the final key handle of the request.
+        self.user_data = UserDataState::SendingResponse { bytes_sent: 0 };
+        self.user_data_last_activity = self.now();
+        Ok(())
+    }
+
+    #[cfg(any(feature = "postcard", feature = "json"))]
+    fn check_pending_request_as<T: serde::de::DeserializeOwned>(
+        &mut self,
+        codec: impl Codec,
+    ) -> Option<T> {
+        let request = codec.decode(self.check_pending_request()?.payload);
+        if request.is_none() {
+            warn!("failed to decode user request, responding with RequestError::DecodeFailed");
+            self.send_error_response(&codec, RequestError::DecodeFailed)
+                .ok();
+        }
+        request
+    }
+
+    #[cfg(any(feature = "postcard", feature = "json"))]
+    fn send_response_as<T: serde::Serialize>(
+        &mut self,
+        codec: impl Codec,
+        response: &T,
+    ) -> Result<(), NotWebUsbError> {
+        self.send_encoded_response(&codec, &Ok::<_, RequestError>(response))
+    }
+
+    #[cfg(any(feature = "postcard", feature = "json"))]
+    fn send_error_response(
+        &mut self,
+        codec: &impl Codec,
+        error: RequestError,
+    ) -> Result<(), NotWebUsbError> {
+        self.send_encoded_response(codec, &Err::<(), _>(error))
+    }
+
+    /// The response is encoded directly into `user_data_buffer`, overwriting the request.
+    /// If encoding fails, the request remains pending but with its contents cleared.
+    #[cfg(any(feature = "postcard", feature = "json"))]
+    fn send_encoded_response<T: serde::Serialize>(
+        &mut self,
+        codec: &impl Codec,
+        response: &Result<T, RequestError>,
+    ) -> Result<(), NotWebUsbError> {
+        if !matches!(self.user_data, UserDataState::ReceivedRequest) {
+            warn!("NotWebusb::send_response_as was called without a pending request");
+            return Err(NotWebUsbError::NoPendingRequest);
         }
+        let buffer = &mut self.user_data_buffer;
+        buffer.extend(core::iter::repeat_n(0, buffer.remaining_capacity()));
+        let Some(len) = codec.encode(response, buffer) else {
+            warn!("encoded user response is longer than MAX_MESSAGE_LEN");
+            buffer.clear();
+            return Err(NotWebUsbError::ResponseEncodingFailed);
+        };
+        self.send_response_with(|_| len)
     }
 }
 
 /// Represents the state of any in progress user requests or responses.
 /// This is the highest level state and does not hold any fido/ctap/u2f state.
-enum UserDataState<const MAX_MESSAGE_LEN: usize> {
-    /// The request has been partially received from the client.
+enum UserDataState {
+    /// The request has been partially received from the client into `Protocol::user_data_buffer`.
     /// The device has not looked at any of it yet.
-    ReceivingRequest(ArrayVec<u8, MAX_MESSAGE_LEN>),
-    /// The entire request has been received from the client.
+    ReceivingRequest,
+    /// The entire request has been received from the client into `Protocol::user_data_buffer`.
     /// The device may or may not have looked at it yet.
-    ReceivedRequest(ArrayVec<u8, MAX_MESSAGE_LEN>),
-    /// The entire response has been sent by the device.
+    /// Meanwhile the client is told to retry the final key handle of the request via `MessageResponseError::ConditionsNotSatisfied`,
+    /// or a CTAP2 request is held open, see `InProgressTransaction::awaiting_user_response`.
+    ReceivedRequest,
+    /// The entire response has been written to `Protocol::user_data_buffer` by the device.
     /// The client may have partially received it but has not fully received it.
-    SendingResponse {
-        data: ArrayVec<u8, MAX_MESSAGE_LEN>,
-        bytes_sent: u32,
-        pending_request: bool,
-    },
+    /// The first part of the response is sent when the client retries the final key handle of the request or to the held open CTAP2 request,
+    /// and every later part when the client asks for it via `RequestHeader::NeedMoreResponseData`.
+    SendingResponse { bytes_sent: u32 },
     /// There are no in progress requests or responses.
     None,
 }
 
-impl<'a, const MAX_MESSAGE_LEN: usize> UserDataState<MAX_MESSAGE_LEN> {
-    fn receive_request(
+/// How a not-webusb key handle is answered, independent of whether it arrived via U2F or CTAP2.
+enum UserDataReply {
+    /// The key handle was stored, the reply carries no response data.
+    Acknowledge,
+    /// The request has been received but the application has not sent a response yet.
+    NotReady,
+    /// The reply carries the next part of the response.
+    Respond,
+    /// The request was discarded because its origin lacks the permissions it requires.
+    Forbidden,
+}
+
+impl UserDataState {
+    /// Updates the state for a key handle received from the client, returning how it must be answered.
+    ///
+    /// Returns `NotWebUsbError::ProtocolViolation` if the key handle violates the not-webusb protocol, see `send_user_rejection`.
+    fn receive_request<const MAX_MESSAGE_LEN: usize>(
         &mut self,
-        request: ArrayVec<u8, 255>,
-        in_progress_message: &mut InProgressTransaction,
-        tx: &mut Producer<'a, MAXIMUM_CTAPHID_MESSAGE_X2>,
-    ) {
+        request: &UserKeyHandle,
+        buffer: &mut ArrayVec<u8, MAX_MESSAGE_LEN>,
+        origin: &mut [u8; 32],
+    ) -> Result<UserDataReply, NotWebUsbError> {
+        let key_handle = request.key_handle;
+        let Some(header) = key_handle.first().copied().and_then(RequestHeader::parse) else {
+            warn!("unknown user request header");
+            return self.protocol_violation();
+        };
+        let data = &key_handle[1..];
+        let is_retry = matches!(header, RequestHeader::FinalRequest)
+            && *origin == request.application_parameter;
         match self {
-            UserDataState::ReceivingRequest(partial_request) => {
-                let header = RequestHeader::parse(request[0]);
-                partial_request.extend(request.as_slice()[1..].iter().copied());
-                match header {
-                    Some(RequestHeader::FinalRequest) => {
-                        info!("continuing user request - final request packet");
-                        *self = UserDataState::ReceivedRequest({
-                            let mut v = ArrayVec::new();
-                            v.extend(partial_request.as_slice().iter().copied());
-                            v
-                        });
+            UserDataState::ReceivingRequest => match header {
+                RequestHeader::FinalRequest | RequestHeader::InitialRequest => {
+                    if buffer.try_extend_from_slice(data).is_err() {
+                        warn!("user request is longer than MAX_MESSAGE_LEN");
+                        return self.protocol_violation();
                     }
-                    Some(RequestHeader::InitialRequest) => {
+                    if let RequestHeader::FinalRequest = header {
+                        info!("continuing user request - final request packet");
+                        *self = UserDataState::ReceivedRequest;
+                        Ok(UserDataReply::NotReady)
+                    } else {
                         info!("continuing user request - initial request packet");
-                        in_progress_message.send_user_response(&[], &mut 0, tx);
-                    }
-                    Some(RequestHeader::NeedMoreResponseData) => {
-                        panic!("unexpected request header")
+                        Ok(UserDataReply::Acknowledge)
                     }
-                    None => todo!("unknown request header"),
                 }
+                RequestHeader::NeedMoreResponseData => {
+                    warn!(
+                        "received request for more response data while still receiving the request"
+                    );
+                    self.protocol_violation()
+                }
+            },
+            UserDataState::ReceivedRequest => {
+                if !is_retry {
+                    warn!(
+                        "received user request while the previous request is still waiting for a response"
+                    );
+                    return self.protocol_violation();
+                }
+                info!("user request retried while still waiting for a response");
+                Ok(UserDataReply::NotReady)
             }
-            UserDataState::ReceivedRequest(_) => {
-                panic!("TODO: handle case where request received when already have one")
-            }
-            UserDataState::SendingResponse {
-                pending_request, ..
-            } => match RequestHeader::parse(request[0]) {
-                Some(RequestHeader::NeedMoreResponseData) => {
+            UserDataState::SendingResponse { bytes_sent } => match header {
+                RequestHeader::NeedMoreResponseData => {
                     info!("received user request for more response data");
-                    *pending_request = true;
+                    Ok(UserDataReply::Respond)
+                }
+                RequestHeader::FinalRequest if is_retry && *bytes_sent == 0 => {
+                    info!("user request retried, sending the response");
+                    Ok(UserDataReply::Respond)
+                }
+                _ => {
+                    warn!("received new user request before the previous response was fully sent");
+                    self.protocol_violation()
                 }
-                _ => panic!(
-                    "TODO: handle protocol violation where request is sent without correct header value"
-                ),
             },
             UserDataState::None => {
                 // start a new transaction
-                match RequestHeader::parse(request[0]) {
-                    Some(RequestHeader::FinalRequest) => {
+                buffer.clear();
+                *origin = request.application_parameter;
+                if buffer.try_extend_from_slice(data).is_err() {
+                    warn!("user request is longer than MAX_MESSAGE_LEN");
+                    return self.protocol_violation();
+                }
+                match header {
+                    RequestHeader::FinalRequest => {
                         info!("starting new user request - final request packet");
-                        *self = UserDataState::ReceivedRequest({
-                            let mut v = ArrayVec::new();
-                            v.extend(request.as_slice()[1..].iter().copied());
-                            v
-                        });
+                        *self = UserDataState::ReceivedRequest;
+                        Ok(UserDataReply::NotReady)
                     }
-                    Some(RequestHeader::InitialRequest) => {
+                    RequestHeader::InitialRequest => {
                         info!("starting new user request - initial request packet");
-                        in_progress_message.send_user_response(&[], &mut 0, tx);
-                        *self = UserDataState::ReceivingRequest({
-                            let mut v = ArrayVec::new();
-                            v.extend(request.as_slice()[1..].iter().copied());
-                            v
-                        });
+                        *self = UserDataState::ReceivingRequest;
+                        Ok(UserDataReply::Acknowledge)
                     }
-                    Some(RequestHeader::NeedMoreResponseData) => {
-                        panic!("TODO: unexpected request header")
+                    RequestHeader::NeedMoreResponseData => {
+                        warn!("received request for more response data without a response");
+                        self.protocol_violation()
                     }
-                    None => todo!("unknown user request header"),
                 }
             }
         }
     }
+
+    /// Writes `reply` as the response message to a key handle that arrived in a message of `message_type`.
+    ///
+    /// `rp_id_hash` is the `application_parameter` of that key handle, and `buffer` is `Protocol::user_data_buffer`.
+    fn send_reply(
+        &mut self,
+        reply: UserDataReply,
+        message_type: MessageType,
+        rp_id_hash: &[u8; 32],
+        buffer: &[u8],
+        tx: &mut OutgoingMessage,
+    ) -> Result<(), NotWebUsbError> {
+        match reply {
+            UserDataReply::Acknowledge => {
+                send_user_response(message_type, &[], &mut 0, rp_id_hash, tx)
+            }
+            // Held open CTAP2 requests are not answered until the response is ready, so this is only reached by U2F.
+            UserDataReply::NotReady => {
+                u2f::send_error_response(tx, MessageResponseError::ConditionsNotSatisfied)
+            }
+            UserDataReply::Respond => {
+                let UserDataState::SendingResponse { bytes_sent } = self else {
+                    error!("attempted to send a user response while there is no response");
+                    return Err(NotWebUsbError::InternalError);
+                };
+                send_user_response(message_type, buffer, bytes_sent, rp_id_hash, tx)?;
+                if *bytes_sent >= buffer.len() as u32 {
+                    *self = UserDataState::None;
+                }
+                Ok(())
+            }
+            UserDataReply::Forbidden => send_forbidden_response(message_type, rp_id_hash, tx),
+        }
+    }
+
+    /// Handles a request that violates the not-webusb protocol, the client must be sent an error via `send_user_rejection`.
+    ///
+    /// Any partially received request or partially sent response is discarded.
+    /// A fully received request is kept, since the application may already be processing it.
+    fn protocol_violation(&mut self) -> Result<UserDataReply, NotWebUsbError> {
+        if !matches!(self, UserDataState::ReceivedRequest) {
+            *self = UserDataState::None;
+        }
+        Err(NotWebUsbError::ProtocolViolation)
+    }
+}
+
+/// Sends the next part of `response` in the format of `message_type`, see `u2f::send_user_response`.
+#[cfg_attr(not(feature = "ctap2"), allow(unused_variables))]
+fn send_user_response(
+    message_type: MessageType,
+    response: &[u8],
+    bytes_sent: &mut u32,
+    rp_id_hash: &[u8; 32],
+    tx: &mut OutgoingMessage,
+) -> Result<(), NotWebUsbError> {
+    match message_type {
+        MessageType::U2f => u2f::send_user_response(response, bytes_sent, tx),
+        #[cfg(feature = "ctap2")]
+        MessageType::Cbor => ctap2::send_user_response(response, bytes_sent, rp_id_hash, tx),
+        // Without the `ctap2` feature, CBOR messages never carry user data.
+        #[cfg(not(feature = "ctap2"))]
+        MessageType::Cbor => Err(NotWebUsbError::InternalError),
+    }
+}
+
+/// Answers a key handle that violates the not-webusb protocol with an error, in the format of `message_type`.
+fn send_user_rejection(
+    message_type: MessageType,
+    tx: &mut OutgoingMessage,
+) -> Result<(), NotWebUsbError> {
+    match message_type {
+        MessageType::U2f => u2f::send_error_response(tx, MessageResponseError::WrongData),
+        #[cfg(feature = "ctap2")]
+        MessageType::Cbor => ctap2::send_error_response(tx, ctap2::Status::NoCredentials),
+        #[cfg(not(feature = "ctap2"))]
+        MessageType::Cbor => Err(NotWebUsbError::InternalError),
+    }
+}
+
+/// Answers a key handle completing a request that its origin lacks the permissions for, in the format of `message_type`.
+#[cfg_attr(not(feature = "ctap2"), allow(unused_variables))]
+fn send_forbidden_response(
+    message_type: MessageType,
+    rp_id_hash: &[u8; 32],
+    tx: &mut OutgoingMessage,
+) -> Result<(), NotWebUsbError> {
+    match message_type {
+        MessageType::U2f => u2f::send_forbidden_response(tx),
+        #[cfg(feature = "ctap2")]
+        MessageType::Cbor => ctap2::send_forbidden_response(tx, rp_id_hash),
+        #[cfg(not(feature = "ctap2"))]
+        MessageType::Cbor => Err(NotWebUsbError::InternalError),
+    }
 }
 
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -480,10 +1366,42 @@ impl RequestHeader {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum NotWebUsbError {
     /// A USB error that NotWebusb cannot handle.
     /// This will not occur in regular usage and indicates something has gone terribly wrong.
     /// All NotWebUsb internal state is reset including the loss of any in progress request or response is lost.
     /// Attempt to recover by either recreating the USB connection or resetting the device.
     UsbError,
+    /// The client stopped sending or collecting the packets of a CTAPHID transaction for longer than `Timeouts::transaction`.
+    /// The transaction was aborted, and if the request was still being received, the client was sent a `MessageTimeout` error.
+    /// NotWebUsb remains usable, no action is required.
+    TransactionTimeout,
+    /// The client did not send the rest of a partially received request within `Timeouts::user_data`.
+    /// The request was discarded without ever being returned by `NotWebUsb::check_pending_request`.
+    /// NotWebUsb remains usable, no action is required.
+    RequestTimeout,
+    /// The client did not collect the rest of the response within `Timeouts::user_data`.
+    /// The response was discarded, the client will need to send its request again.
+    /// NotWebUsb remains usable, no action is required.
+    ResponseTimeout,
+    /// The client sent a request that violates the not-webusb protocol, e.g. an unknown header or a request longer than `MAX_MESSAGE_LEN`.
+    /// The client was sent an error and any partially received request or partially sent response was discarded.
+    /// NotWebUsb remains usable, no action is required.
+    ProtocolViolation,
+    /// `NotWebUsb::send_response` was called while there was no pending request.
+    /// The response was dropped, NotWebUsb remains usable.
+    NoPendingRequest,
+    /// `NotWebUsb::send_response_from_slice` was called with a response longer than `MAX_MESSAGE_LEN`.
+    /// The request remains pending, a different response must be sent instead.
+    ResponseTooLong,
+    /// `NotWebUsb::send_response_as` could not encode the response, usually because it is longer than `MAX_MESSAGE_LEN`.
+    /// The response was partially written over the request, so the request remains pending but is now empty.
+    /// A different response must be sent instead.
+    #[cfg(any(feature = "postcard", feature = "json"))]
+    ResponseEncodingFailed,
+    /// NotWebUsb ran into an internal inconsistency that should not be possible, please report this as a bug.
+    /// The in progress transaction was aborted, the client was sent an error and any partially received request or partially sent response was discarded.
+    /// NotWebUsb remains usable.
+    InternalError,
 }
@@ -0,0 +1,312 @@
+//! The `OriginFilter` trait and its implementations, which receive the sha256 hash of the rpId of every request.
+
+use crate::OriginStore;
+
+/// The decision of an `OriginFilter` on the website that sent a request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum OriginVerdict {
+    /// The request is passed on to the application.
+    Allow,
+    /// The request is dropped, the client receives a valid response that carries no data.
+    Deny,
+    /// The filter cannot decide yet, e.g. because it is waiting for the user to approve the website.
+    ///
+    /// The request is left unanswered and the filter is asked again until it decides:
+    /// a U2F client is told to retry, as if waiting for the user to touch the device,
+    /// while with the `ctap2` feature a CTAP2 request is held open with keepalives telling the client that user presence is needed.
+    Ask,
+}
+
+impl From<bool> for OriginVerdict {
+    fn from(allow: bool) -> Self {
+        if allow {
+            OriginVerdict::Allow
+        } else {
+            OriginVerdict::Deny
+        }
+    }
+}
+
+/// Limits the websites that can talk to the device, see `NotWebUsb::new`.
+///
+/// Implemented for closures returning whether to allow a request, and for `OriginAllowlist` and `OriginPolicy`.
+/// Implement it for your own type to keep state between requests, e.g. to count attempts or to consult settings stored in flash.
+/// The filter can be accessed afterwards via `NotWebUsb::origin_filter_mut`.
+pub trait OriginFilter {
+    /// Decide on a request from the website whose rpId hashes to `origin_hash`.
+    ///
+    /// Called for every not-webusb key handle, including retries, so a website can be allowed or denied partway through a request.
+    fn check(&mut self, origin_hash: [u8; 32]) -> OriginVerdict;
+
+    /// The permissions granted to requests from the website whose rpId hashes to `origin_hash`,
+    /// as a bitmask of application defined permissions, see `NotWebUsbBuilder::required_permissions`.
+    ///
+    /// Only called for websites that were allowed by `OriginFilter::check`.
+    /// Defaults to granting every permission.
+    fn permissions(&mut self, origin_hash: [u8; 32]) -> u32 {
+        let _ = origin_hash;
+        u32::MAX
+    }
+
+    /// The store of allowed origins that admin messages list, add to and remove from, see `NotWebUsbBuilder::admin_origin`.
+    ///
+    /// Defaults to `None`, in which case admin messages are answered with `AdminStatus::Unsupported`.
+    fn origin_store(&mut self) -> Option<&mut dyn OriginStore> {
+        None
+    }
+}
+
+impl<F: FnMut([u8; 32]) -> bool> OriginFilter for F {
+    fn check(&mut self, origin_hash: [u8; 32]) -> OriginVerdict {
+        self(origin_hash).into()
+    }
+}
+
+/// Returns the sha256 hash of `rp_id`, which is what an `OriginFilter` receives for requests from that rpId.
+///
+/// This is a `const fn`, prefer the `origin_hash!` macro to guarantee it is evaluated at compile time.
+pub const fn origin_hash(rp_id: &str) -> [u8; 32] {
+    sha256(rp_id.as_bytes())
+}
+
+/// The sha256 hash of an rpId, evaluated at compile time, see `origin_hash`.
+///
+/// ```
+/// const EXAMPLE_ORIGIN_HASH: [u8; 32] = not_webusb::origin_hash!("example.com");
+/// ```
+#[macro_export]
+macro_rules! origin_hash {
+    ($rp_id:expr) => {
+        const { $crate::origin_hash($rp_id) }
+    };
+}
+
+/// An `OriginFilter` that accepts requests from any of a fixed set of rpIds, e.g. production, staging and a local development proxy.
+///
+/// ```
+/// use not_webusb::OriginAllowlist;
+///
+/// let web_origin_filter = OriginAllowlist::new(["example.com", "staging.example.com", "localhost"]);
+/// # use not_webusb::{OriginFilter, OriginVerdict};
+/// # let mut web_origin_filter = web_origin_filter;
+/// # assert_eq!(web_origin_filter.check(not_webusb::origin_hash!("localhost")), OriginVerdict::Allow);
+/// ```
+///
+/// Since `new` is a `const fn`, it can also initialize a `static` to be consulted from a closure: `&|origin_hash| ALLOWLIST.allows(origin_hash)`.
+#[derive(Clone, Copy, Debug)]
+pub struct OriginAllowlist<const N: usize> {
+    hashes: [[u8; 32]; N],
+}
+
+impl<const N: usize> OriginAllowlist<N> {
+    /// Hashes each of `rp_ids`, this is a `const fn` so that it can initialize a `static`.
+    pub const fn new(rp_ids: [&str; N]) -> Self {
+        let mut hashes = [[0; 32]; N];
+        let mut i = 0;
+        while i < N {
+            hashes[i] = origin_hash(rp_ids[i]);
+            i += 1;
+        }
+        OriginAllowlist { hashes }
+    }
+
+    /// Create an allowlist from already hashed rpIds, see `origin_hash!`.
+    pub const fn from_hashes(hashes: [[u8; 32]; N]) -> Self {
+        OriginAllowlist { hashes }
+    }
+
+    /// Returns true if `origin_hash` is the hash of one of the allowed rpIds.
+    pub fn allows(&self, origin_hash: [u8; 32]) -> bool {
+        self.hashes.contains(&origin_hash)
+    }
+}
+
+impl<const N: usize> OriginFilter for OriginAllowlist<N> {
+    fn check(&mut self, origin_hash: [u8; 32]) -> OriginVerdict {
+        self.allows(origin_hash).into()
+    }
+}
+
+/// Grants each of a fixed set of rpIds its own permissions, as a bitmask of application defined permissions.
+///
+/// As the `OriginFilter`, it accepts every listed rpId and grants it its permissions,
+/// which are checked against `NotWebUsbBuilder::required_permissions` before a request is handed to the application:
+/// ```
+/// use not_webusb::{OriginFilter, OriginPolicy};
+///
+/// const READ: u32 = 1 << 0;
+/// const WRITE: u32 = 1 << 1;
+/// const FIRMWARE_UPDATE: u32 = 1 << 2;
+///
+/// static POLICY: OriginPolicy<2> = OriginPolicy::new([
+///     ("configurator.example.com", READ),
+///     ("service.example.com", READ | WRITE | FIRMWARE_UPDATE),
+/// ]);
+/// let mut web_origin_filter = POLICY;
+/// # assert_eq!(web_origin_filter.check(not_webusb::origin_hash!("configurator.example.com")), not_webusb::OriginVerdict::Allow);
+/// # assert_eq!(OriginFilter::permissions(&mut web_origin_filter, not_webusb::origin_hash!("configurator.example.com")), READ);
+/// # assert_eq!(POLICY.permissions(not_webusb::origin_hash!("example.org")), 0);
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct OriginPolicy<const N: usize> {
+    origins: [([u8; 32], u32); N],
+}
+
+impl<const N: usize> OriginPolicy<N> {
+    /// Hashes the rpId of each of `origins`, this is a `const fn` so that it can initialize a `static`.
+    pub const fn new(origins: [(&str, u32); N]) -> Self {
+        let mut hashed = [([0; 32], 0); N];
+        let mut i = 0;
+        while i < N {
+            hashed[i] = (origin_hash(origins[i].0), origins[i].1);
+            i += 1;
+        }
+        OriginPolicy { origins: hashed }
+    }
+
+    /// Create a policy from already hashed rpIds, see `origin_hash!`.
+    pub const fn from_hashes(origins: [([u8; 32], u32); N]) -> Self {
+        OriginPolicy { origins }
+    }
+
+    /// Returns true if `origin_hash` is the hash of one of the listed rpIds, regardless of its permissions.
+    pub fn allows(&self, origin_hash: [u8; 32]) -> bool {
+        self.origins.iter().any(|(hash, _)| *hash == origin_hash)
+    }
+
+    /// Returns the permissions granted to the rpId hashed as `origin_hash`, or no permissions if it is not listed.
+    pub fn permissions(&self, origin_hash: [u8; 32]) -> u32 {
+        self.origins
+            .iter()
+            .find(|(hash, _)| *hash == origin_hash)
+            .map_or(0, |(_, permissions)| *permissions)
+    }
+}
+
+impl<const N: usize> OriginFilter for OriginPolicy<N> {
+    fn check(&mut self, origin_hash: [u8; 32]) -> OriginVerdict {
+        self.allows(origin_hash).into()
+    }
+
+    fn permissions(&mut self, origin_hash: [u8; 32]) -> u32 {
+        OriginPolicy::permissions(self, origin_hash)
+    }
+}
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+const H: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+/// A plain sha256 implementation, written as a `const fn` so that origin hashes can be computed at compile time.
+///
+/// It is also used at runtime to hash the rpId of CTAP2 requests, which are short enough that speed does not matter.
+pub(crate) const fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut state = H;
+    // The padding is a 0x80 byte, zeroes and the bit length as a big endian u64, making the total a multiple of 64 bytes.
+    let padded_len = (data.len() + 1 + 8).div_ceil(64) * 64;
+    let bit_len = (data.len() as u64 * 8).to_be_bytes();
+
+    let mut block_start = 0;
+    while block_start < padded_len {
+        let mut block = [0u8; 64];
+        let mut i = 0;
+        while i < 64 {
+            let offset = block_start + i;
+            block[i] = if offset < data.len() {
+                data[offset]
+            } else if offset == data.len() {
+                0x80
+            } else if offset >= padded_len - 8 {
+                bit_len[offset - (padded_len - 8)]
+            } else {
+                0
+            };
+            i += 1;
+        }
+        state = compress(state, &block);
+        block_start += 64;
+    }
+
+    let mut hash = [0; 32];
+    let mut i = 0;
+    while i < 8 {
+        let word = state[i].to_be_bytes();
+        hash[i * 4] = word[0];
+        hash[i * 4 + 1] = word[1];
+        hash[i * 4 + 2] = word[2];
+        hash[i * 4 + 3] = word[3];
+        i += 1;
+    }
+    hash
+}
+
+const fn compress(state: [u32; 8], block: &[u8; 64]) -> [u32; 8] {
+    let mut w = [0u32; 64];
+    let mut i = 0;
+    while i < 16 {
+        w[i] = u32::from_be_bytes([
+            block[i * 4],
+            block[i * 4 + 1],
+            block[i * 4 + 2],
+            block[i * 4 + 3],
+        ]);
+        i += 1;
+    }
+    while i < 64 {
+        let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+        let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+        w[i] = w[i - 16]
+            .wrapping_add(s0)
+            .wrapping_add(w[i - 7])
+            .wrapping_add(s1);
+        i += 1;
+    }
+
+    let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = state;
+    let mut i = 0;
+    while i < 64 {
+        let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+        let ch = (e & f) ^ (!e & g);
+        let temp1 = h
+            .wrapping_add(s1)
+            .wrapping_add(ch)
+            .wrapping_add(K[i])
+            .wrapping_add(w[i]);
+        let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+        let maj = (a & b) ^ (a & c) ^ (b & c);
+        let temp2 = s0.wrapping_add(maj);
+
+        h = g;
+        g = f;
+        f = e;
+        e = d.wrapping_add(temp1);
+        d = c;
+        c = b;
+        b = a;
+        a = temp1.wrapping_add(temp2);
+        i += 1;
+    }
+
+    [
+        state[0].wrapping_add(a),
+        state[1].wrapping_add(b),
+        state[2].wrapping_add(c),
+        state[3].wrapping_add(d),
+        state[4].wrapping_add(e),
+        state[5].wrapping_add(f),
+        state[6].wrapping_add(g),
+        state[7].wrapping_add(h),
+    ]
+}
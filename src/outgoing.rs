@@ -0,0 +1,76 @@
+use crate::{MAXIMUM_RESPONSE_MESSAGE, NotWebUsbError};
+use core::ops::{Deref, DerefMut};
+
+/// The U2F/CBOR response message currently being sent to the client.
+///
+/// Only contains data for one message at a time.
+/// The reader can determine the total length of the message as the length of `OutgoingMessage::unsent` before it is partially sent.
+pub struct OutgoingMessage<'a> {
+    buffer: &'a mut [u8; MAXIMUM_RESPONSE_MESSAGE],
+    len: usize,
+    sent: usize,
+}
+
+impl<'a> OutgoingMessage<'a> {
+    pub fn new(buffer: &'a mut [u8; MAXIMUM_RESPONSE_MESSAGE]) -> Self {
+        OutgoingMessage {
+            buffer,
+            len: 0,
+            sent: 0,
+        }
+    }
+
+    /// Grants space for an entire response message, which is not sent until `Grant::commit` is called.
+    ///
+    /// This fails if the previous message was not fully sent or discarded.
+    pub fn grant(&mut self) -> Result<Grant<'_, 'a>, NotWebUsbError> {
+        if !self.unsent().is_empty() {
+            error!("Failed to grant space for a response as the previous response is unsent");
+            return Err(NotWebUsbError::InternalError);
+        }
+        Ok(Grant { message: self })
+    }
+
+    /// The part of the message that has not been sent yet, empty if there is no message.
+    pub fn unsent(&self) -> &[u8] {
+        &self.buffer[self.sent..self.len]
+    }
+
+    /// Marks the first `len` bytes of `OutgoingMessage::unsent` as sent, once they have been copied into a report.
+    pub fn mark_sent(&mut self, len: usize) {
+        self.sent = (self.sent + len).min(self.len);
+    }
+
+    /// Drops the message, whether or not it was fully sent.
+    pub fn discard(&mut self) {
+        self.len = 0;
+        self.sent = 0;
+    }
+}
+
+/// Space for writing a response message into, see `OutgoingMessage::grant`.
+pub struct Grant<'g, 'a> {
+    message: &'g mut OutgoingMessage<'a>,
+}
+
+impl Grant<'_, '_> {
+    /// Makes the first `len` bytes written to the grant available to be sent.
+    pub fn commit(self, len: usize) {
+        self.message.len = len.min(MAXIMUM_RESPONSE_MESSAGE);
+        self.message.sent = 0;
+    }
+}
+
+impl Deref for Grant<'_, '_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.message.buffer.as_slice()
+    }
+}
+
+impl DerefMut for Grant<'_, '_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.message.buffer.as_mut_slice()
+    }
+}
@@ -0,0 +1,194 @@
+//! Approving new origins by pressing a button on the device, see `ButtonPairing`.
+
+use crate::{Duration, Instant, OriginFilter, OriginVerdict};
+use arrayvec::ArrayVec;
+use embedded_hal::digital::InputPin;
+
+/// Where `ButtonPairing` keeps the origins the user has approved.
+///
+/// Implemented for `ArrayVec<[u8; 32], N>`, which forgets every approval on reset,
+/// and for `FlashAllowlist`, which keeps them in flash across resets.
+pub trait OriginStore {
+    /// Returns true if the origin whose rpId hashes to `origin_hash` has been approved.
+    fn contains(&mut self, origin_hash: [u8; 32]) -> bool;
+
+    /// Records that the origin whose rpId hashes to `origin_hash` has been approved.
+    /// Returns false if it could not be recorded, e.g. because the store is full, in which case the origin is denied.
+    fn insert(&mut self, origin_hash: [u8; 32]) -> bool;
+
+    /// Forgets the approval of the origin whose rpId hashes to `origin_hash`.
+    /// Returns false if it was not stored or could not be removed.
+    fn remove(&mut self, origin_hash: [u8; 32]) -> bool;
+
+    /// Returns the `index`th stored origin hash, or `None` once `index` is past the last one.
+    fn get(&mut self, index: usize) -> Option<[u8; 32]>;
+}
+
+impl<const N: usize> OriginStore for ArrayVec<[u8; 32], N> {
+    fn contains(&mut self, origin_hash: [u8; 32]) -> bool {
+        self.as_slice().contains(&origin_hash)
+    }
+
+    fn insert(&mut self, origin_hash: [u8; 32]) -> bool {
+        self.try_push(origin_hash).is_ok()
+    }
+
+    fn remove(&mut self, origin_hash: [u8; 32]) -> bool {
+        match self.iter().position(|stored| *stored == origin_hash) {
+            Some(index) => {
+                ArrayVec::remove(self, index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn get(&mut self, index: usize) -> Option<[u8; 32]> {
+        self.as_slice().get(index).copied()
+    }
+}
+
+/// An `OriginFilter` that lets the user approve new websites by pressing a button, similar to pairing a bluetooth device.
+///
+/// Origins already in the `OriginStore` are allowed.
+/// A request from any other origin starts pairing: the filter answers `OriginVerdict::Ask`
+/// until the button is pressed, which approves the origin and adds it to the store,
+/// or until `window` has passed without a press, which denies the request.
+/// Only a press after the button has been seen released counts, so a button that is held down,
+/// stuck or configured with the wrong polarity when pairing starts never approves anything.
+/// While one origin is pairing, requests from other unknown origins are denied.
+///
+/// ```ignore
+/// let now = || timer.get_counter();
+/// let button = pins.gpio3.into_pull_up_input();
+/// let pairing = ButtonPairing::new(button, ArrayVec::<_, 8>::new(), &now, Duration::secs(10)).active_low();
+/// let mut not_webusb = NotWebUsb::new(fido, storage, pairing).with_timeouts(&now, Timeouts::default());
+/// ```
+/// Use `ButtonPairing::pairing_origin` via `NotWebUsb::origin_filter` to show the user that a website is waiting for approval, e.g. by blinking an LED.
+pub struct ButtonPairing<'a, P: InputPin, S: OriginStore> {
+    button: P,
+    active_low: bool,
+    store: S,
+    now: &'a dyn Fn() -> Instant,
+    window: Duration,
+    pairing: Option<Pairing>,
+}
+
+/// An origin waiting for the button to be pressed.
+#[derive(Clone, Copy)]
+struct Pairing {
+    origin_hash: [u8; 32],
+    started: Instant,
+    /// True once the button has been read as released since pairing started, so that the next press approves the origin.
+    released: bool,
+}
+
+impl<'a, P: InputPin, S: OriginStore> ButtonPairing<'a, P, S> {
+    /// Approve origins into `store` when `button` is pressed, after having been seen released, within `window` of the first request from the origin.
+    ///
+    /// `now` must return the current time of a monotonic clock, see `NotWebUsb::with_timeouts`.
+    pub fn new(button: P, store: S, now: &'a dyn Fn() -> Instant, window: Duration) -> Self {
+        ButtonPairing {
+            button,
+            active_low: false,
+            store,
+            now,
+            window,
+            pairing: None,
+        }
+    }
+
+    /// Treat the button as pressed when it reads low instead, e.g. for a button to ground on a pin with a pull-up.
+    pub fn active_low(mut self) -> Self {
+        self.active_low = true;
+        self
+    }
+
+    /// The origin currently waiting for the button to be pressed, if any.
+    pub fn pairing_origin(&self) -> Option<[u8; 32]> {
+        self.pairing
+            .filter(|pairing| !self.expired(pairing.started))
+            .map(|pairing| pairing.origin_hash)
+    }
+
+    /// The store of approved origins, e.g. to forget an origin.
+    pub fn store(&mut self) -> &mut S {
+        &mut self.store
+    }
+
+    fn expired(&self, started: Instant) -> bool {
+        (self.now)()
+            .checked_duration_since(started)
+            .is_some_and(|elapsed| elapsed > self.window)
+    }
+
+    fn is_pressed(&mut self) -> bool {
+        let pressed = if self.active_low {
+            self.button.is_low()
+        } else {
+            self.button.is_high()
+        };
+        // A button that cannot be read is never pressed.
+        pressed.unwrap_or(false)
+    }
+}
+
+impl<P: InputPin, S: OriginStore> OriginFilter for ButtonPairing<'_, P, S> {
+    fn origin_store(&mut self) -> Option<&mut dyn OriginStore> {
+        Some(&mut self.store)
+    }
+
+    fn check(&mut self, origin_hash: [u8; 32]) -> OriginVerdict {
+        if self.store.contains(origin_hash) {
+            return OriginVerdict::Allow;
+        }
+        match self.pairing {
+            Some(pairing) if pairing.origin_hash == origin_hash => {
+                let pressed = self.is_pressed();
+                if pressed && pairing.released {
+                    self.pairing = None;
+                    if self.store.insert(origin_hash) {
+                        info!("origin {} approved via button press", origin_hash);
+                        OriginVerdict::Allow
+                    } else {
+                        warn!("origin {} approved, but could not be stored", origin_hash);
+                        OriginVerdict::Deny
+                    }
+                } else if self.expired(pairing.started) {
+                    info!("origin {} was not approved in time", origin_hash);
+                    self.pairing = None;
+                    OriginVerdict::Deny
+                } else {
+                    if !pressed {
+                        self.pairing = Some(Pairing {
+                            released: true,
+                            ..pairing
+                        });
+                    }
+                    OriginVerdict::Ask
+                }
+            }
+            Some(pairing) if !self.expired(pairing.started) => {
+                info!(
+                    "origin {} denied while another origin is pairing",
+                    origin_hash
+                );
+                OriginVerdict::Deny
+            }
+            _ => {
+                info!("origin {} waiting for a button press", origin_hash);
+                // A button that is already pressed must be released first.
+                let released = !self.is_pressed();
+                if !released {
+                    warn!("button is already pressed as pairing starts");
+                }
+                self.pairing = Some(Pairing {
+                    origin_hash,
+                    started: (self.now)(),
+                    released,
+                });
+                OriginVerdict::Ask
+            }
+        }
+    }
+}
@@ -0,0 +1,261 @@
+use crate::{NotWebUsb, NotWebUsbError, OriginFilter, Postcard, Protocol, RequestError};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use usb_device::bus::UsbBus;
+
+/// The method ID reserved for the `version` method that every `rpc!` interface provides.
+pub const VERSION_METHOD: u16 = 0;
+
+/// Returns the ID of the method that `request` calls, or `None` if it does not start with one.
+pub fn method_id(request: &[u8]) -> Option<u16> {
+    postcard::take_from_bytes::<u16>(request)
+        .ok()
+        .map(|(method, _)| method)
+}
+
+/// Sends an RPC call to a device, implemented by clients such as `not_webusb_client::Client`.
+///
+/// The methods of the `Client` trait generated by `rpc!` are implemented on top of this.
+pub trait Caller {
+    type Error;
+
+    /// Calls the method with ID `method`, passing `args` which must be a tuple of the method's arguments.
+    ///
+    /// The request is sent as the postcard encoding of `(method, args)`,
+    /// and the response is received as the postcard encoding of `Result<O, RequestError>`.
+    fn call<A: Serialize, O: DeserializeOwned>(
+        &mut self,
+        method: u16,
+        args: &A,
+    ) -> Result<O, Self::Error>;
+}
+
+/// Answers RPC calls from clients, implemented by `NotWebUsb` and `EmbassyNotWebUsb`.
+///
+/// The `dispatch` function generated by `rpc!` is implemented on top of this, so it works with either backend.
+pub trait Callee<'a, const MAX_MESSAGE_LEN: usize, F: OriginFilter> {
+    /// Returns the current request decoded as an RPC call, if there is one, see `NotWebUsb::check_pending_call`.
+    fn check_pending_call(&mut self) -> Option<Call<'_, 'a, MAX_MESSAGE_LEN, F>>;
+}
+
+/// A pending request, decoded as an RPC call, see `NotWebUsb::check_pending_call`.
+///
+/// This is the building block of the `dispatch` function generated by `rpc!`.
+pub struct Call<'n, 'a, const MAX_MESSAGE_LEN: usize, F: OriginFilter> {
+    protocol: &'n mut Protocol<'a, MAX_MESSAGE_LEN, F>,
+    method: u16,
+    args_start: usize,
+}
+
+impl<const MAX_MESSAGE_LEN: usize, F: OriginFilter> Call<'_, '_, MAX_MESSAGE_LEN, F> {
+    /// The ID of the method being called.
+    pub fn method(&self) -> u16 {
+        self.method
+    }
+
+    /// Decodes the arguments of the call as a `T`, which must be a tuple of the method's arguments.
+    ///
+    /// If the arguments cannot be decoded as a `T`, the call is answered with `RequestError::DecodeFailed` and `None` is returned.
+    pub fn args<T: DeserializeOwned>(&mut self) -> Option<T> {
+        let args = self
+            .protocol
+            .check_pending_request()
+            .and_then(|request| postcard::from_bytes(&request.payload[self.args_start..]).ok());
+        if args.is_none() {
+            warn!(
+                "failed to decode rpc call arguments, responding with RequestError::DecodeFailed"
+            );
+            self.protocol
+                .send_error_response(&Postcard, RequestError::DecodeFailed)
+                .ok();
+        }
+        args
+    }
+
+    /// Sends the return value of the method as the response.
+    pub fn respond<T: Serialize>(self, output: &T) -> Result<(), NotWebUsbError> {
+        self.protocol.send_response_as(Postcard, output)
+    }
+
+    /// Answers a call to a method that the device does not know about, with `RequestError::UnknownMethod`.
+    pub fn unknown_method(self, device_version: u16) -> Result<(), NotWebUsbError> {
+        warn!("unknown rpc method {}", self.method);
+        self.protocol
+            .send_error_response(&Postcard, RequestError::UnknownMethod { device_version })
+    }
+}
+
+impl<'a, UsbBusT: UsbBus, const MAX_MESSAGE_LEN: usize, F: OriginFilter>
+    NotWebUsb<'a, UsbBusT, MAX_MESSAGE_LEN, F>
+{
+    /// Returns the current request decoded as an RPC call, if there is one.
+    /// Calling this does not consume the request, it is consumed once the returned `Call` responds.
+    ///
+    /// If the request does not start with a method ID, it is consumed by sending `RequestError::DecodeFailed` to the client and `None` is returned.
+    ///
+    /// This is usually called via the `dispatch` function generated by `rpc!`.
+    pub fn check_pending_call(&mut self) -> Option<Call<'_, 'a, MAX_MESSAGE_LEN, F>> {
+        self.protocol.check_pending_call()
+    }
+}
+
+impl<'a, UsbBusT: UsbBus, const MAX_MESSAGE_LEN: usize, F: OriginFilter>
+    Callee<'a, MAX_MESSAGE_LEN, F> for NotWebUsb<'a, UsbBusT, MAX_MESSAGE_LEN, F>
+{
+    fn check_pending_call(&mut self) -> Option<Call<'_, 'a, MAX_MESSAGE_LEN, F>> {
+        self.protocol.check_pending_call()
+    }
+}
+
+impl<'a, const MAX_MESSAGE_LEN: usize, F: OriginFilter> Protocol<'a, MAX_MESSAGE_LEN, F> {
+    pub(crate) fn check_pending_call(&mut self) -> Option<Call<'_, 'a, MAX_MESSAGE_LEN, F>> {
+        let request = self.check_pending_request()?.payload;
+        match postcard::take_from_bytes::<u16>(request) {
+            Ok((method, args)) => {
+                let args_start = request.len() - args.len();
+                Some(Call {
+                    protocol: self,
+                    method,
+                    args_start,
+                })
+            }
+            Err(_) => {
+                warn!("failed to decode rpc method, responding with RequestError::DecodeFailed");
+                self.send_error_response(&Postcard, RequestError::DecodeFailed)
+                    .ok();
+                None
+            }
+        }
+    }
+}
+
+/// Defines an RPC interface between a device and its clients.
+///
+/// The interface is defined once, in a crate shared by the firmware and the client, e.g.:
+/// ```ignore
+/// not_webusb::rpc! {
+///     /// Configures a keyboard.
+///     pub mod keyboard {
+///         version = 3;
+///
+///         /// Maps `key` to the keycode `code`, returns false if there is no such key.
+///         1 => fn set_key(key: u8, code: u16) -> bool;
+///         /// Added in version 2.
+///         2 => fn layout() -> [u16; 8];
+///         /// Added in version 3, only callable by origins granted the `FIRMWARE_UPDATE` permission.
+///         3 requires FIRMWARE_UPDATE => fn update_firmware(chunk: [u8; 32]) -> bool;
+///     }
+/// }
+/// ```
+///
+/// This generates a module `keyboard` containing:
+/// * `VERSION`, the version of the interface.
+/// * `Handler`, a trait to be implemented by the firmware, with one method per RPC method.
+/// * `dispatch`, which the firmware calls in its main loop to answer pending calls via a `Handler`.
+///   It accepts a `NotWebUsb` or, with the `embassy` feature, an `EmbassyNotWebUsb`.
+/// * `Client`, a trait implemented for every `Caller` such as `not_webusb_client::Client`,
+///   with one method per RPC method that calls the method on the device.
+///   It also has a `version` method which returns the `VERSION` of the device.
+///
+/// Types in the parent module are in scope within the interface.
+/// All argument and return types must implement `serde::Serialize` and `serde::de::DeserializeOwned`.
+///
+/// ## Versioning
+///
+/// Each method is identified on the wire by its ID, not its name, so methods can be renamed freely.
+/// To allow firmware and clients to be updated independently:
+/// * Only ever add new methods, each with a new ID, and increment `version` when doing so.
+/// * Never change the arguments or return type of an existing method, add a new method instead.
+/// * Never reuse the ID of a removed method. ID 0 is reserved for `version`.
+///
+/// A client calling a method that an older device does not know about receives `RequestError::UnknownMethod`, which includes the device's version.
+///
+/// ## Permissions
+///
+/// A method declared with `requires PERMISSIONS`, where `PERMISSIONS` is a `u32` bitmask, is only called for origins granted all of those permissions.
+/// Pass the generated `required_permissions` function to `NotWebUsbBuilder::required_permissions`,
+/// and grant permissions to origins via `OriginFilter::permissions`, e.g. with `OriginPolicy`.
+/// Calls from other origins are rejected before reaching the `Handler`, and clients receive `Error::Forbidden`.
+#[macro_export]
+macro_rules! rpc {
+    (
+        $(#[$meta:meta])*
+        $vis:vis mod $module:ident {
+            version = $version:expr;
+            $(
+                $(#[$method_meta:meta])*
+                $id:literal $(requires $permissions:expr)? => fn $method:ident($($arg:ident: $arg_ty:ty),* $(,)?) -> $output:ty;
+            )*
+        }
+    ) => {
+        $(#[$meta])*
+        $vis mod $module {
+            #[allow(unused_imports)]
+            use super::*;
+
+            /// The version of this interface.
+            pub const VERSION: u16 = $version;
+
+            /// Implemented by the firmware to answer calls, see `dispatch`.
+            pub trait Handler {
+                $(
+                    $(#[$method_meta])*
+                    fn $method(&mut self, $($arg: $arg_ty),*) -> $output;
+                )*
+            }
+
+            /// The permissions required by the method that `request` calls, see `NotWebUsbBuilder::required_permissions`.
+            pub fn required_permissions(request: &[u8]) -> u32 {
+                match $crate::method_id(request) {
+                    $(
+                        Some($id) => 0 $(| $permissions)?,
+                    )*
+                    _ => 0,
+                }
+            }
+
+            /// Answers the pending request, if there is one, by calling the matching method of `handler`.
+            ///
+            /// Call this in your main loop after `NotWebUsb::poll`, in place of `NotWebUsb::check_pending_request`.
+            /// With an `EmbassyNotWebUsb`, call it after `EmbassyNotWebUsb::next_request` instead.
+            pub fn dispatch<'a, const MAX_MESSAGE_LEN: usize, F: $crate::OriginFilter>(
+                not_webusb: &mut impl $crate::Callee<'a, MAX_MESSAGE_LEN, F>,
+                handler: &mut impl Handler,
+            ) -> Result<(), $crate::NotWebUsbError> {
+                let Some(call) = not_webusb.check_pending_call() else {
+                    return Ok(());
+                };
+                match call.method() {
+                    $crate::VERSION_METHOD => call.respond(&VERSION),
+                    $(
+                        $id => {
+                            let mut call = call;
+                            let Some(($($arg,)*)) = call.args::<($($arg_ty,)*)>() else {
+                                return Ok(());
+                            };
+                            call.respond(&handler.$method($($arg),*))
+                        }
+                    )*
+                    _ => call.unknown_method(VERSION),
+                }
+            }
+
+            /// Calls the methods of this interface on a device.
+            pub trait Client: $crate::Caller {
+                /// Returns the `VERSION` of the interface implemented by the device.
+                fn version(&mut self) -> Result<u16, Self::Error> {
+                    self.call($crate::VERSION_METHOD, &())
+                }
+
+                $(
+                    $(#[$method_meta])*
+                    fn $method(&mut self, $($arg: $arg_ty),*) -> Result<$output, Self::Error> {
+                        self.call($id, &($($arg,)*))
+                    }
+                )*
+            }
+
+            impl<C: $crate::Caller + ?Sized> Client for C {}
+        }
+    };
+}
@@ -0,0 +1,63 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+/// A serde data format used to decode requests and encode responses, see `NotWebUsb::check_pending_request_as`.
+pub trait Codec {
+    /// Decode `bytes` as a `T`, returning `None` if `bytes` is not a valid `T`.
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Option<T>;
+
+    /// Encode `value` into the start of `buf`, returning the number of bytes written or `None` if `buf` is too small.
+    fn encode<T: Serialize>(&self, value: &T, buf: &mut [u8]) -> Option<usize>;
+}
+
+/// Compact binary encoding via [postcard](https://docs.rs/postcard).
+#[cfg(feature = "postcard")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Postcard;
+
+#[cfg(feature = "postcard")]
+impl Codec for Postcard {
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Option<T> {
+        postcard::from_bytes(bytes).ok()
+    }
+
+    fn encode<T: Serialize>(&self, value: &T, buf: &mut [u8]) -> Option<usize> {
+        postcard::to_slice(value, buf).ok().map(|used| used.len())
+    }
+}
+
+/// JSON encoding via [serde-json-core](https://docs.rs/serde-json-core), convenient for clients written in JS.
+#[cfg(feature = "json")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl Codec for Json {
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Option<T> {
+        serde_json_core::from_slice(bytes)
+            .ok()
+            .map(|(value, _)| value)
+    }
+
+    fn encode<T: Serialize>(&self, value: &T, buf: &mut [u8]) -> Option<usize> {
+        serde_json_core::to_slice(value, buf).ok()
+    }
+}
+
+/// Sent to the client by NotWebUsb in place of a response when it could not hand the request to the application.
+///
+/// Typed responses are sent to the client as a `Result<T, RequestError>`, so that the client can tell the two apart.
+/// e.g. with postcard a response starts with the byte `0` and an error with the byte `1`,
+/// while with JSON a response looks like `{"Ok":...}` and an error like `{"Err":"DecodeFailed"}`.
+/// Typed requests are not wrapped, the client sends the encoded request as is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RequestError {
+    /// The request could not be decoded as the type passed to `NotWebUsb::check_pending_request_as`.
+    DecodeFailed,
+    /// The RPC method called is not known to the device, see `rpc!`.
+    UnknownMethod {
+        /// The version of the RPC interface implemented by the device.
+        device_version: u16,
+    },
+}
@@ -1,16 +1,62 @@
-use crate::{MAXIMUM_CTAPHID_MESSAGE, MAXIMUM_CTAPHID_MESSAGE_X2};
+use crate::admin::KeyHandleFilter;
+use crate::outgoing::OutgoingMessage;
+use crate::{NotWebUsbError, OriginVerdict};
 use arrayvec::ArrayVec;
-use bbqueue::Producer;
 use core::iter;
 
+/// The longest U2F request that not-webusb handles: an authenticate request with a 255 byte key handle,
+/// using the extended length encoding for both Lc and Le.
+pub const MAXIMUM_REQUEST_LEN: usize = 4 + 3 + 32 + 32 + 1 + 255 + 2;
+
+/// The length of the signature that responses are smuggled in: an ASN.1 sequence of two 32 byte integers.
+const SIGNATURE_LEN: usize = 2 + 2 * (2 + 32);
+
+/// The signature of a request that its origin lacks the permissions for, see `NotWebUsbBuilder::required_permissions`.
+/// It is an empty ASN.1 sequence, so clients can tell it apart from both a response and the empty signature sent to filtered origins.
+pub const FORBIDDEN_SIGNATURE: [u8; 2] = [0x30, 0x00];
+
+/// The longest U2F response that not-webusb sends: an authenticate response.
+pub const MAXIMUM_RESPONSE_LEN: usize = 1 + 4 + SIGNATURE_LEN + 2;
+
+/// The longest version string that is sent in response to version requests, which must fit in `MAXIMUM_RESPONSE_LEN` along with the status word.
+pub const MAXIMUM_VERSION_LEN: usize = 64;
+
+/// A not-webusb key handle, received via a request that passed the `web_origin_filter`.
+/// It borrows from the request message, since a CTAP2 credential ID can be far longer than a U2F key handle.
+pub struct UserKeyHandle<'r> {
+    pub key_handle: &'r [u8],
+    /// The sha256 hash of the domain of the website that sent the key handle.
+    pub application_parameter: [u8; 32],
+}
+
+/// The outcome of receiving a complete U2F or CTAP2 request.
+pub enum Received<'r> {
+    /// The request has been answered, there is nothing more to do.
+    Answered,
+    /// The request carries a not-webusb key handle, which is left for the caller to answer.
+    UserKeyHandle(UserKeyHandle<'r>),
+    /// The `OriginFilter` asked the user about the origin of a CTAP2 request, which is left unanswered.
+    /// The request must be received again, asking the filter again, until it decides.
+    #[cfg(feature = "ctap2")]
+    AwaitingVerdict,
+}
+
 /// Receives and responds to incoming requests.
-/// If a tunnelled not-webusb request is present, instead of responding to it, the bytes of the tunneled request are returned.
-pub fn receive_user_request(
-    message_data: &[u8],
-    tx: &mut Producer<MAXIMUM_CTAPHID_MESSAGE_X2>,
-    web_origin_filter: &dyn Fn([u8; 32]) -> bool,
-) -> Option<ArrayVec<u8, 255>> {
-    let request = U2fRequest::decode(message_data);
+/// If a tunnelled not-webusb request is present, instead of responding to it, the key handle of the tunneled request is returned.
+pub fn receive_user_request<'r>(
+    message_data: &'r [u8],
+    tx: &mut OutgoingMessage,
+    web_origin_filter: &mut dyn KeyHandleFilter,
+    version: &'static str,
+) -> Result<Received<'r>, NotWebUsbError> {
+    let request = match U2fRequest::decode(message_data) {
+        Ok(request) => request,
+        Err(error) => {
+            warn!("received malformed u2f request {}", message_data);
+            write_response(tx, U2fResponse::Error(error))?;
+            return Ok(Received::Answered);
+        }
+    };
 
     match &request {
         U2fRequest::Authenticate {
@@ -20,10 +66,7 @@ pub fn receive_user_request(
             key_handle,
         } => info!(
             "received u2f request: authenticate control={} challenge_parameter={} application_parameter={} key_handle={}",
-            control,
-            challenge_parameter,
-            application_parameter,
-            key_handle.as_slice()
+            control, challenge_parameter, application_parameter, key_handle
         ),
         U2fRequest::Version => info!("received u2f request: version"),
         U2fRequest::Unknown { cla, ins } => {
@@ -41,19 +84,32 @@ pub fn receive_user_request(
             if let AuthenticateControl::CheckOnly = control {
                 // Actually indicates success.
                 U2fResponse::Error(MessageResponseError::ConditionsNotSatisfied)
-            } else if web_origin_filter(application_parameter) {
-                return Some(key_handle);
             } else {
-                // web_origin_filter failed, so send a valid response, but dont give any user data.
-                info!("authenticate request filtered by web_origin_filter");
-                U2fResponse::Authenticate {
-                    user_presence: true,
-                    counter: 0,
-                    signature: ArrayVec::new(),
+                match web_origin_filter.check(application_parameter, key_handle) {
+                    OriginVerdict::Allow => {
+                        return Ok(Received::UserKeyHandle(UserKeyHandle {
+                            key_handle,
+                            application_parameter,
+                        }));
+                    }
+                    OriginVerdict::Deny => {
+                        // web_origin_filter failed, so send a valid response, but dont give any user data.
+                        info!("authenticate request filtered by web_origin_filter");
+                        U2fResponse::Authenticate {
+                            user_presence: true,
+                            counter: 0,
+                            signature: ArrayVec::new(),
+                        }
+                    }
+                    OriginVerdict::Ask => {
+                        // The same response as while waiting for the user to touch the device, so the client retries.
+                        info!("authenticate request awaiting a verdict from web_origin_filter");
+                        U2fResponse::Error(MessageResponseError::ConditionsNotSatisfied)
+                    }
                 }
             }
         }
-        U2fRequest::Version => U2fResponse::Version,
+        U2fRequest::Version => U2fResponse::Version(version),
         U2fRequest::Unknown { cla, ins } => {
             warn!("unknown message request cla={} ins={}", cla, ins);
             if cla == 0 {
@@ -65,21 +121,21 @@ pub fn receive_user_request(
         }
     };
 
-    write_response(tx, response);
+    write_response(tx, response)?;
 
-    None
+    Ok(Received::Answered)
 }
 
 // TODO: pull header bytes out into lib.rs level logic
 pub fn send_user_response(
     response: &[u8],
     payload_written_bytes: &mut u32,
-    tx: &mut Producer<MAXIMUM_CTAPHID_MESSAGE_X2>,
-) {
+    tx: &mut OutgoingMessage,
+) -> Result<(), NotWebUsbError> {
     // the signature contains two asn.1 integers that we can smuggle data in.
     // They must be exactly 20 bytes each and must never be > 0, since they are signed integers this means starting with 0x7f
 
-    let mut signature: ArrayVec<u8, 255> = [
+    let mut signature: ArrayVec<u8, SIGNATURE_LEN> = [
         0x30, // ASN.1 sequence
         0x44, // Number of bytes in ASN.1 sequence
         0x02, // ASN.1 integer
@@ -147,19 +203,38 @@ pub fn send_user_response(
     write_response(tx, response)
 }
 
-fn write_response(tx: &mut Producer<MAXIMUM_CTAPHID_MESSAGE_X2>, response: U2fResponse) {
-    let mut granted = tx.grant_exact(MAXIMUM_CTAPHID_MESSAGE).unwrap();
+/// Answers a not-webusb key handle whose request requires permissions that its origin is not granted,
+/// with `FORBIDDEN_SIGNATURE` in place of a response.
+pub fn send_forbidden_response(tx: &mut OutgoingMessage) -> Result<(), NotWebUsbError> {
+    let response = U2fResponse::Authenticate {
+        user_presence: true,
+        counter: 0,
+        signature: FORBIDDEN_SIGNATURE.into_iter().collect(),
+    };
+    write_response(tx, response)
+}
+
+/// Responds to the current request with a U2F error status word.
+pub fn send_error_response(
+    tx: &mut OutgoingMessage,
+    error: MessageResponseError,
+) -> Result<(), NotWebUsbError> {
+    write_response(tx, U2fResponse::Error(error))
+}
+
+fn write_response(tx: &mut OutgoingMessage, response: U2fResponse) -> Result<(), NotWebUsbError> {
+    let mut granted = tx.grant()?;
     let size = response.encode(&mut granted);
     granted.commit(size);
+    Ok(())
 }
 
-#[allow(clippy::large_enum_variant)]
-pub enum U2fRequest {
+pub enum U2fRequest<'r> {
     Authenticate {
         control: AuthenticateControl,
         challenge_parameter: [u8; 32],
         application_parameter: [u8; 32],
-        key_handle: ArrayVec<u8, 255>,
+        key_handle: &'r [u8],
     },
     Version,
     Unknown {
@@ -168,44 +243,67 @@ pub enum U2fRequest {
     },
 }
 
-impl U2fRequest {
-    fn decode(message_data: &[u8]) -> Self {
-        let cla = message_data[0];
-        let ins = message_data[1];
-        let p1 = message_data[2];
-        let _p2 = message_data[3];
-
-        let (length, data_start) = if message_data[4] == 0 {
-            (
-                u16::from_be_bytes(message_data[5..7].try_into().unwrap()),
-                7,
-            )
-        } else {
-            (message_data[4] as u16, 5)
+impl<'r> U2fRequest<'r> {
+    /// Returns the status word to respond with if the request is malformed.
+    fn decode(message_data: &'r [u8]) -> Result<Self, MessageResponseError> {
+        let Some((&[cla, ins, p1, _p2], rest)) = message_data.split_first_chunk() else {
+            return Err(MessageResponseError::WrongLength);
         };
-        let body = &message_data[data_start..data_start + length as usize];
 
         match ins {
             0x02 => {
-                let key_handle_length = body[64];
-                let mut key_handle = [0; 255];
-                key_handle[0..key_handle_length as usize]
-                    .copy_from_slice(&body[65..65 + key_handle_length as usize]);
-                U2fRequest::Authenticate {
-                    control: AuthenticateControl::decode(p1),
-                    challenge_parameter: body[0..32].try_into().unwrap(),
-                    application_parameter: body[32..64].try_into().unwrap(),
-                    key_handle: ArrayVec::from_iter(
-                        key_handle.iter().copied().take(key_handle_length as usize),
-                    ),
+                let body = apdu_body(rest)?;
+                let Some((challenge_parameter, body)) = body.split_first_chunk() else {
+                    return Err(MessageResponseError::WrongLength);
+                };
+                let Some((application_parameter, body)) = body.split_first_chunk() else {
+                    return Err(MessageResponseError::WrongLength);
+                };
+                let Some((&key_handle_length, key_handle)) = body.split_first() else {
+                    return Err(MessageResponseError::WrongLength);
+                };
+                if key_handle.len() != key_handle_length as usize {
+                    return Err(MessageResponseError::WrongLength);
                 }
+                Ok(U2fRequest::Authenticate {
+                    control: AuthenticateControl::decode(p1),
+                    challenge_parameter: *challenge_parameter,
+                    application_parameter: *application_parameter,
+                    key_handle,
+                })
+            }
+            0x03 => {
+                apdu_body(rest)?;
+                Ok(U2fRequest::Version)
             }
-            0x03 => U2fRequest::Version,
-            _ => U2fRequest::Unknown { cla, ins },
+            _ => Ok(U2fRequest::Unknown { cla, ins }),
         }
     }
 }
 
+/// Returns the request data of an APDU, given everything after the 4 byte header.
+///
+/// The Lc and Le fields may use either the short or extended length encoding, and both are optional.
+fn apdu_body(rest: &[u8]) -> Result<&[u8], MessageResponseError> {
+    let (lc, data, le_len) = match rest {
+        // No Lc or Le.
+        [] => return Ok(&[]),
+        // Short Le only.
+        [_le] => return Ok(&[]),
+        // Extended Le only.
+        [0, _le1, _le2] => return Ok(&[]),
+        [0, lc1, lc2, data @ ..] => (u16::from_be_bytes([*lc1, *lc2]) as usize, data, 2),
+        [lc, data @ ..] => (*lc as usize, data, 1),
+    };
+    // The data must be exactly Lc bytes long, optionally followed by Le.
+    if data.len() == lc || data.len() == lc + le_len {
+        Ok(&data[..lc])
+    } else {
+        warn!("APDU Lc was {} but {} bytes followed it", lc, data.len());
+        Err(MessageResponseError::WrongLength)
+    }
+}
+
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum AuthenticateControl {
     CheckOnly,
@@ -230,10 +328,10 @@ pub enum U2fResponse {
     Authenticate {
         user_presence: bool,
         counter: u32,
-        signature: ArrayVec<u8, 255>, // TODO: There seems to be no maximum length, not sure what to do here.
+        signature: ArrayVec<u8, SIGNATURE_LEN>,
     },
     Error(MessageResponseError),
-    Version,
+    Version(&'static str),
 }
 
 impl U2fResponse {
@@ -267,14 +365,15 @@ impl U2fResponse {
 
                 2
             }
-            U2fResponse::Version => {
-                data[..6].copy_from_slice("U2F_V2".as_bytes());
+            U2fResponse::Version(version) => {
+                let len = version.len();
+                data[..len].copy_from_slice(version.as_bytes());
 
                 // success
-                data[6] = 0x90;
-                data[7] = 0x00;
+                data[len] = 0x90;
+                data[len + 1] = 0x00;
 
-                8
+                len + 2
             }
         }
     }
@@ -287,10 +386,10 @@ pub enum MessageResponseError {
     ConditionsNotSatisfied = 0x6985,
 
     /// The request was rejected due to an invalid key handle.
-    //WrongData = 0x6A80,
+    WrongData = 0x6A80,
 
     /// The length of the request was invalid.
-    //WrongLength = 0x6700,
+    WrongLength = 0x6700,
 
     /// The Class byte of the request is not supported.
     ClaNotSupported = 0x6E00,
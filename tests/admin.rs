@@ -0,0 +1,283 @@
+use embedded_storage::nor_flash::{
+    ErrorType, NorFlash, NorFlashErrorKind, ReadNorFlash, check_erase, check_read, check_write,
+};
+use not_webusb::{AdminRequest, AdminStatus, FlashAllowlist, OriginStore, origin_hash};
+use not_webusb_client::{ClientOptions, Error};
+use not_webusb_simulator::{SimulatedDevice, client_options};
+use pretty_assertions::assert_eq;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+const ADMIN: [u8; 32] = origin_hash!("admin.example.com");
+
+/// NOR flash held in memory, shared so that it outlives the allowlist stored in it.
+#[derive(Clone)]
+struct RamFlash {
+    bytes: Rc<RefCell<Vec<u8>>>,
+    /// When set, the number of writes that succeed before the device loses power and every later write fails.
+    writes_left: Rc<Cell<Option<usize>>>,
+}
+
+impl RamFlash {
+    fn new() -> Self {
+        RamFlash {
+            bytes: Rc::new(RefCell::new(vec![0xFF; 3 * Self::ERASE_SIZE])),
+            writes_left: Rc::new(Cell::new(None)),
+        }
+    }
+}
+
+impl ErrorType for RamFlash {
+    type Error = NorFlashErrorKind;
+}
+
+impl ReadNorFlash for RamFlash {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        check_read(self, offset, bytes.len())?;
+        let offset = offset as usize;
+        bytes.copy_from_slice(&self.bytes.borrow()[offset..offset + bytes.len()]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.bytes.borrow().len()
+    }
+}
+
+impl NorFlash for RamFlash {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = 4096;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        check_erase(self, from, to)?;
+        self.bytes.borrow_mut()[from as usize..to as usize].fill(0xFF);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        check_write(self, offset, bytes.len())?;
+        match self.writes_left.get() {
+            Some(0) => return Err(NorFlashErrorKind::Other),
+            Some(writes_left) => self.writes_left.set(Some(writes_left - 1)),
+            None => {}
+        }
+        let offset = offset as usize;
+        // Like real NOR flash, writing can only clear bits.
+        for (stored, byte) in self.bytes.borrow_mut()[offset..].iter_mut().zip(bytes) {
+            *stored &= byte;
+        }
+        Ok(())
+    }
+}
+
+fn admin_device(flash: RamFlash) -> SimulatedDevice {
+    let allowlist = FlashAllowlist::<_, 2>::new(flash, RamFlash::ERASE_SIZE as u32).unwrap();
+    let mut device =
+        SimulatedDevice::with_builder(allowlist, |builder| builder.admin_origin(ADMIN));
+    device.set_request_handler(|request| request.iter().copied().collect());
+    device
+}
+
+fn send(device: &mut SimulatedDevice, rp_id: &str, request: &[u8]) -> Result<Vec<u8>, Error> {
+    let options = ClientOptions {
+        rp_id: rp_id.to_owned(),
+        ..client_options()
+    };
+    device.client_with(options).read_write(request)
+}
+
+/// Sends `request` via U2F, whose short signatures split longer responses across several key handles.
+fn send_u2f(device: &mut SimulatedDevice, rp_id: &str, request: &[u8]) -> Result<Vec<u8>, Error> {
+    let options = ClientOptions {
+        rp_id: rp_id.to_owned(),
+        ctap2: false,
+        ..client_options()
+    };
+    device.client_with(options).read_write(request)
+}
+
+fn admin(device: &mut SimulatedDevice, request: AdminRequest) -> Vec<u8> {
+    send(device, "admin.example.com", &request.encode()).unwrap()
+}
+
+#[test]
+fn manage_origins() {
+    let flash = RamFlash::new();
+    let mut device = admin_device(flash.clone());
+    let app = origin_hash!("app.example.com");
+    let staging = origin_hash!("staging.example.com");
+
+    // No origin is allowed until origins are added.
+    // The admin origin can only send admin messages, it is not allowed to talk to the application.
+    let result = send(&mut device, "app.example.com", b"hi");
+    assert!(matches!(result, Err(Error::OriginRejected)), "{result:?}");
+    let result = send(&mut device, "admin.example.com", b"hi");
+    assert!(matches!(result, Err(Error::OriginRejected)), "{result:?}");
+    assert_eq!(
+        admin(&mut device, AdminRequest::List { start: 0 }),
+        [AdminStatus::Ok as u8]
+    );
+
+    assert_eq!(
+        admin(&mut device, AdminRequest::Add(app)),
+        [AdminStatus::Ok as u8]
+    );
+    assert_eq!(send(&mut device, "app.example.com", b"hi").unwrap(), b"hi");
+    assert_eq!(
+        admin(&mut device, AdminRequest::Add(staging)),
+        [AdminStatus::Ok as u8]
+    );
+    assert_eq!(
+        admin(&mut device, AdminRequest::Add(origin_hash!("example.org"))),
+        [AdminStatus::Failed as u8]
+    );
+
+    let listed = admin(&mut device, AdminRequest::List { start: 0 });
+    assert_eq!(listed[0], AdminStatus::Ok as u8);
+    assert_eq!(listed[1..], [app, staging].concat());
+    let listed = admin(&mut device, AdminRequest::List { start: 1 });
+    assert_eq!(listed[1..], staging);
+
+    // The admin origin can fetch the rest of a response spanning several key handles.
+    let listed = send_u2f(
+        &mut device,
+        "admin.example.com",
+        &AdminRequest::List { start: 0 }.encode(),
+    )
+    .unwrap();
+    assert_eq!(listed[1..], [app, staging].concat());
+
+    // Other origins cannot send admin messages, even when they are allowed.
+    assert_eq!(
+        send(
+            &mut device,
+            "app.example.com",
+            &AdminRequest::Remove(staging).encode()
+        )
+        .unwrap(),
+        [AdminStatus::Forbidden as u8]
+    );
+
+    assert_eq!(
+        admin(&mut device, AdminRequest::Remove(app)),
+        [AdminStatus::Ok as u8]
+    );
+    let result = send(&mut device, "app.example.com", b"hi");
+    assert!(matches!(result, Err(Error::OriginRejected)), "{result:?}");
+
+    // The admin origin talks to the application once it is allowed like any other origin.
+    assert_eq!(
+        admin(&mut device, AdminRequest::Add(ADMIN)),
+        [AdminStatus::Ok as u8]
+    );
+    assert_eq!(
+        send(&mut device, "admin.example.com", b"hi").unwrap(),
+        b"hi"
+    );
+
+    // The origins are loaded from flash after a reset.
+    drop(device);
+    let allowlist = FlashAllowlist::<_, 2>::new(flash, RamFlash::ERASE_SIZE as u32).unwrap();
+    assert_eq!(allowlist.origins(), [staging, ADMIN]);
+}
+
+#[test]
+fn interrupted_save() {
+    let flash = RamFlash::new();
+    let offset = RamFlash::ERASE_SIZE as u32;
+    let app = origin_hash!("app.example.com");
+    let staging = origin_hash!("staging.example.com");
+    // Enough origins that each copy spans several writes.
+    let mut allowlist = FlashAllowlist::<_, 16>::new(flash.clone(), offset).unwrap();
+    assert_eq!(
+        FlashAllowlist::<RamFlash, 16>::region_len(),
+        2 * RamFlash::ERASE_SIZE
+    );
+    for index in 0..10 {
+        assert!(allowlist.insert([index; 32]));
+    }
+    assert!(allowlist.insert(app));
+    let saved = allowlist.origins().to_vec();
+
+    // Power is lost partway through writing the next copy, the previous copy is loaded instead.
+    for writes_left in 0..2 {
+        flash.writes_left.set(Some(writes_left));
+        assert!(!allowlist.insert(staging));
+        assert_eq!(allowlist.origins(), saved);
+        flash.writes_left.set(None);
+        allowlist = FlashAllowlist::new(flash.clone(), offset).unwrap();
+        assert_eq!(allowlist.origins(), saved);
+    }
+
+    // Later changes are written over the incomplete copy, and win over the previous one.
+    assert!(allowlist.insert(staging));
+    assert!(OriginStore::remove(&mut allowlist, app));
+    assert!(allowlist.insert(app));
+    let allowlist = FlashAllowlist::<_, 16>::new(flash.clone(), offset).unwrap();
+    assert_eq!(allowlist.origins()[10..], [staging, app]);
+
+    // Copies that fail their checksum are ignored.
+    for slot in 0..2 {
+        flash.bytes.borrow_mut()[offset as usize + slot * RamFlash::ERASE_SIZE + 32] ^= 1;
+    }
+    let allowlist = FlashAllowlist::<_, 16>::new(flash, offset).unwrap();
+    assert_eq!(allowlist.origins(), [] as [[u8; 32]; 0]);
+}
+
+#[test]
+fn malformed_admin_message() {
+    let mut device = admin_device(RamFlash::new());
+    let mut request = AdminRequest::Add([1; 32]).encode();
+    request.pop();
+    assert_eq!(
+        send(&mut device, "admin.example.com", &request).unwrap(),
+        [AdminStatus::Malformed as u8]
+    );
+}
+
+#[test]
+fn unsupported_filter() {
+    let mut device =
+        SimulatedDevice::with_builder(&|_| true, |builder| builder.admin_origin(ADMIN));
+    device.set_request_handler(|request| request.iter().copied().collect());
+    assert_eq!(
+        admin(&mut device, AdminRequest::Add([1; 32])),
+        [AdminStatus::Unsupported as u8]
+    );
+}
+
+#[test]
+fn no_admin_origin() {
+    // Without an admin origin, admin messages are passed on to the application like any other request.
+    let mut device = SimulatedDevice::<1024>::new(&|_| true);
+    device.set_request_handler(|request| request.iter().copied().collect());
+    let request = AdminRequest::List { start: 0 }.encode();
+    assert_eq!(
+        admin(&mut device, AdminRequest::List { start: 0 }),
+        request.as_slice()
+    );
+}
+
+#[test]
+fn encoding() {
+    for request in [
+        AdminRequest::List { start: 513 },
+        AdminRequest::Add([7; 32]),
+        AdminRequest::Remove([9; 32]),
+    ] {
+        assert_eq!(AdminRequest::decode(&request.encode()), Some(request));
+    }
+    assert_eq!(AdminRequest::decode(b"hi"), None);
+    for status in [
+        AdminStatus::Ok,
+        AdminStatus::Forbidden,
+        AdminStatus::Malformed,
+        AdminStatus::Unsupported,
+        AdminStatus::Failed,
+    ] {
+        assert_eq!(AdminStatus::from_byte(status as u8), Some(status));
+    }
+    assert_eq!(AdminStatus::from_byte(5), None);
+}
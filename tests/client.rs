@@ -0,0 +1,91 @@
+use not_webusb_client::ClientOptions;
+use not_webusb_client::u2f::ApduEncoding;
+use not_webusb_simulator::{SimulatedDevice, client_options};
+use pretty_assertions::assert_eq;
+use proptest::collection::vec;
+use proptest::prelude::*;
+use proptest::test_runner::TestRunner;
+use std::cell::RefCell;
+
+const MAX_MESSAGE_LEN: usize = 1024;
+
+fn options(apdu_encoding: ApduEncoding) -> ClientOptions {
+    ClientOptions {
+        apdu_encoding,
+        ..client_options()
+    }
+}
+
+// All cases share one device, which also checks that the device state is correctly reset between requests.
+#[test]
+fn round_trip() {
+    let mut device = SimulatedDevice::<MAX_MESSAGE_LEN>::new(&|_| true);
+    device.set_request_handler(|request| request.iter().rev().copied().collect());
+    let device = RefCell::new(device);
+
+    let round_trip = |request: &[u8], apdu_encoding| {
+        let mut device = device.borrow_mut();
+        let mut client = device.client_with(options(apdu_encoding));
+        let response = client.read_write(request).unwrap();
+        let expected: Vec<u8> = request.iter().rev().copied().collect();
+        assert_eq!(response, expected);
+    };
+
+    // The request and response lengths where an extra key handle or signature is needed.
+    for len in [
+        0,
+        1,
+        57,
+        58,
+        59,
+        119,
+        120,
+        253,
+        254,
+        255,
+        508,
+        509,
+        MAX_MESSAGE_LEN,
+    ] {
+        let request: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        round_trip(&request, ApduEncoding::Extended);
+        round_trip(&request, ApduEncoding::ShortWhenPossible);
+    }
+
+    // The signature from the hardware test in `tests/test.rs`, which was truncated, is now fully received.
+    round_trip(
+        b"abcdefghijklmnopqrstuvwxyzabcdefghijklmnopqrstuvwxyzabcdefghijklmnopqrstuvwxyz",
+        ApduEncoding::Extended,
+    );
+
+    let strategy = (
+        vec(any::<u8>(), 0..=MAX_MESSAGE_LEN),
+        prop_oneof![
+            Just(ApduEncoding::Extended),
+            Just(ApduEncoding::ShortWhenPossible)
+        ],
+    );
+    TestRunner::default()
+        .run(&strategy, |(request, apdu_encoding)| {
+            round_trip(&request, apdu_encoding);
+            Ok(())
+        })
+        .unwrap();
+}
+
+#[test]
+fn multiple_devices() {
+    let mut reverse = SimulatedDevice::<MAX_MESSAGE_LEN>::new(&|_| true);
+    reverse.set_request_handler(|request| request.iter().rev().copied().collect());
+    let mut upper = SimulatedDevice::<MAX_MESSAGE_LEN>::new(&|_| true);
+    upper.set_request_handler(|request| request.to_ascii_uppercase().into_iter().collect());
+
+    // Each device has its own storage, so alternating between them does not mix up their messages.
+    let request = [b'a'; 200];
+    let mut reverse_client = reverse.client_with(options(ApduEncoding::Extended));
+    let mut upper_client = upper.client_with(options(ApduEncoding::Extended));
+    assert_eq!(upper_client.read_write(b"hello").unwrap(), b"HELLO");
+    assert_eq!(reverse_client.read_write(b"hello").unwrap(), b"olleh");
+    assert_eq!(upper_client.read_write(&request).unwrap(), [b'A'; 200]);
+    assert_eq!(reverse_client.read_write(&request).unwrap(), request);
+}
@@ -0,0 +1,345 @@
+use not_webusb::{Duration, NotWebUsbError, OriginFilter, OriginVerdict, Timeouts};
+use not_webusb_client::framing::{MAX_CREDENTIAL_ID_LEN, RequestHeader};
+use not_webusb_client::{Client, ClientOptions, Error, Transport, ctap2, ctaphid, u2f};
+use not_webusb_simulator::{SimulatedDevice, client_options};
+use pretty_assertions::assert_eq;
+use serde_cbor::Value;
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+const MAX_MESSAGE_LEN: usize = 4096;
+const STATUS_INVALID_COMMAND: u8 = 0x01;
+const STATUS_INVALID_CBOR: u8 = 0x12;
+const STATUS_NO_CREDENTIALS: u8 = 0x2E;
+
+fn options(ctap2: bool) -> ClientOptions {
+    ClientOptions {
+        ctap2,
+        ..client_options()
+    }
+}
+
+fn reverse(request: &[u8]) -> Vec<u8> {
+    request.iter().rev().copied().collect()
+}
+
+/// Collects the next message sent by the device, returning its cmd and payload.
+fn receive_message(device: &mut SimulatedDevice<MAX_MESSAGE_LEN>) -> (u8, Vec<u8>) {
+    let initial = device.poll_until_report(100).unwrap().unwrap().packet;
+    let len = u16::from_be_bytes([initial[5], initial[6]]) as usize;
+    let mut payload = initial[7..].to_vec();
+    while payload.len() < len {
+        let continuation = device.poll_until_report(100).unwrap().unwrap().packet;
+        payload.extend_from_slice(&continuation[5..]);
+    }
+    payload.truncate(len);
+    (initial[4], payload)
+}
+
+// All cases share one device, which also checks that the device state is correctly reset between requests.
+#[test]
+fn round_trip() {
+    let mut device = SimulatedDevice::<MAX_MESSAGE_LEN>::new(&|_| true);
+    device.set_request_handler(|request| reverse(request).into_iter().collect());
+
+    // The request and response lengths where an extra key handle or signature is needed,
+    // both for the first key handle, which is limited to U2F length, and for later key handles.
+    for len in [
+        0, 1, 253, 254, 255, 1017, 1018, 1019, 1275, 1276, 2000, 4096,
+    ] {
+        let request: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        for ctap2 in [true, false] {
+            let mut client = device.client_with(options(ctap2));
+            assert_eq!(client.uses_ctap2(), ctap2);
+            assert_eq!(client.read_write(&request).unwrap(), reverse(&request));
+        }
+    }
+    assert!(device.take_errors().is_empty());
+}
+
+#[test]
+fn get_info() {
+    let mut device = SimulatedDevice::<MAX_MESSAGE_LEN>::new(&|_| true);
+    let (_, response) = ctaphid::transact(
+        &mut device,
+        ctaphid::BROADCAST_CID,
+        ctaphid::CMD_INIT,
+        &[0; 8],
+    )
+    .unwrap();
+    // CBOR support is advertised in the capabilities, otherwise browsers never send GetInfo.
+    assert_eq!(response[12..17], [2, 0, 0, 0, 0x04]);
+
+    let cid = ctaphid::init(&mut device).unwrap();
+    let (_, response) = ctaphid::transact(
+        &mut device,
+        cid,
+        ctaphid::CMD_CBOR,
+        &ctap2::get_info_request(),
+    )
+    .unwrap();
+    assert!(ctap2::supports_fido_2_0(&response));
+
+    let info: BTreeMap<u8, serde_cbor::Value> = serde_cbor::from_slice(&response[1..]).unwrap();
+    assert_eq!(
+        info.keys().copied().collect::<Vec<_>>(),
+        [0x01, 0x03, 0x04, 0x05, 0x08]
+    );
+    let options = Value::Map(BTreeMap::from([
+        (Value::Text("rk".into()), Value::Bool(false)),
+        (Value::Text("up".into()), Value::Bool(true)),
+    ]));
+    assert_eq!(info[&0x04], options);
+    // The longest request, a getAssertion with a credential ID of the advertised maximum length, must fit in maxMsgSize.
+    assert_eq!(info[&0x08], Value::Integer(MAX_CREDENTIAL_ID_LEN as i128));
+    let Value::Integer(max_msg_size) = info[&0x05] else {
+        panic!("maxMsgSize is {:?}", info[&0x05]);
+    };
+    let request = ctap2::get_assertion_request(
+        &ClientOptions::default().rp_id,
+        &[0; 32],
+        &[0; MAX_CREDENTIAL_ID_LEN],
+    );
+    assert!(request.len() as i128 <= max_msg_size);
+}
+
+#[test]
+fn ctap2_disabled() {
+    let mut device =
+        SimulatedDevice::<MAX_MESSAGE_LEN>::with_builder(&|_| true, |builder| builder.ctap2(false));
+    device.set_request_handler(|request| reverse(request).into_iter().collect());
+
+    // The init response does not advertise CBOR support.
+    let (_, response) = ctaphid::transact(
+        &mut device,
+        ctaphid::BROADCAST_CID,
+        ctaphid::CMD_INIT,
+        &[0; 8],
+    )
+    .unwrap();
+    assert_eq!(response[12..17], [2, 0, 0, 0, 0]);
+
+    let mut client = device.client_with(options(true));
+    assert!(!client.uses_ctap2());
+    assert_eq!(client.read_write(b"hello").unwrap(), b"olleh");
+    assert!(matches!(
+        client.get_assertion(&[RequestHeader::FinalRequest as u8]),
+        Err(Error::Ctap2(STATUS_INVALID_COMMAND))
+    ));
+}
+
+#[test]
+fn other_commands() {
+    let mut device = SimulatedDevice::<MAX_MESSAGE_LEN>::new(&|_| true);
+    let cid = ctaphid::init(&mut device).unwrap();
+    for (request, status) in [
+        // makeCredential
+        (&[0x01, 0xA0][..], 0x27),
+        // reset
+        (&[0x07][..], 0x00),
+        // getNextAssertion
+        (&[0x08][..], 0x30),
+        // selection
+        (&[0x0B][..], 0x00),
+        // clientPin
+        (&[0x06, 0xA0][..], STATUS_INVALID_COMMAND),
+    ] {
+        let (_, response) =
+            ctaphid::transact(&mut device, cid, ctaphid::CMD_CBOR, request).unwrap();
+        assert_eq!(response, [status]);
+    }
+}
+
+#[test]
+fn long_key_handles() {
+    let mut device = SimulatedDevice::<MAX_MESSAGE_LEN>::new(&|_| true);
+    device.set_request_handler(|request| reverse(request).into_iter().collect());
+    let mut client = device.client_with(options(true));
+
+    // The device advertises the longest credential ID it accepts in the signCount.
+    let mut key_handle = vec![RequestHeader::FinalRequest as u8];
+    key_handle.extend([b'a'; MAX_CREDENTIAL_ID_LEN - 1]);
+    let assertion = client.get_assertion(&key_handle).unwrap();
+    assert_eq!(assertion.authenticator_data.len(), 37);
+    assert_eq!(
+        assertion.authenticator_data[..32],
+        u2f::rp_id_hash(&ClientOptions::default().rp_id)
+    );
+    assert_eq!(
+        assertion.authenticator_data[33..],
+        (MAX_CREDENTIAL_ID_LEN as u32).to_be_bytes()
+    );
+
+    // The response is longer than a single signature, so the rest of it must be requested.
+    let assertion = client
+        .get_assertion(&[RequestHeader::NeedMoreResponseData as u8])
+        .unwrap();
+    assert!(!assertion.signature.is_empty());
+    assert_eq!(client.read_write(b"hello").unwrap(), b"olleh");
+}
+
+#[test]
+fn origin_rejected() {
+    let mut device = SimulatedDevice::<MAX_MESSAGE_LEN>::new(&|_| false);
+    let mut client = device.client_with(options(true));
+    assert!(matches!(
+        client.read_write(b"hello"),
+        Err(Error::OriginRejected)
+    ));
+}
+
+// All scenarios share one device, so after every error the device must still work.
+#[test]
+fn invalid_requests() {
+    let mut device = SimulatedDevice::<MAX_MESSAGE_LEN>::new(&|_| true);
+    device.set_request_handler(|request| request.iter().copied().collect());
+
+    // more response data requested when there is no response
+    let result = Client::new(&mut device, options(true))
+        .unwrap()
+        .get_assertion(&[RequestHeader::NeedMoreResponseData as u8]);
+    assert!(
+        matches!(result, Err(Error::Ctap2(STATUS_NO_CREDENTIALS))),
+        "{result:?}"
+    );
+    let errors = device.take_errors();
+    assert!(
+        matches!(errors[..], [NotWebUsbError::ProtocolViolation]),
+        "{errors:?}"
+    );
+
+    // malformed CBOR and unsupported commands
+    let cid = ctaphid::init(&mut device).unwrap();
+    for (request, status) in [
+        (&[0x02, 0xFF][..], STATUS_INVALID_CBOR),
+        (&[0x02, 0xA0][..], 0x14),
+        (&[0x40][..], STATUS_INVALID_COMMAND),
+    ] {
+        let (_, response) =
+            ctaphid::transact(&mut device, cid, ctaphid::CMD_CBOR, request).unwrap();
+        assert_eq!(response, [status]);
+    }
+
+    let mut client = device.client_with(options(true));
+    assert_eq!(client.read_write(b"hello").unwrap(), b"hello");
+    assert!(device.take_errors().is_empty());
+}
+
+#[test]
+fn slow_response() {
+    let timeouts = Timeouts {
+        transaction: Duration::secs(1),
+        user_data: Duration::secs(30),
+    };
+    let mut device = SimulatedDevice::<MAX_MESSAGE_LEN>::with_timeouts(&|_| true, timeouts);
+    let cid = ctaphid::init(&mut device).unwrap();
+    let request = ctap2::get_assertion_request(
+        &ClientOptions::default().rp_id,
+        &[0; 32],
+        &[RequestHeader::FinalRequest as u8, 1, 2, 3],
+    );
+    for packet in ctaphid::encode_message(cid, ctaphid::CMD_CBOR, &request).unwrap() {
+        device.write_report(&packet).unwrap();
+    }
+
+    // The request is held open while the application prepares its response, with keepalives sent meanwhile.
+    assert_eq!(device.poll_until_report(100).unwrap(), None);
+    assert_eq!(
+        device
+            .not_webusb()
+            .check_pending_request()
+            .map(|request| request.payload),
+        Some(&[1, 2, 3][..])
+    );
+    for _ in 0..3 {
+        device.advance_time(Duration::millis(100));
+        let keepalive = device.poll_until_report(100).unwrap().unwrap().packet;
+        assert_eq!(keepalive[0..4], cid.to_be_bytes());
+        assert_eq!(keepalive[4..8], [0xBB, 0, 1, 0x01]);
+    }
+    // The stalled transaction timeout does not apply, since the device is the one holding things up.
+    device.advance_time(Duration::secs(2));
+    assert_eq!(device.poll().map_err(|err| format!("{err:?}")), Ok(()));
+
+    device
+        .not_webusb()
+        .send_response_from_slice(b"slow")
+        .unwrap();
+    let (cmd, response) = loop {
+        let (cmd, response) = receive_message(&mut device);
+        if cmd != 0xBB {
+            break (cmd, response);
+        }
+    };
+    assert_eq!(cmd, 0x90);
+    let assertion = ctap2::parse_get_assertion_response(&response).unwrap();
+    let mut exchange = not_webusb_client::framing::Exchange::new(&[1, 2, 3]);
+    exchange
+        .push_assertion(&assertion.authenticator_data, &assertion.signature)
+        .unwrap();
+    assert_eq!(exchange.next_key_handle(), None);
+    assert_eq!(exchange.into_response(), b"slow");
+}
+
+/// Returns whatever verdict the test has set.
+struct SharedVerdict(Rc<Cell<OriginVerdict>>);
+
+impl OriginFilter for SharedVerdict {
+    fn check(&mut self, _origin_hash: [u8; 32]) -> OriginVerdict {
+        self.0.get()
+    }
+}
+
+#[test]
+fn origin_verdict_pending() {
+    let verdict = Rc::new(Cell::new(OriginVerdict::Ask));
+    let mut device = SimulatedDevice::<MAX_MESSAGE_LEN>::with_timeouts(
+        SharedVerdict(verdict.clone()),
+        Timeouts::default(),
+    );
+    device.set_request_handler(|request| reverse(request).into_iter().collect());
+    let cid = ctaphid::init(&mut device).unwrap();
+    let request = ctap2::get_assertion_request(
+        &ClientOptions::default().rp_id,
+        &[0; 32],
+        &[RequestHeader::FinalRequest as u8, 1, 2, 3],
+    );
+    for packet in ctaphid::encode_message(cid, ctaphid::CMD_CBOR, &request).unwrap() {
+        device.write_report(&packet).unwrap();
+    }
+
+    // The request is held open while the filter waits on the user, with keepalives asking for user presence.
+    assert_eq!(device.poll_until_report(100).unwrap(), None);
+    for _ in 0..3 {
+        device.advance_time(Duration::millis(100));
+        let keepalive = device.poll_until_report(100).unwrap().unwrap().packet;
+        assert_eq!(keepalive[0..4], cid.to_be_bytes());
+        assert_eq!(keepalive[4..8], [0xBB, 0, 1, 0x02]);
+    }
+    // The filter is asked again, without the request being sent again.
+    assert!(device.not_webusb().check_pending_request().is_none());
+    verdict.set(OriginVerdict::Allow);
+    let (cmd, response) = loop {
+        let (cmd, response) = receive_message(&mut device);
+        if cmd != 0xBB {
+            break (cmd, response);
+        }
+    };
+    assert_eq!(cmd, 0x90);
+    let assertion = ctap2::parse_get_assertion_response(&response).unwrap();
+    let mut exchange = not_webusb_client::framing::Exchange::new(&[1, 2, 3]);
+    exchange
+        .push_assertion(&assertion.authenticator_data, &assertion.signature)
+        .unwrap();
+    assert_eq!(exchange.into_response(), [3, 2, 1]);
+
+    // A denied origin is answered like any other filtered request.
+    verdict.set(OriginVerdict::Deny);
+    let mut client = device.client_with(options(true));
+    assert!(matches!(
+        client.read_write(b"hello"),
+        Err(Error::OriginRejected)
+    ));
+    assert!(device.take_errors().is_empty());
+}
@@ -0,0 +1,112 @@
+use not_webusb::{Duration, Timeouts};
+use not_webusb_client::{Transport, ctaphid};
+use not_webusb_simulator::SimulatedEmbassyDevice;
+use pretty_assertions::assert_eq;
+#[cfg(feature = "ctap2")]
+use {
+    not_webusb::{OriginFilter, OriginVerdict},
+    not_webusb_client::ctap2,
+    std::{cell::Cell, rc::Rc},
+};
+
+fn round_trip(device: &mut SimulatedEmbassyDevice, request: &[u8]) {
+    let mut client = device.client();
+    let response = client.read_write(request).unwrap();
+    let expected: Vec<u8> = request.iter().rev().copied().collect();
+    assert_eq!(response, expected);
+}
+
+// The embassy-time mock clock is global, so all scenarios share one device.
+#[test]
+fn embassy() {
+    let timeouts = Timeouts {
+        transaction: Duration::secs(1),
+        user_data: Duration::secs(30),
+    };
+    let mut device =
+        SimulatedEmbassyDevice::with_timeouts::<1024>(&|_| true, timeouts, |request| {
+            request.iter().rev().copied().collect()
+        });
+
+    // Requests and responses that fit in a single key handle/signature, and those that do not.
+    for len in [0, 1, 58, 254, 255, 1024] {
+        let request: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        round_trip(&mut device, &request);
+    }
+
+    // The client only sends the first packet of a multi packet message.
+    let cid = ctaphid::init(&mut device).unwrap();
+    let packets = ctaphid::encode_message(cid, ctaphid::CMD_MSG, &[0; 100]).unwrap();
+    device.write_report(&packets[0]).unwrap();
+    assert_eq!(device.poll_until_report(100), None);
+    // The device stops waiting on the client to check for stalled transactions.
+    device.advance_time(Duration::millis(1100));
+    let error = device.poll_until_report(100).unwrap().packet;
+    assert_eq!(error[0..4], cid.to_be_bytes());
+    assert_eq!(error[4..8], [0xBF, 0, 1, 0x05]);
+    round_trip(&mut device, b"hello");
+
+    #[cfg(feature = "ctap2")]
+    ask_user();
+}
+
+/// An `OriginFilter` that allows every origin once it has been asked more than `asks` times.
+#[cfg(feature = "ctap2")]
+struct AskUser {
+    asked: Rc<Cell<u32>>,
+    asks: u32,
+}
+
+#[cfg(feature = "ctap2")]
+impl OriginFilter for AskUser {
+    fn check(&mut self, _origin_hash: [u8; 32]) -> OriginVerdict {
+        self.asked.set(self.asked.get() + 1);
+        if self.asked.get() > self.asks {
+            OriginVerdict::Allow
+        } else {
+            OriginVerdict::Ask
+        }
+    }
+}
+
+// A getAssertion held open by `OriginVerdict::Ask` is checked again while the client sends nothing, even without timeouts.
+#[cfg(feature = "ctap2")]
+fn ask_user() {
+    let asked = Rc::new(Cell::new(0));
+    let filter = AskUser {
+        asked: asked.clone(),
+        asks: 5,
+    };
+    let mut device = SimulatedEmbassyDevice::new::<1024>(filter, |request| {
+        request.iter().rev().copied().collect()
+    });
+
+    let cid = ctaphid::init(&mut device).unwrap();
+    let request = ctap2::get_assertion_request("localhost", &[0; 32], b"\x02hi");
+    for packet in ctaphid::encode_message(cid, ctaphid::CMD_CBOR, &request).unwrap() {
+        device.write_report(&packet).unwrap();
+    }
+    assert_eq!(device.poll_until_report(100), None);
+    // The device now waits for a report from the client, or for time to pass.
+    let asked_before = asked.get();
+    assert!(asked_before <= 5);
+    assert_eq!(device.poll_until_report(100), None);
+    assert_eq!(asked.get(), asked_before);
+
+    let mut response = None;
+    for _ in 0..10 {
+        device.advance_time(Duration::millis(100));
+        response = device.poll_until_report(100);
+        if response.is_some() {
+            break;
+        }
+    }
+    let response = response.expect("the filter was not asked again").packet;
+    assert_eq!(asked.get(), 6);
+    assert_eq!(response[0..4], cid.to_be_bytes());
+    assert_eq!(response[4], 0x80 | ctaphid::CMD_CBOR);
+    // CTAP2 status success
+    assert_eq!(response[7], 0x00);
+    while device.poll_until_report(100).is_some() {}
+    round_trip(&mut device, b"hello");
+}
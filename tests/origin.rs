@@ -0,0 +1,305 @@
+use arrayvec::ArrayVec;
+use embedded_hal::digital::{ErrorType, InputPin};
+use not_webusb::{
+    ButtonPairing, Duration, Instant, OriginAllowlist, OriginFilter, OriginPolicy, OriginVerdict,
+    origin_hash,
+};
+use not_webusb_client::{ClientOptions, Error, u2f};
+use not_webusb_simulator::{SimulatedDevice, client_options};
+use pretty_assertions::assert_eq;
+use std::cell::Cell;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+
+#[test]
+fn sha256() {
+    // Test vectors from FIPS 180-2, covering the padding spilling into a second block.
+    assert_eq!(
+        origin_hash(""),
+        hex("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855")
+    );
+    assert_eq!(
+        origin_hash("abc"),
+        hex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+    );
+    assert_eq!(
+        origin_hash("abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+        hex("248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1")
+    );
+
+    // The same hash that clients send as the application parameter.
+    const HASH: [u8; 32] = not_webusb::origin_hash!("rukai.github.io");
+    assert_eq!(HASH, u2f::rp_id_hash("rukai.github.io"));
+}
+
+fn hex(hex: &str) -> [u8; 32] {
+    std::array::from_fn(|i| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).unwrap())
+}
+
+#[test]
+fn allowlist() {
+    static ALLOWLIST: OriginAllowlist<2> =
+        OriginAllowlist::new(["example.com", "staging.example.com"]);
+    assert!(ALLOWLIST.allows(origin_hash("staging.example.com")));
+    assert!(!ALLOWLIST.allows(origin_hash("example.org")));
+
+    let mut device = SimulatedDevice::<1024>::new(&|origin_hash| ALLOWLIST.allows(origin_hash));
+    device.set_request_handler(|request| request.iter().copied().collect());
+    for (rp_id, allowed) in [
+        ("example.com", true),
+        ("staging.example.com", true),
+        ("localhost", false),
+    ] {
+        let options = ClientOptions {
+            rp_id: rp_id.to_owned(),
+            ..client_options()
+        };
+        let result = device.client_with(options).read_write(b"hi");
+        if allowed {
+            assert_eq!(result.unwrap(), b"hi");
+        } else {
+            assert!(matches!(result, Err(Error::OriginRejected)), "{result:?}");
+        }
+    }
+}
+
+#[test]
+fn policy_permissions() {
+    const WRITE: u32 = 1 << 0;
+    static POLICY: OriginPolicy<2> =
+        OriginPolicy::new([("viewer.example.com", 0), ("editor.example.com", WRITE)]);
+    // Requests starting with `w` write, and must not reach the application from origins that cannot.
+    let required_permissions: &'static dyn Fn(&[u8]) -> u32 =
+        &|request| if request.starts_with(b"w") { WRITE } else { 0 };
+    let mut device = SimulatedDevice::<1024>::with_builder(POLICY, |builder| {
+        builder.required_permissions(required_permissions)
+    });
+    let handled = Rc::new(Cell::new(0));
+    let device_handled = handled.clone();
+    device.set_request_handler(move |request| {
+        device_handled.set(device_handled.get() + 1);
+        request.iter().copied().collect()
+    });
+    for ctap2 in [false, true] {
+        let mut send = |rp_id: &str, request: &[u8]| {
+            let options = ClientOptions {
+                rp_id: rp_id.to_owned(),
+                ctap2,
+                ..client_options()
+            };
+            device.client_with(options).read_write(request)
+        };
+        assert_eq!(send("viewer.example.com", b"read").unwrap(), b"read");
+        let result = send("viewer.example.com", b"write");
+        assert!(matches!(result, Err(Error::Forbidden)), "{result:?}");
+        assert_eq!(send("editor.example.com", b"write").unwrap(), b"write");
+        let result = send("example.org", b"read");
+        assert!(matches!(result, Err(Error::OriginRejected)), "{result:?}");
+    }
+    assert_eq!(handled.get(), 4);
+    assert!(device.take_errors().is_empty());
+}
+
+#[test]
+fn request_origin() {
+    static ALLOWLIST: OriginAllowlist<2> =
+        OriginAllowlist::new(["example.com", "staging.example.com"]);
+    let channels = Arc::new(Mutex::new(vec![]));
+    let mut device = SimulatedDevice::<1024>::new(&|origin_hash| ALLOWLIST.allows(origin_hash));
+    let device_channels = channels.clone();
+    device.set_poll_handler(move |not_webusb| {
+        let Some(request) = not_webusb.check_pending_request() else {
+            return;
+        };
+        device_channels.lock().unwrap().push(request.cid);
+        let response: &[u8] = if request.origin_hash == origin_hash!("staging.example.com") {
+            b"staging"
+        } else {
+            b"production"
+        };
+        not_webusb.send_response_from_slice(response).unwrap();
+    });
+
+    for (rp_id, expected) in [
+        ("example.com", &b"production"[..]),
+        ("staging.example.com", &b"staging"[..]),
+    ] {
+        let options = ClientOptions {
+            rp_id: rp_id.to_owned(),
+            ..client_options()
+        };
+        let mut client = device.client_with(options);
+        let cid = client.cid();
+        assert_eq!(client.read_write(b"hi").unwrap(), expected);
+        assert_eq!(channels.lock().unwrap().pop(), Some(cid));
+    }
+}
+
+/// Asks the user about every origin, deciding on `verdict` once asked `asks` times.
+struct AskUser {
+    asked: Rc<Cell<u32>>,
+    asks: u32,
+    verdict: OriginVerdict,
+}
+
+impl OriginFilter for AskUser {
+    fn check(&mut self, _origin_hash: [u8; 32]) -> OriginVerdict {
+        self.asked.set(self.asked.get() + 1);
+        if self.asked.get() > self.asks {
+            self.verdict
+        } else {
+            OriginVerdict::Ask
+        }
+    }
+}
+
+#[test]
+fn ask_user() {
+    for verdict in [OriginVerdict::Allow, OriginVerdict::Deny] {
+        let asked = Rc::new(Cell::new(0));
+        let filter = AskUser {
+            asked: asked.clone(),
+            asks: 3,
+            verdict,
+        };
+        let mut device = SimulatedDevice::<1024>::new(filter);
+        device.set_request_handler(|request| request.iter().copied().collect());
+
+        // The client keeps retrying, as if waiting for the user to touch the device, until the filter decides.
+        let result = device.client().read_write(b"hi");
+        match verdict {
+            OriginVerdict::Deny => {
+                assert!(matches!(result, Err(Error::OriginRejected)), "{result:?}")
+            }
+            _ => assert_eq!(result.unwrap(), b"hi"),
+        }
+        assert!(asked.get() > 3);
+    }
+}
+
+#[test]
+fn stateful_filter() {
+    // A closure filter can keep state, here rejecting only the very first key handle.
+    let mut attempts = 0;
+    let filter = move |_| {
+        attempts += 1;
+        attempts > 1
+    };
+    let mut device = SimulatedDevice::<1024>::new(filter);
+    device.set_request_handler(|request| request.iter().copied().collect());
+    let result = device.client().read_write(b"hi");
+    assert!(matches!(result, Err(Error::OriginRejected)), "{result:?}");
+    for _ in 0..2 {
+        let mut client = device.client();
+        assert_eq!(client.read_write(b"hi").unwrap(), b"hi");
+    }
+}
+
+/// A button that reads as pressed from its `pressed_after`th read onwards, if ever.
+struct Button {
+    reads: Rc<Cell<u32>>,
+    pressed_after: Option<u32>,
+}
+
+impl ErrorType for Button {
+    type Error = core::convert::Infallible;
+}
+
+impl InputPin for Button {
+    fn is_high(&mut self) -> Result<bool, Self::Error> {
+        self.reads.set(self.reads.get() + 1);
+        Ok(self
+            .pressed_after
+            .is_some_and(|pressed_after| self.reads.get() >= pressed_after))
+    }
+
+    fn is_low(&mut self) -> Result<bool, Self::Error> {
+        self.is_high().map(|high| !high)
+    }
+}
+
+/// A clock that moves forward by one second every time it is read.
+fn ticking_clock() -> &'static dyn Fn() -> Instant {
+    let time = Cell::new(0);
+    Box::leak(Box::new(move || {
+        time.set(time.get() + Duration::secs(1).ticks());
+        Instant::from_ticks(time.get())
+    }))
+}
+
+#[test]
+fn button_pairing() {
+    let options = |rp_id: &str| ClientOptions {
+        rp_id: rp_id.to_owned(),
+        ..client_options()
+    };
+    let reads = Rc::new(Cell::new(0));
+    let button = Button {
+        reads: reads.clone(),
+        pressed_after: Some(3),
+    };
+    let pairing = ButtonPairing::new(
+        button,
+        ArrayVec::<_, 1>::new(),
+        ticking_clock(),
+        Duration::secs(30),
+    );
+    let mut device = SimulatedDevice::<1024>::new(pairing);
+    device.set_request_handler(|request| request.iter().copied().collect());
+
+    // The first request waits for the button press.
+    let mut client = device.client_with(options("example.com"));
+    assert_eq!(client.read_write(b"hi").unwrap(), b"hi");
+    assert_eq!(reads.get(), 3);
+
+    // Once approved, the origin is allowed without touching the button again.
+    let mut client = device.client_with(options("example.com"));
+    assert_eq!(client.read_write(b"hi").unwrap(), b"hi");
+    assert_eq!(reads.get(), 3);
+
+    // The store is full, so a newly approved origin is still denied.
+    let result = device.client_with(options("example.org")).read_write(b"hi");
+    assert!(matches!(result, Err(Error::OriginRejected)), "{result:?}");
+}
+
+#[test]
+fn button_pairing_timeout() {
+    let reads = Rc::new(Cell::new(0));
+    let button = Button {
+        reads: reads.clone(),
+        pressed_after: None,
+    };
+    let pairing = ButtonPairing::new(
+        button,
+        ArrayVec::<_, 8>::new(),
+        ticking_clock(),
+        Duration::secs(5),
+    );
+    let mut device = SimulatedDevice::<1024>::new(pairing);
+    device.set_request_handler(|request| request.iter().copied().collect());
+
+    // Nobody presses the button, so the request is denied once the window has passed.
+    let result = device.client().read_write(b"hi");
+    assert!(matches!(result, Err(Error::OriginRejected)), "{result:?}");
+    assert!(reads.get() > 0);
+}
+
+#[test]
+fn button_pairing_held() {
+    let button = Button {
+        reads: Rc::new(Cell::new(0)),
+        pressed_after: Some(0),
+    };
+    let pairing = ButtonPairing::new(
+        button,
+        ArrayVec::<_, 8>::new(),
+        ticking_clock(),
+        Duration::secs(5),
+    );
+    let mut device = SimulatedDevice::<1024>::new(pairing);
+    device.set_request_handler(|request| request.iter().copied().collect());
+
+    // The button is held down from before pairing started, which is not a press, so the request is denied.
+    let result = device.client().read_write(b"hi");
+    assert!(matches!(result, Err(Error::OriginRejected)), "{result:?}");
+}
@@ -0,0 +1,105 @@
+use arrayvec::ArrayVec;
+use not_webusb::NotWebUsbError;
+use not_webusb_client::u2f::{self, ApduEncoding, AuthenticateControl};
+use not_webusb_client::{Error, Transport, ctaphid};
+use not_webusb_simulator::SimulatedDevice;
+use pretty_assertions::assert_eq;
+
+const MAX_MESSAGE_LEN: usize = 300;
+const SW_WRONG_DATA: u16 = 0x6A80;
+const CTAP2_ERR_NO_CREDENTIALS: u8 = 0x2E;
+
+fn assert_protocol_violation(
+    device: &mut SimulatedDevice<MAX_MESSAGE_LEN>,
+    result: Result<Vec<u8>, Error>,
+) {
+    // With the `ctap2` feature, `Client::read_write` sends getAssertion, where violations are answered with NoCredentials instead.
+    assert!(
+        matches!(
+            result,
+            Err(Error::StatusWord(SW_WRONG_DATA) | Error::Ctap2(CTAP2_ERR_NO_CREDENTIALS))
+        ),
+        "expected WrongData but was {result:?}"
+    );
+    let errors = device.take_errors();
+    assert!(
+        matches!(errors[..], [NotWebUsbError::ProtocolViolation]),
+        "{errors:?}"
+    );
+}
+
+fn assert_round_trip(device: &mut SimulatedDevice<MAX_MESSAGE_LEN>) {
+    assert_eq!(device.client().read_write(b"hello").unwrap(), b"hello");
+    assert!(device.take_errors().is_empty());
+}
+
+// All scenarios share one device, so after every violation the device must still work.
+#[test]
+fn protocol_violations() {
+    let mut device = SimulatedDevice::<MAX_MESSAGE_LEN>::new(&|_| true);
+    device.set_request_handler(|request| request.iter().copied().collect());
+
+    // more response data requested when there is no response
+    let result = device.client().authenticate(&[1]);
+    assert_protocol_violation(&mut device, result);
+    assert_round_trip(&mut device);
+
+    // unknown header
+    let result = device.client().authenticate(&[7, 1, 2]);
+    assert_protocol_violation(&mut device, result);
+    assert_round_trip(&mut device);
+
+    // no header
+    let result = device.client().authenticate(&[]);
+    assert_protocol_violation(&mut device, result);
+    assert_round_trip(&mut device);
+
+    // request longer than MAX_MESSAGE_LEN
+    let result = device.client().read_write(&[5; MAX_MESSAGE_LEN + 1]);
+    assert_protocol_violation(&mut device, result);
+    assert_round_trip(&mut device);
+
+    // a new request while the previous response has not been fully received
+    let mut client_a = device.client();
+    let mut key_handle = vec![2];
+    key_handle.extend_from_slice(&[b'a'; 100]);
+    client_a.authenticate(&key_handle).unwrap();
+    let result = client_a.read_write(b"hello");
+    assert_protocol_violation(&mut device, result);
+    assert_round_trip(&mut device);
+
+    // responding without a request
+    assert!(matches!(
+        device.not_webusb().send_response(ArrayVec::new()),
+        Err(NotWebUsbError::NoPendingRequest)
+    ));
+    assert_round_trip(&mut device);
+
+    // cancelling a transaction while its response is partially sent
+    let cid = ctaphid::init(&mut device).unwrap();
+    let apdu = u2f::authenticate_apdu(
+        AuthenticateControl::EnforceUserPresenceAndSign,
+        &[0; 32],
+        &u2f::rp_id_hash("localhost"),
+        b"\x02hi",
+        ApduEncoding::Extended,
+    );
+    let packets = ctaphid::encode_message(cid, ctaphid::CMD_MSG, &apdu).unwrap();
+    for packet in &packets {
+        device.write_report(packet).unwrap();
+    }
+    // The client is told to retry while the device handles the request.
+    let initial = device.read_report().unwrap();
+    assert_eq!(initial[4..9], [0x80 | ctaphid::CMD_MSG, 0, 2, 0x69, 0x85]);
+    for packet in &packets {
+        device.write_report(packet).unwrap();
+    }
+    let initial = device.read_report().unwrap();
+    // The rest of the response is still waiting to be sent
+    assert!(u16::from_be_bytes([initial[5], initial[6]]) > 57);
+    let cancel = ctaphid::encode_message(cid, ctaphid::CMD_CANCEL, &[]).unwrap();
+    device.write_report(&cancel[0]).unwrap();
+    let error = device.read_report().unwrap();
+    assert_eq!(error[4..8], [0xBF, 0, 1, 0x2D]);
+    assert_round_trip(&mut device);
+}
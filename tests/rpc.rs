@@ -0,0 +1,256 @@
+use not_webusb::{OriginPolicy, RequestError};
+use not_webusb_client::{ClientOptions, Error};
+use not_webusb_simulator::{SimulatedDevice, SimulatedEmbassyDevice, client_options};
+use pretty_assertions::assert_eq;
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::rc::Rc;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Layout {
+    keys: Vec<u16>,
+}
+
+not_webusb::rpc! {
+    /// The interface as implemented by old firmware.
+    mod keyboard_v1 {
+        version = 1;
+
+        1 => fn set_key(key: u8, code: u16) -> bool;
+    }
+}
+
+not_webusb::rpc! {
+    mod keyboard {
+        version = 2;
+
+        1 => fn set_key(key: u8, code: u16) -> bool;
+        2 => fn layout() -> Layout;
+    }
+}
+
+const READ: u32 = 1 << 0;
+const WRITE: u32 = 1 << 1;
+
+not_webusb::rpc! {
+    /// The interface with permissions, as used by websites with different levels of access.
+    mod keyboard_guarded {
+        version = 2;
+
+        1 requires WRITE => fn set_key(key: u8, code: u16) -> bool;
+        2 requires READ => fn layout() -> Layout;
+    }
+}
+
+#[derive(Default)]
+struct Keyboard {
+    keys: Vec<u16>,
+}
+
+impl Keyboard {
+    fn set_key(&mut self, key: u8, code: u16) -> bool {
+        match self.keys.get_mut(usize::from(key)) {
+            Some(existing) => {
+                *existing = code;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl keyboard_v1::Handler for Keyboard {
+    fn set_key(&mut self, key: u8, code: u16) -> bool {
+        Keyboard::set_key(self, key, code)
+    }
+}
+
+impl keyboard::Handler for Keyboard {
+    fn set_key(&mut self, key: u8, code: u16) -> bool {
+        Keyboard::set_key(self, key, code)
+    }
+
+    fn layout(&mut self) -> Layout {
+        Layout {
+            keys: self.keys.clone(),
+        }
+    }
+}
+
+impl keyboard_guarded::Handler for Keyboard {
+    fn set_key(&mut self, key: u8, code: u16) -> bool {
+        Keyboard::set_key(self, key, code)
+    }
+
+    fn layout(&mut self) -> Layout {
+        keyboard::Handler::layout(self)
+    }
+}
+
+#[test]
+fn rpc() {
+    use keyboard::Client as _;
+
+    let mut device: SimulatedDevice = SimulatedDevice::new(&|_| true);
+    let updated = Rc::new(Cell::new(false));
+    let mut keyboard = Keyboard { keys: vec![0; 4] };
+    device.set_poll_handler({
+        let updated = updated.clone();
+        move |not_webusb| {
+            if updated.get() {
+                keyboard::dispatch(not_webusb, &mut keyboard).unwrap();
+            } else {
+                keyboard_v1::dispatch(not_webusb, &mut keyboard).unwrap();
+            }
+        }
+    });
+
+    // A new client talking to old firmware can use the methods the firmware knows about.
+    assert_eq!(device.client().version().unwrap(), 1);
+    assert!(device.client().set_key(1, 0x04).unwrap());
+    assert!(!device.client().set_key(10, 0x04).unwrap());
+    assert!(matches!(
+        device.client().layout(),
+        Err(Error::Rpc(RequestError::UnknownMethod {
+            device_version: 1
+        }))
+    ));
+
+    // After a firmware update, the new method is available.
+    updated.set(true);
+    assert_eq!(device.client().version().unwrap(), 2);
+    assert!(device.client().set_key(2, 0x05).unwrap());
+    assert_eq!(
+        device.client().layout().unwrap(),
+        Layout {
+            keys: vec![0, 0x04, 0x05, 0]
+        }
+    );
+
+    // Requests that are not valid calls are answered without reaching the handler.
+    let response = device.client().read_write(&[]).unwrap();
+    assert_eq!(
+        postcard::from_bytes::<Result<(), RequestError>>(&response).unwrap(),
+        Err(RequestError::DecodeFailed)
+    );
+    // Method 1 without its arguments.
+    let response = device.client().read_write(&[1]).unwrap();
+    assert_eq!(
+        postcard::from_bytes::<Result<(), RequestError>>(&response).unwrap(),
+        Err(RequestError::DecodeFailed)
+    );
+
+    assert!(device.take_errors().is_empty());
+}
+
+#[test]
+fn permissions() {
+    use keyboard_guarded::Client as _;
+
+    static POLICY: OriginPolicy<2> = OriginPolicy::new([
+        ("configurator.example.com", READ),
+        ("service.example.com", READ | WRITE),
+    ]);
+    let mut device: SimulatedDevice = SimulatedDevice::with_builder(POLICY, |builder| {
+        builder.required_permissions(&keyboard_guarded::required_permissions)
+    });
+    let mut keyboard = Keyboard { keys: vec![0; 4] };
+    device.set_poll_handler(move |not_webusb| {
+        keyboard_guarded::dispatch(not_webusb, &mut keyboard).unwrap();
+    });
+
+    let options = |rp_id: &str| ClientOptions {
+        rp_id: rp_id.to_owned(),
+        ..client_options()
+    };
+
+    // The public configurator can only read.
+    let configurator = "configurator.example.com";
+    assert_eq!(
+        device.client_with(options(configurator)).version().unwrap(),
+        2
+    );
+    assert!(matches!(
+        device.client_with(options(configurator)).set_key(1, 0x04),
+        Err(Error::Forbidden)
+    ));
+    assert_eq!(
+        device.client_with(options(configurator)).layout().unwrap(),
+        Layout {
+            keys: vec![0, 0, 0, 0]
+        }
+    );
+
+    // The service portal can also write.
+    let service = "service.example.com";
+    assert!(
+        device
+            .client_with(options(service))
+            .set_key(1, 0x04)
+            .unwrap()
+    );
+    assert_eq!(
+        device.client_with(options(configurator)).layout().unwrap(),
+        Layout {
+            keys: vec![0, 0x04, 0, 0]
+        }
+    );
+
+    // Origins outside the policy are rejected before any permissions are checked.
+    assert!(matches!(
+        device.client_with(options("example.org")).version(),
+        Err(Error::OriginRejected)
+    ));
+    assert!(device.take_errors().is_empty());
+}
+
+#[test]
+fn embassy() {
+    use keyboard::Client as _;
+
+    let mut device = SimulatedEmbassyDevice::with_firmware::<1024, _, _>(
+        |_: [u8; 32]| true,
+        |mut not_webusb| async move {
+            let mut keyboard = Keyboard { keys: vec![0; 4] };
+            loop {
+                not_webusb.next_request().await;
+                keyboard::dispatch(&mut not_webusb, &mut keyboard).unwrap();
+            }
+        },
+    );
+    let mut client = device.client();
+    assert_eq!(client.version().unwrap(), 2);
+    assert!(client.set_key(3, 0x06).unwrap());
+    assert_eq!(
+        client.layout().unwrap(),
+        Layout {
+            keys: vec![0, 0, 0, 0x06]
+        }
+    );
+}
+
+#[test]
+fn embassy_next_call() {
+    let mut device = SimulatedEmbassyDevice::with_firmware::<1024, _, _>(
+        |_: [u8; 32]| true,
+        |mut not_webusb| async move {
+            loop {
+                let call = not_webusb.next_call().await;
+                let method = call.method();
+                call.respond(&method).unwrap();
+            }
+        },
+    );
+    let mut client = device.client();
+    // Requests that are not valid calls are answered without being returned.
+    let response = client.read_write(&[]).unwrap();
+    assert_eq!(
+        postcard::from_bytes::<Result<(), RequestError>>(&response).unwrap(),
+        Err(RequestError::DecodeFailed)
+    );
+    let response = client.read_write(&[7]).unwrap();
+    assert_eq!(
+        postcard::from_bytes::<Result<u16, RequestError>>(&response).unwrap(),
+        Ok(7)
+    );
+}
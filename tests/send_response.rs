@@ -0,0 +1,58 @@
+use not_webusb::NotWebUsbError;
+use not_webusb_simulator::SimulatedDevice;
+use pretty_assertions::assert_eq;
+
+const MAX_MESSAGE_LEN: usize = 300;
+
+#[test]
+fn send_response() {
+    let mut device = SimulatedDevice::<MAX_MESSAGE_LEN>::new(&|_| true);
+    device.set_poll_handler(|not_webusb| {
+        let Some(request) = not_webusb
+            .check_pending_request()
+            .map(|request| request.payload)
+        else {
+            return;
+        };
+        if request == b"copy" {
+            not_webusb.send_response_from_slice(b"copied").unwrap();
+            return;
+        }
+
+        // A response that does not fit is rejected without consuming the request.
+        let len = request.len();
+        assert!(matches!(
+            not_webusb.send_response_from_slice(&[0; MAX_MESSAGE_LEN + 1]),
+            Err(NotWebUsbError::ResponseTooLong)
+        ));
+        not_webusb
+            .send_response_with(|buf| {
+                assert_eq!(buf.len(), MAX_MESSAGE_LEN);
+                buf[..len].reverse();
+                len
+            })
+            .unwrap();
+    });
+
+    // The response is written over the request in place.
+    for len in [0, 1, 58, 254, 255, MAX_MESSAGE_LEN] {
+        let request: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        let expected: Vec<u8> = request.iter().rev().copied().collect();
+        assert_eq!(device.client().read_write(&request).unwrap(), expected);
+    }
+
+    // The response may be longer than the request.
+    assert_eq!(device.client().read_write(b"copy").unwrap(), b"copied");
+
+    // responding without a request
+    assert!(matches!(
+        device.not_webusb().send_response_with(|_| unreachable!()),
+        Err(NotWebUsbError::NoPendingRequest)
+    ));
+    assert!(matches!(
+        device.not_webusb().send_response_from_slice(b"hi"),
+        Err(NotWebUsbError::NoPendingRequest)
+    ));
+
+    assert!(device.take_errors().is_empty());
+}
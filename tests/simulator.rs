@@ -0,0 +1,475 @@
+use not_webusb::NotWebUsbError;
+use not_webusb_simulator::SimulatedDevice;
+use pretty_assertions::assert_eq;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use usbd_human_interface_device::device::fido::RawFidoReport;
+
+const BROADCAST_CID: u32 = 0xFFFFFFFF;
+const CTAPHID_PING: u8 = 0x81;
+const CTAPHID_MSG: u8 = 0x83;
+const CTAPHID_INIT: u8 = 0x86;
+const CTAPHID_ERROR: u8 = 0xBF;
+const CTAPHID_CBOR: u8 = 0x90;
+
+/// The CTAPHID_INIT capabilities every device advertises: CBOR support, with the `ctap2` feature.
+const CAPABILITIES: u8 = if cfg!(feature = "ctap2") { 0x04 } else { 0 };
+
+const DEFAULT_AAGUID: [u8; 16] = [
+    0xe3, 0xb1, 0x76, 0x8b, 0x55, 0x91, 0x4a, 0xd7, 0xb4, 0x6e, 0xac, 0xc7, 0x60, 0x84, 0x0b, 0x3e,
+];
+
+/// Split a CTAPHID message into initialization and continuation packets.
+fn encode_message(cid: u32, cmd: u8, payload: &[u8]) -> Vec<RawFidoReport> {
+    let mut reports = vec![];
+    let mut report = RawFidoReport::default();
+    report.packet[0..4].copy_from_slice(&cid.to_be_bytes());
+    report.packet[4] = cmd;
+    report.packet[5..7].copy_from_slice(&(payload.len() as u16).to_be_bytes());
+    let initial_len = payload.len().min(57);
+    report.packet[7..7 + initial_len].copy_from_slice(&payload[..initial_len]);
+    reports.push(report);
+
+    for (sequence, chunk) in payload[initial_len..].chunks(59).enumerate() {
+        let mut report = RawFidoReport::default();
+        report.packet[0..4].copy_from_slice(&cid.to_be_bytes());
+        report.packet[4] = sequence as u8;
+        report.packet[5..5 + chunk.len()].copy_from_slice(chunk);
+        reports.push(report);
+    }
+    reports
+}
+
+/// Send a CTAPHID message and reassemble the response, returning the response cmd and payload.
+fn transact<const N: usize>(
+    device: &mut SimulatedDevice<N>,
+    cid: u32,
+    cmd: u8,
+    payload: &[u8],
+) -> (u8, Vec<u8>) {
+    for report in encode_message(cid, cmd, payload) {
+        device.send_report(report);
+    }
+
+    let initial = device.poll_until_report(1000).unwrap().unwrap().packet;
+    assert_eq!(u32::from_be_bytes(initial[0..4].try_into().unwrap()), cid);
+    let length = u16::from_be_bytes(initial[5..7].try_into().unwrap()) as usize;
+    let mut response = initial[7..7 + length.min(57)].to_vec();
+    let mut sequence = 0;
+    while response.len() < length {
+        let continuation = device.poll_until_report(1000).unwrap().unwrap().packet;
+        assert_eq!(continuation[4], sequence);
+        let remaining = (length - response.len()).min(59);
+        response.extend_from_slice(&continuation[5..5 + remaining]);
+        sequence += 1;
+    }
+    (initial[4], response)
+}
+
+fn init_channel<const N: usize>(device: &mut SimulatedDevice<N>) -> u32 {
+    let nonce = [1, 2, 3, 4, 5, 6, 7, 8];
+    let (cmd, response) = transact(device, BROADCAST_CID, CTAPHID_INIT, &nonce);
+    assert_eq!(cmd, CTAPHID_INIT);
+    assert_eq!(response.len(), 17);
+    assert_eq!(response[0..8], nonce);
+    u32::from_be_bytes(response[8..12].try_into().unwrap())
+}
+
+/// Build an extended length U2F authenticate APDU carrying `key_handle`.
+fn authenticate_apdu(control: u8, key_handle: &[u8]) -> Vec<u8> {
+    let length = 65 + key_handle.len();
+    let mut apdu = vec![0x00, 0x02, control, 0x00, 0x00];
+    apdu.extend_from_slice(&(length as u16).to_be_bytes());
+    // challenge parameter
+    apdu.extend_from_slice(&[0; 32]);
+    // application parameter
+    apdu.extend_from_slice(&[0xAA; 32]);
+    apdu.push(key_handle.len() as u8);
+    apdu.extend_from_slice(key_handle);
+    // Le
+    apdu.extend_from_slice(&[0x00, 0x00]);
+    apdu
+}
+
+/// Send a single not-webusb request packet, returning the signature of the authenticate response.
+fn authenticate<const N: usize>(
+    device: &mut SimulatedDevice<N>,
+    cid: u32,
+    key_handle: &[u8],
+) -> Vec<u8> {
+    // The browser checks the key handle is valid before signing with it.
+    let (cmd, response) = transact(
+        device,
+        cid,
+        CTAPHID_MSG,
+        &authenticate_apdu(0x07, key_handle),
+    );
+    assert_eq!(cmd, CTAPHID_MSG);
+    assert_eq!(response, [0x69, 0x85]);
+
+    // Like a browser, retry while the device reports that it is not ready.
+    let response = loop {
+        let (cmd, response) = transact(
+            device,
+            cid,
+            CTAPHID_MSG,
+            &authenticate_apdu(0x03, key_handle),
+        );
+        assert_eq!(cmd, CTAPHID_MSG);
+        if response != [0x69, 0x85] {
+            break response;
+        }
+    };
+    assert_eq!(response[response.len() - 2..], [0x90, 0x00]);
+    // skip user presence and counter
+    response[5..response.len() - 2].to_vec()
+}
+
+/// The expected GetInfo response for a device with the given U2F version and aaguid.
+///
+/// Without the `ctap2` feature, GetInfo tells the browser that only U2F is supported.
+/// With it, FIDO_2_0 is listed too, along with the options and limits browsers need to send getAssertion.
+#[rustfmt::skip]
+fn get_info_response(u2f_version: &str, aaguid: [u8; 16]) -> Vec<u8> {
+    let u2f_version = [&[0x60 | u2f_version.len() as u8], u2f_version.as_bytes()].concat();
+    let mut response = vec![0x00]; // status
+    if cfg!(feature = "ctap2") {
+        response.push(0xA5); // map of 5 entries
+        response.extend([0x01, 0x82]); // versions: [u2f_version, "FIDO_2_0"]
+        response.extend(u2f_version);
+        response.extend(b"\x68FIDO_2_0");
+    } else {
+        response.push(0xA2); // map of 2 entries
+        response.extend([0x01, 0x81]); // versions: [u2f_version]
+        response.extend(u2f_version);
+    }
+    response.extend([0x03, 0x50]); // aaguid
+    response.extend(aaguid);
+    if cfg!(feature = "ctap2") {
+        response.extend([0x04, 0xA2, 0x62, b'r', b'k', 0xF4, 0x62, b'u', b'p', 0xF5]); // options: {"rk": false, "up": true}
+        response.extend([0x05, 0x19, 0x06, 0x00]); // maxMsgSize: 1536
+        response.extend([0x08, 0x19, 0x03, 0xFF]); // maxCredentialIdLength: 1023
+    }
+    response
+}
+
+fn rot13(x: u8) -> u8 {
+    match x {
+        b'a'..=b'm' | b'A'..=b'M' => x + 13,
+        b'n'..=b'z' | b'N'..=b'Z' => x - 13,
+        x => x,
+    }
+}
+
+#[test]
+fn simulated_device() {
+    let mut device = SimulatedDevice::<1024>::new(&|_| true);
+    device.set_request_handler(|request| request.iter().copied().map(rot13).collect());
+
+    // Nothing is sent until the host sends something.
+    assert_eq!(device.poll_until_report(100).unwrap(), None);
+
+    let cid = init_channel(&mut device);
+    assert_ne!(cid, 0);
+    assert_ne!(cid, BROADCAST_CID);
+
+    // ping
+    let mut ping = encode_message(cid, CTAPHID_PING, b"ping!")[0];
+    device.send_report(ping);
+    ping.packet[4] = CTAPHID_PING;
+    assert_eq!(device.poll_until_report(100).unwrap(), Some(ping));
+
+    // U2F version
+    let (cmd, response) = transact(
+        &mut device,
+        cid,
+        CTAPHID_MSG,
+        &[0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00],
+    );
+    assert_eq!(cmd, CTAPHID_MSG);
+    assert_eq!(response, b"U2F_V2\x90\x00");
+
+    // U2F version without Lc or Le, or with a short Le
+    for apdu in [
+        &[0x00, 0x03, 0x00, 0x00][..],
+        &[0x00, 0x03, 0x00, 0x00, 0x00],
+    ] {
+        let (cmd, response) = transact(&mut device, cid, CTAPHID_MSG, apdu);
+        assert_eq!(cmd, CTAPHID_MSG);
+        assert_eq!(response, b"U2F_V2\x90\x00");
+    }
+
+    // Malformed U2F requests are answered with WrongLength
+    let valid = authenticate_apdu(0x07, b"\x02Hello");
+    let mut short_lc = valid[..4].to_vec();
+    short_lc.push(valid.len() as u8 - 9);
+    short_lc.extend_from_slice(&valid[7..valid.len() - 2]);
+    let mut key_handle_too_long = valid.clone();
+    key_handle_too_long[7 + 64] = 7;
+    let mut body_too_short = valid[..4].to_vec();
+    body_too_short.extend_from_slice(&[0x00, 0x00, 0x40]);
+    body_too_short.extend_from_slice(&[0; 64]);
+    for apdu in [
+        &[0x00, 0x02][..],
+        &valid[..6],
+        &valid[..valid.len() - 3],
+        &short_lc[..short_lc.len() - 1],
+        &key_handle_too_long,
+        &body_too_short,
+    ] {
+        let (cmd, response) = transact(&mut device, cid, CTAPHID_MSG, apdu);
+        assert_eq!(cmd, CTAPHID_MSG);
+        assert_eq!(response, [0x67, 0x00], "{apdu:?}");
+    }
+
+    // Le is optional, and Lc may use the short encoding
+    let mut short_lc_and_le = short_lc.clone();
+    short_lc_and_le.push(0x00);
+    for apdu in [&valid[..valid.len() - 2], &short_lc, &short_lc_and_le] {
+        let (_, response) = transact(&mut device, cid, CTAPHID_MSG, apdu);
+        assert_eq!(response, [0x69, 0x85], "{apdu:?}");
+    }
+
+    // unknown CTAPHID command
+    let (cmd, response) = transact(&mut device, cid, 0x80 | 0x3A, &[]);
+    assert_eq!(cmd, CTAPHID_ERROR);
+    assert_eq!(response, [0x01]);
+
+    // Messages longer than the longest request are rejected with InvalidLen on the first packet.
+    // That is the longest U2F request, or with the `ctap2` feature the longest CTAP2 getAssertion request.
+    const LONGEST_REQUEST: usize = if cfg!(feature = "ctap2") {
+        1 + 1023 + 512
+    } else {
+        329
+    };
+    let longest = authenticate_apdu(0x07, &[0; 255]);
+    assert_eq!(longest.len(), 329);
+    let too_long = encode_message(cid, CTAPHID_MSG, &[0; LONGEST_REQUEST + 1]);
+    device.send_report(too_long[0]);
+    let error = device.poll_until_report(100).unwrap().unwrap().packet;
+    assert_eq!(error[0..4], cid.to_be_bytes());
+    assert_eq!(error[4..8], [CTAPHID_ERROR, 0, 1, 0x03]);
+    let (_, response) = transact(&mut device, cid, CTAPHID_MSG, &longest);
+    assert_eq!(response, [0x69, 0x85]);
+
+    // single packet request with a single packet response
+    let signature = authenticate(&mut device, cid, b"\x02Hello");
+    assert_eq!(signature[0..5], [0x30, 0x44, 0x02, 0x20, 0x7f]);
+    assert_eq!(signature[5..9], 5u32.to_be_bytes());
+    assert_eq!(&signature[9..14], b"Uryyb");
+    assert_eq!(signature[36..39], [0x02, 0x20, 0x7f]);
+
+    // multi packet request with a multi packet response
+    let request: Vec<u8> = (0..400).map(|i| b'a' + (i % 26) as u8).collect();
+    let mut key_handle = vec![0];
+    key_handle.extend_from_slice(&request[..254]);
+    let signature = authenticate(&mut device, cid, &key_handle);
+    assert_eq!(signature.len(), 0x46);
+
+    let mut key_handle = vec![2];
+    key_handle.extend_from_slice(&request[254..]);
+    let signature = authenticate(&mut device, cid, &key_handle);
+    assert_eq!(signature[5..9], 400u32.to_be_bytes());
+    let mut response = signature[9..36].to_vec();
+    response.extend_from_slice(&signature[39..70]);
+    while response.len() < request.len() {
+        let signature = authenticate(&mut device, cid, &[1]);
+        response.extend_from_slice(&signature[5..36]);
+        response.extend_from_slice(&signature[39..70]);
+    }
+    response.truncate(request.len());
+    let expected: Vec<u8> = request.iter().copied().map(rot13).collect();
+    assert_eq!(response, expected);
+}
+
+#[test]
+fn channels() {
+    let mut device = SimulatedDevice::<1024>::new(&|_| true);
+    let first = init_channel(&mut device);
+    let second = init_channel(&mut device);
+    assert_ne!(first, second);
+
+    // Requests on unallocated and reserved channels are rejected.
+    for cid in [0, BROADCAST_CID, second + 1] {
+        device.send_report(encode_message(cid, CTAPHID_PING, b"ping!")[0]);
+        let error = device.poll_until_report(100).unwrap().unwrap().packet;
+        assert_eq!(error[0..4], cid.to_be_bytes());
+        assert_eq!(error[4..8], [CTAPHID_ERROR, 0, 1, 0x0B]);
+    }
+
+    // Another channel cannot start a transaction, or interfere with it, while the first channel is sending its request.
+    let apdu = authenticate_apdu(0x07, b"\x02Hello");
+    let packets = encode_message(first, CTAPHID_MSG, &apdu);
+    device.send_report(packets[0]);
+    assert_eq!(device.poll_until_report(100).unwrap(), None);
+    let other = encode_message(second, CTAPHID_MSG, &apdu);
+    device.send_report(other[0]);
+    let error = device.poll_until_report(100).unwrap().unwrap().packet;
+    assert_eq!(error[0..4], second.to_be_bytes());
+    assert_eq!(error[4..8], [CTAPHID_ERROR, 0, 1, 0x06]);
+    device.send_report(other[1]);
+    device.send_report(encode_message(second, 0x80 | 0x11, &[])[0]);
+    assert_eq!(device.poll_until_report(100).unwrap(), None);
+    device.send_report(packets[1]);
+    let response = device.poll_until_report(100).unwrap().unwrap().packet;
+    assert_eq!(response[0..4], first.to_be_bytes());
+    assert_eq!(response[4..9], [CTAPHID_MSG, 0, 2, 0x69, 0x85]);
+
+    // CTAPHID_INIT on an allocated channel keeps the channel and aborts its transaction.
+    device.send_report(packets[0]);
+    let nonce = [8, 7, 6, 5, 4, 3, 2, 1];
+    let (cmd, response) = transact(&mut device, first, CTAPHID_INIT, &nonce);
+    assert_eq!(cmd, CTAPHID_INIT);
+    assert_eq!(response[0..8], nonce);
+    assert_eq!(response[8..12], first.to_be_bytes());
+    let (_, response) = transact(&mut device, second, CTAPHID_MSG, &apdu);
+    assert_eq!(response, [0x69, 0x85]);
+
+    // Once all channels are in use, the oldest channel is evicted.
+    let newer: Vec<u32> = (0..7).map(|_| init_channel(&mut device)).collect();
+    assert!(!newer.contains(&0) && !newer.contains(&first) && !newer.contains(&second));
+    device.send_report(encode_message(first, CTAPHID_MSG, &apdu)[0]);
+    let error = device.poll_until_report(100).unwrap().unwrap().packet;
+    assert_eq!(error[4..8], [CTAPHID_ERROR, 0, 1, 0x0B]);
+    for cid in [second].into_iter().chain(newer) {
+        let (_, response) = transact(&mut device, cid, CTAPHID_MSG, &apdu);
+        assert_eq!(response, [0x69, 0x85]);
+    }
+}
+
+#[test]
+fn wink() {
+    static WINKS: AtomicUsize = AtomicUsize::new(0);
+    const CTAPHID_WINK: u8 = 0x88;
+
+    // Without a wink callback, wink is not advertised or supported.
+    let mut device = SimulatedDevice::<1024>::new(&|_| true);
+    let (_, response) = transact(&mut device, BROADCAST_CID, CTAPHID_INIT, &[0; 8]);
+    assert_eq!(response[12..17], [2, 0, 0, 0, CAPABILITIES]);
+    let cid = u32::from_be_bytes(response[8..12].try_into().unwrap());
+    let (cmd, response) = transact(&mut device, cid, CTAPHID_WINK, &[]);
+    assert_eq!(cmd, CTAPHID_ERROR);
+    assert_eq!(response, [0x01]);
+
+    let mut device = SimulatedDevice::<1024>::new(&|_| true).with_wink(&|| {
+        WINKS.fetch_add(1, Ordering::SeqCst);
+    });
+    let (_, response) = transact(&mut device, BROADCAST_CID, CTAPHID_INIT, &[0; 8]);
+    assert_eq!(response[12..17], [2, 0, 0, 0, CAPABILITIES | 0x01]);
+    let cid = u32::from_be_bytes(response[8..12].try_into().unwrap());
+    let (cmd, response) = transact(&mut device, cid, CTAPHID_WINK, &[]);
+    assert_eq!(cmd, CTAPHID_WINK);
+    assert_eq!(response, []);
+    assert_eq!(WINKS.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn cbor_get_info() {
+    let mut device = SimulatedDevice::<1024>::new(&|_| true);
+    let cid = init_channel(&mut device);
+    let (cmd, response) = transact(&mut device, cid, CTAPHID_CBOR, &[0x04]);
+    assert_eq!(cmd, CTAPHID_CBOR);
+    assert_eq!(response, get_info_response("U2F_V2", DEFAULT_AAGUID));
+
+    // Without the `ctap2` feature every other CTAP2 command, including getAssertion, is unsupported.
+    // With it, a getAssertion without any parameters is missing the required ones.
+    let (cmd, response) = transact(&mut device, cid, CTAPHID_CBOR, &[0x02, 0xA0]);
+    assert_eq!(cmd, CTAPHID_CBOR);
+    let status = if cfg!(feature = "ctap2") { 0x14 } else { 0x01 };
+    assert_eq!(response, [status]);
+}
+
+#[test]
+fn device_identity() {
+    const AAGUID: [u8; 16] = [7; 16];
+
+    let mut device = SimulatedDevice::<1024>::with_builder(&|_| true, |builder| {
+        builder
+            .aaguid(AAGUID)
+            .device_version(1, 2, 3)
+            .u2f_version("U2F_V2_CUSTOM")
+    });
+    let (_, response) = transact(&mut device, BROADCAST_CID, CTAPHID_INIT, &[0; 8]);
+    assert_eq!(response[12..17], [2, 1, 2, 3, CAPABILITIES]);
+    let cid = u32::from_be_bytes(response[8..12].try_into().unwrap());
+
+    let (_, response) = transact(&mut device, cid, CTAPHID_MSG, &[0, 0x03, 0, 0]);
+    assert_eq!(response, b"U2F_V2_CUSTOM\x90\x00");
+
+    let (_, response) = transact(&mut device, cid, CTAPHID_CBOR, &[0x04]);
+    assert_eq!(response, get_info_response("U2F_V2_CUSTOM", AAGUID));
+}
+
+// The U2F version response is the only one whose length can be chosen, here it steps past exactly filling the initial packet.
+// The transaction must still end on that packet, without an empty packet after it.
+#[test]
+fn exact_packet_multiple() {
+    for len in 54..=57 {
+        let version: &'static str = "U2F_V2".repeat(10).leak();
+        let version = &version[..len];
+        let mut device = SimulatedDevice::<1024>::with_builder(&|_| true, |builder| {
+            builder.u2f_version(version)
+        });
+        let cid = init_channel(&mut device);
+        for _ in 0..2 {
+            let (cmd, response) = transact(&mut device, cid, CTAPHID_MSG, &[0, 0x03, 0, 0]);
+            assert_eq!(cmd, CTAPHID_MSG);
+            assert_eq!(response, [version.as_bytes(), &[0x90, 0x00]].concat());
+            assert_eq!(device.poll_until_report(100).unwrap(), None);
+        }
+    }
+}
+
+#[test]
+fn slow_response() {
+    let mut device = SimulatedDevice::<1024>::new(&|_| true);
+    let cid = init_channel(&mut device);
+    let request = authenticate_apdu(0x03, b"\x02slow");
+
+    // The client is told to retry for as long as the application takes to respond.
+    for _ in 0..3 {
+        let (_, response) = transact(&mut device, cid, CTAPHID_MSG, &request);
+        assert_eq!(response, [0x69, 0x85]);
+        assert_eq!(
+            device
+                .not_webusb()
+                .check_pending_request()
+                .map(|request| request.payload),
+            Some(&b"slow"[..])
+        );
+    }
+
+    // A request from another website is not mistaken for a retry.
+    let mut other_origin = request.clone();
+    other_origin[7 + 32..7 + 64].fill(0xBB);
+    for report in encode_message(cid, CTAPHID_MSG, &other_origin) {
+        device.send_report(report);
+    }
+    assert!(matches!(
+        device.poll_until_report(1000),
+        Err(NotWebUsbError::ProtocolViolation)
+    ));
+    let response = device.poll_until_report(1000).unwrap().unwrap().packet;
+    assert_eq!(response[4..9], [CTAPHID_MSG, 0, 2, 0x6A, 0x80]);
+    assert_eq!(
+        device
+            .not_webusb()
+            .check_pending_request()
+            .map(|request| request.payload),
+        Some(&b"slow"[..])
+    );
+
+    // The next retry picks up the response.
+    device
+        .not_webusb()
+        .send_response_from_slice(b"done")
+        .unwrap();
+    assert!(device.not_webusb().check_pending_request().is_none());
+    let (_, response) = transact(&mut device, cid, CTAPHID_MSG, &request);
+    assert_eq!(response[response.len() - 2..], [0x90, 0x00]);
+    assert_eq!(response[10..14], 4u32.to_be_bytes());
+    assert_eq!(&response[14..18], b"done");
+
+    // Once the response is collected the retry is a new request.
+    let (_, response) = transact(&mut device, cid, CTAPHID_MSG, &request);
+    assert_eq!(response, [0x69, 0x85]);
+}
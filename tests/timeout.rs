@@ -0,0 +1,72 @@
+use not_webusb::{Duration, NotWebUsbError, Timeouts};
+use not_webusb_client::{Transport, ctaphid};
+use not_webusb_simulator::SimulatedDevice;
+use pretty_assertions::assert_eq;
+
+/// Poll the device until it reports an error, which must happen before the device sends any packets.
+fn poll_until_error(device: &mut SimulatedDevice) -> NotWebUsbError {
+    for _ in 0..100 {
+        if let Err(err) = device.poll() {
+            return err;
+        }
+    }
+    panic!("device did not report an error")
+}
+
+fn assert_round_trip(device: &mut SimulatedDevice) {
+    let mut client = device.client();
+    assert_eq!(client.read_write(b"hello").unwrap(), b"hello");
+}
+
+#[test]
+fn timeouts() {
+    let timeouts = Timeouts {
+        transaction: Duration::secs(1),
+        user_data: Duration::secs(30),
+    };
+    let mut device = SimulatedDevice::<1024>::with_timeouts(&|_| true, timeouts);
+    device.set_request_handler(|request| request.iter().copied().collect());
+
+    // The client only sends the first packet of a multi packet message.
+    let cid = ctaphid::init(&mut device).unwrap();
+    let packets = ctaphid::encode_message(cid, ctaphid::CMD_MSG, &[0; 100]).unwrap();
+    device.write_report(&packets[0]).unwrap();
+    assert_eq!(device.poll_until_report(100).unwrap(), None);
+    // Time spent waiting on the client within the timeout is fine.
+    device.advance_time(Duration::millis(900));
+    assert_eq!(device.poll_until_report(100).unwrap(), None);
+    device.advance_time(Duration::millis(200));
+    assert!(matches!(
+        poll_until_error(&mut device),
+        NotWebUsbError::TransactionTimeout
+    ));
+    let error = device.poll_until_report(100).unwrap().unwrap().packet;
+    assert_eq!(error[0..4], cid.to_be_bytes());
+    assert_eq!(error[4..8], [0xBF, 0, 1, 0x05]);
+    assert_round_trip(&mut device);
+
+    // The client sends the first key handle of a multi key handle request, then disappears.
+    let mut client = device.client();
+    let mut key_handle = vec![0];
+    key_handle.extend_from_slice(&[b'x'; 254]);
+    client.authenticate(&key_handle).unwrap();
+    device.advance_time(Duration::secs(31));
+    assert!(matches!(
+        poll_until_error(&mut device),
+        NotWebUsbError::RequestTimeout
+    ));
+    // The stale request data must not leak into the next request.
+    assert_round_trip(&mut device);
+
+    // The client receives the first part of a multi signature response, then disappears.
+    let mut client = device.client();
+    let mut key_handle = vec![2];
+    key_handle.extend_from_slice(&[b'y'; 200]);
+    client.authenticate(&key_handle).unwrap();
+    device.advance_time(Duration::secs(31));
+    assert!(matches!(
+        poll_until_error(&mut device),
+        NotWebUsbError::ResponseTimeout
+    ));
+    assert_round_trip(&mut device);
+}
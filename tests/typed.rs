@@ -0,0 +1,113 @@
+use not_webusb::{Codec, Json, NotWebUsbError, Postcard, RequestError};
+use not_webusb_simulator::{SimulatedDevice, SimulatedNotWebUsb};
+use pretty_assertions::assert_eq;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+#[derive(Serialize, Deserialize)]
+enum Request {
+    Add {
+        a: u32,
+        b: u32,
+    },
+    /// Asks for a response too long to fit in `MAX_MESSAGE_LEN`.
+    Large,
+}
+
+const MAX_MESSAGE_LEN: usize = 128;
+
+type NotWebUsb = SimulatedNotWebUsb<MAX_MESSAGE_LEN>;
+
+fn handle(
+    not_webusb: &mut NotWebUsb,
+    codec: impl Codec + Copy,
+    errors: &RefCell<Vec<NotWebUsbError>>,
+) {
+    match not_webusb.check_pending_request_as::<Request>(codec) {
+        Some(Request::Add { a, b }) => not_webusb
+            .send_response_as(codec, &(u64::from(a) + u64::from(b)))
+            .unwrap(),
+        Some(Request::Large) => {
+            let error = not_webusb
+                .send_response_as(codec, &[u32::MAX; 32])
+                .unwrap_err();
+            errors.borrow_mut().push(error);
+            not_webusb.send_response_as(codec, &0u64).unwrap();
+        }
+        None => {}
+    }
+}
+
+#[test]
+fn typed() {
+    let mut device = SimulatedDevice::<MAX_MESSAGE_LEN>::new(&|_| true);
+    let use_json = Rc::new(RefCell::new(false));
+    let errors = Rc::new(RefCell::new(vec![]));
+    device.set_poll_handler({
+        let use_json = use_json.clone();
+        let errors = errors.clone();
+        move |not_webusb| {
+            if *use_json.borrow() {
+                handle(not_webusb, Json, &errors)
+            } else {
+                handle(not_webusb, Postcard, &errors)
+            }
+        }
+    });
+
+    // postcard
+    let request = postcard::to_allocvec(&Request::Add { a: 2, b: u32::MAX }).unwrap();
+    let response = device.client().read_write(&request).unwrap();
+    assert_eq!(
+        postcard::from_bytes::<Result<u64, RequestError>>(&response).unwrap(),
+        Ok(u64::from(u32::MAX) + 2)
+    );
+
+    // A request that is not a valid `Request` is answered by NotWebUsb, without reaching the application.
+    let response = device.client().read_write(&[0xFF]).unwrap();
+    assert_eq!(
+        postcard::from_bytes::<Result<u64, RequestError>>(&response).unwrap(),
+        Err(RequestError::DecodeFailed)
+    );
+
+    // A response that does not fit is returned to the application, which can still send a different response.
+    let request = postcard::to_allocvec(&Request::Large).unwrap();
+    let response = device.client().read_write(&request).unwrap();
+    assert_eq!(
+        postcard::from_bytes::<Result<u64, RequestError>>(&response).unwrap(),
+        Ok(0)
+    );
+    assert!(matches!(
+        errors.borrow_mut().as_slice(),
+        [NotWebUsbError::ResponseEncodingFailed]
+    ));
+    errors.borrow_mut().clear();
+
+    // JSON
+    *use_json.borrow_mut() = true;
+    let response = device
+        .client()
+        .read_write(br#"{"Add":{"a":1,"b":2}}"#)
+        .unwrap();
+    assert_eq!(String::from_utf8(response).unwrap(), r#"{"Ok":3}"#);
+
+    let response = device.client().read_write(br#"{"Sub":{}}"#).unwrap();
+    assert_eq!(
+        String::from_utf8(response).unwrap(),
+        r#"{"Err":"DecodeFailed"}"#
+    );
+    assert_eq!(
+        serde_json::from_str::<Result<u64, RequestError>>(r#"{"Err":"DecodeFailed"}"#).unwrap(),
+        Err(RequestError::DecodeFailed)
+    );
+
+    let response = device.client().read_write(br#""Large""#).unwrap();
+    assert_eq!(String::from_utf8(response).unwrap(), r#"{"Ok":0}"#);
+    assert!(matches!(
+        errors.borrow_mut().as_slice(),
+        [NotWebUsbError::ResponseEncodingFailed]
+    ));
+
+    assert!(device.take_errors().is_empty());
+}
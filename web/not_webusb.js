@@ -5,6 +5,7 @@ _not_webusb_internal_lock = false;
 ///
 /// Only a single call to not_webusb_read_write can be running at once.
 /// If a second call is attempted before the first finishes, the second call will throw a `NotWebusbInUseException`.
+/// If the device forbids the request for the origin of this webpage, a `NotWebusbForbiddenException` is thrown.
 async function not_webusb_read_write(input) {
     /// The way packets are packetized relies on having sole access to the not-webusb device,
     /// so we take a lock to ensure only one not-webusb device can be accessed at a time.
@@ -22,55 +23,88 @@ async function not_webusb_read_write(input) {
 
 async function _not_webusb_read_write(input) {
     function toU32(array, offset) {
-        return (array[offset] << 24)
+        return ((array[offset] << 24)
             + (array[offset + 1] << 16)
             + (array[offset + 2] << 8)
-            + (array[offset + 3]);
+            + (array[offset + 3])) >>> 0;
     }
 
     async function concat_uint8array(arrays) {
         return new Uint8Array(await new Blob(arrays).arrayBuffer());
     }
 
-    var total_size = input.length + 4;
-    var number_of_packets = Math.ceil(total_size / 254);
+    /// Returns [length, offset after the length] of the DER length at `offset`.
+    /// CTAP2 signatures are long enough to need the long form.
+    function der_length(array, offset) {
+        var first = array[offset];
+        if (first < 0x80) {
+            return [first, offset + 1];
+        }
+        var length = 0;
+        for (var i = 1; i <= (first & 0x7f); i++) {
+            length = (length << 8) + array[offset + i];
+        }
+        return [length, offset + 1 + (first & 0x7f)];
+    }
+
+    /// Returns the bytes smuggled in the two ASN.1 integers of the signature, with their 0x7f prefixes stripped.
+    /// Devices send an empty ASN.1 sequence instead when the origin lacks the permissions the request requires.
+    function signature_payload(sig) {
+        if (sig.length == 2 && sig[0] == 0x30 && sig[1] == 0x00) {
+            throw new NotWebusbForbiddenException();
+        }
+        var [_sequence_length, offset] = der_length(sig, 1);
+        var payload = [];
+        for (var i = 0; i < 2; i++) {
+            var [length, start] = der_length(sig, offset + 1);
+            payload.push(sig.slice(start + 1, start + length));
+            offset = start + length;
+        }
+        return concat_uint8array(payload);
+    }
 
-    // initial request packets
-    for (var i = 0; i < number_of_packets - 1; i++) {
-        var sig = await _not_webusb_read_write_raw(await concat_uint8array([
-            new Uint8Array([0]),
-            input.slice(i * 254, (i + 1) * 254)
+    /// Devices answering via CTAP2 advertise the longest key handle they accept in the signCount.
+    function max_key_handle_length(authenticator_data) {
+        var sign_count = authenticator_data.length >= 37 ? toU32(authenticator_data, 33) : 0;
+        return Math.min(Math.max(sign_count, 255), 1023);
+    }
+
+    // request packets, sent in key handles as long as the device accepts
+    var chunk_length = 254;
+    var offset = 0;
+    while (true) {
+        var final = offset + chunk_length >= input.length;
+        var assertion = await _not_webusb_read_write_raw(await concat_uint8array([
+            new Uint8Array([final ? 2 : 0]),
+            input.slice(offset, offset + chunk_length)
         ]));
+        offset += chunk_length;
+        if (final) {
+            break;
+        }
+        chunk_length = max_key_handle_length(assertion.authenticator_data) - 1;
     }
 
-    // final request packet + initial response packet
-    var sig = await _not_webusb_read_write_raw(await concat_uint8array([
-        new Uint8Array([2]),
-        input.slice((number_of_packets - 1) * 254)
-    ]));
-    var size = toU32(sig, 5);
-    var response = await concat_uint8array([
-        sig.slice(9, Math.min(36, 9 + size)),
-        sig.slice(39, Math.min(71, 39 + (size - 27)))
-    ]);
-    size -= 58;
+    // initial response packet, the response starts with its length
+    var payload = await signature_payload(assertion.signature);
+    var size = toU32(payload, 0);
+    var response = payload.slice(4, 4 + size);
 
     // final response packets
-    while (size > 0) {
-        var sig = await _not_webusb_read_write_raw(new Uint8Array([1]));
+    while (response.length < size) {
+        var assertion = await _not_webusb_read_write_raw(new Uint8Array([1]));
+        var payload = await signature_payload(assertion.signature);
         response = await concat_uint8array([
             response,
-            sig.slice(5, Math.min(36, 5 + size)),
-            sig.slice(39, Math.min(71, 39 + size - 31))
+            payload.slice(0, size - response.length)
         ]);
-        size -= 62;
     }
 
     return response;
 }
 
-/// Takes a Uint8array request of length 0..255
-/// Returns a Uint8Array of the raw response, it must be further processed to retrieve user response data.
+/// Takes a Uint8array request of length 0..1023, only 0..255 is accepted by devices without CTAP2 support.
+/// Returns the raw signature and authenticator_data Uint8Arrays, they must be further processed to retrieve user response data.
 async function _not_webusb_read_write_raw(input) {
     let credential = await navigator.credentials.get({
         publicKey: {
@@ -83,7 +117,10 @@ async function _not_webusb_read_write_raw(input) {
             userVerification: "discouraged",
         }
     });
-    return new Uint8Array(credential.response.signature);
+    return {
+        signature: new Uint8Array(credential.response.signature),
+        authenticator_data: new Uint8Array(credential.response.authenticatorData),
+    };
 }
 
 class NotWebusbInUseException extends Error {
@@ -92,3 +129,10 @@ class NotWebusbInUseException extends Error {
         this.name = this.constructor.name;
     }
 }
+
+class NotWebusbForbiddenException extends Error {
+    constructor() {
+        super("the device forbids this request for the origin of this webpage");
+        this.name = this.constructor.name;
+    }
+}